@@ -0,0 +1,15 @@
+-- tracking is opt-in: subscribers have to explicitly consent to it
+ALTER TABLE subscriptions ADD COLUMN tracking_consent BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE tracking_events(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    newsletter_issue_id uuid NOT NULL,
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    kind TEXT NOT NULL,
+    url TEXT NULL,
+    occurred_at timestamptz NOT NULL
+);
+
+CREATE INDEX tracking_events_issue_idx ON tracking_events (newsletter_issue_id, kind);
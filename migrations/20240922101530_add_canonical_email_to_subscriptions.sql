@@ -0,0 +1,29 @@
+-- uniqueness is enforced on the canonical form of the address, the typed one is kept for display.
+-- Internationalised domains can't be converted to punycode here, the rows stored so far
+-- are expected to use ASCII domains.
+ALTER TABLE subscriptions ADD COLUMN email_canonical TEXT NULL;
+UPDATE subscriptions SET email_canonical = lower(email);
+
+-- merge the duplicates into the confirmed subscriber if there is one, into the oldest otherwise
+CREATE TEMPORARY TABLE subscription_merges AS
+SELECT id AS duplicate_id, kept_id
+FROM (
+    SELECT
+        id,
+        first_value(id) OVER (
+            PARTITION BY email_canonical
+            ORDER BY status = 'confirmed' DESC, subscribed_at, id
+        ) AS kept_id
+    FROM subscriptions
+) candidates
+WHERE id <> kept_id;
+
+UPDATE tracking_events SET subscriber_id = kept_id
+FROM subscription_merges WHERE subscriber_id = duplicate_id;
+DELETE FROM subscription_tokens USING subscription_merges WHERE subscriber_id = duplicate_id;
+DELETE FROM subscriptions USING subscription_merges WHERE id = duplicate_id;
+DROP TABLE subscription_merges;
+
+ALTER TABLE subscriptions ALTER COLUMN email_canonical SET NOT NULL;
+ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_email_canonical_key UNIQUE (email_canonical);
+ALTER TABLE subscriptions DROP CONSTRAINT subscriptions_email_key;
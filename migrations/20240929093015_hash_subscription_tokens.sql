@@ -0,0 +1,17 @@
+-- the subscription tokens are stored as a keyed hash, so that they can't be used
+-- by whoever can read the database.
+-- The key is known only to the application, so the existing tokens can't be hashed here:
+-- they are kept aside, no longer accepted, until `migrate` sends their pending subscribers
+-- a new link and deletes them.
+ALTER TABLE subscription_tokens RENAME TO legacy_subscription_tokens;
+ALTER TABLE legacy_subscription_tokens
+    RENAME CONSTRAINT subscription_tokens_pkey TO legacy_subscription_tokens_pkey;
+ALTER TABLE legacy_subscription_tokens
+    RENAME CONSTRAINT subscription_tokens_subscriber_id_fkey TO legacy_subscription_tokens_subscriber_id_fkey;
+ALTER TABLE legacy_subscription_tokens ADD COLUMN retired_at timestamptz NOT NULL DEFAULT now();
+
+CREATE TABLE subscription_tokens(
+    subscription_token_hash TEXT NOT NULL PRIMARY KEY,
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id)
+);
@@ -0,0 +1,5 @@
+-- when the last confirmation email was sent, the signups received in the following
+-- `confirmation.resend_interval_seconds` don't send another one
+ALTER TABLE subscriptions ADD COLUMN confirmation_sent_at TIMESTAMPTZ NULL;
+UPDATE subscriptions SET confirmation_sent_at = subscribed_at;
+ALTER TABLE subscriptions ALTER COLUMN confirmation_sent_at SET NOT NULL;
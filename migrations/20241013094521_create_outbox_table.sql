@@ -0,0 +1,19 @@
+-- the emails to send, written in the transaction that decides to send them
+-- and delivered by the relay worker
+CREATE TABLE outbox (
+    id uuid NOT NULL PRIMARY KEY,
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_body TEXT NOT NULL,
+    text_body TEXT NOT NULL,
+    -- the trace context of the request as JSON, so that the delivery is part of the same trace
+    trace_context TEXT NOT NULL DEFAULT '{}',
+    -- pending, delivered or failed
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT NULL,
+    created_at timestamptz NOT NULL,
+    next_attempt_at timestamptz NOT NULL,
+    delivered_at timestamptz NULL
+);
+CREATE INDEX outbox_pending_idx ON outbox (next_attempt_at) WHERE status = 'pending';
@@ -0,0 +1,29 @@
+-- every delivery attempt of the emails, kept across dead-lettering and replays
+CREATE TABLE email_delivery_attempts (
+    id BIGSERIAL PRIMARY KEY,
+    email_id uuid NOT NULL,
+    attempted_at timestamptz NOT NULL,
+    -- NULL if the email was delivered
+    error TEXT NULL
+);
+CREATE INDEX email_delivery_attempts_email_id_idx ON email_delivery_attempts (email_id);
+
+-- the emails the relay gave up on, they leave the outbox until they are replayed
+CREATE TABLE dead_letters (
+    id uuid NOT NULL PRIMARY KEY,
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_body TEXT NOT NULL,
+    text_body TEXT NOT NULL,
+    trace_context TEXT NOT NULL,
+    attempts INT NOT NULL,
+    last_error TEXT NOT NULL,
+    created_at timestamptz NOT NULL,
+    dead_lettered_at timestamptz NOT NULL
+);
+
+-- the emails the relay gave up on so far
+INSERT INTO dead_letters (id, recipient, subject, html_body, text_body, trace_context, attempts, last_error, created_at, dead_lettered_at)
+SELECT id, recipient, subject, html_body, text_body, trace_context, attempts, coalesce(last_error, ''), created_at, now()
+FROM outbox WHERE status = 'failed';
+DELETE FROM outbox WHERE status = 'failed';
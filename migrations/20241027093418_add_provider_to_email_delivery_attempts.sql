@@ -0,0 +1,4 @@
+-- every provider tried gets its own attempt, failed or not
+ALTER TABLE email_delivery_attempts ADD COLUMN provider TEXT NULL;
+COMMENT ON COLUMN email_delivery_attempts.provider IS
+    'the provider the email was handed to, NULL if it was refused before reaching any';
@@ -0,0 +1,5 @@
+-- the relay claims an email by marking it 'sending' until `locked_until`, then sends it
+-- outside of any transaction. An email whose lease expired, e.g. because its relay
+-- crashed mid-delivery, is claimed again.
+ALTER TABLE outbox ADD COLUMN locked_until timestamptz NULL;
+CREATE INDEX outbox_sending_idx ON outbox (locked_until) WHERE status = 'sending';
@@ -0,0 +1,222 @@
+use crate::errors::AuthError;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::{web, Error, ResponseError};
+use anyhow::Context;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::future::LocalBoxFuture;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use uuid::Uuid;
+
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+// hashes a password with Argon2id, the result embeds salt and parameters (PHC string format)
+pub fn compute_password_hash(password: Secret<String>) -> Result<Secret<String>, anyhow::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, None).unwrap(),
+    )
+    .hash_password(password.expose_secret().as_bytes(), &salt)
+    .map_err(|e| anyhow::anyhow!(e.to_string()))
+    .context("failed to hash the password")?
+    .to_string();
+    Ok(Secret::new(password_hash))
+}
+
+#[tracing::instrument(name = "create admin", skip(password, db_pool))]
+pub async fn create_admin(
+    db_pool: &PgPool,
+    username: &str,
+    password: Secret<String>,
+) -> Result<Uuid, anyhow::Error> {
+    if username.trim().is_empty() {
+        anyhow::bail!("the username can't be empty");
+    }
+    if password.expose_secret().is_empty() {
+        anyhow::bail!("the password can't be empty");
+    }
+
+    let user_id = Uuid::new_v4();
+    // hashing is cpu-bound, keep it away from the async executor
+    let password_hash = tokio::task::spawn_blocking(move || compute_password_hash(password))
+        .await
+        .context("failed to spawn the password hashing task")??;
+
+    sqlx::query!(
+        r#"INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3)"#,
+        user_id,
+        username,
+        password_hash.expose_secret()
+    )
+    .execute(db_pool)
+    .await
+    .context("failed to store the new admin, the username may already be taken")?;
+
+    Ok(user_id)
+}
+
+// the credentials of the `Authorization: Basic` header
+pub fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, AuthError> {
+    let missing = |message: &str| AuthError::MissingCredentials(message.to_string());
+    let header_value = headers
+        .get(header::AUTHORIZATION)
+        .ok_or_else(|| missing("the Authorization header is missing"))?
+        .to_str()
+        .map_err(|_| missing("the Authorization header is not a valid UTF-8 string"))?;
+    let encoded = header_value
+        .strip_prefix("Basic ")
+        .ok_or_else(|| missing("the authorization scheme is not 'Basic'"))?;
+    let decoded = STANDARD
+        .decode(encoded)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| missing("the Basic credentials are not valid base64-encoded UTF-8"))?;
+    let (username, password) = decoded
+        .split_once(':')
+        .ok_or_else(|| missing("the Basic credentials have no password"))?;
+    Ok(Credentials {
+        username: username.to_string(),
+        password: Secret::new(password.to_string()),
+    })
+}
+
+#[tracing::instrument(name = "validate credentials", skip(credentials, db_pool))]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    db_pool: &PgPool,
+) -> Result<Uuid, AuthError> {
+    let mut user_id = None;
+    // verify against a dummy hash when the user doesn't exist, so that the response time
+    // doesn't tell which usernames exist
+    let mut expected_password_hash = Secret::new(
+        "$argon2id$v=19$m=15000,t=2,p=1$\
+        gZiV/M1gPc22ElAH/Jh1Hw$\
+        CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno"
+            .to_string(),
+    );
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, db_pool).await?
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    // hashing is cpu-bound, keep it away from the async executor
+    tokio::task::spawn_blocking(move || {
+        verify_password_hash(expected_password_hash, credentials.password)
+    })
+    .await
+    .context("failed to spawn the password verification task")??;
+
+    user_id
+        .ok_or_else(|| anyhow::anyhow!("unknown username"))
+        .map_err(AuthError::InvalidCredentials)
+}
+
+async fn get_stored_credentials(
+    username: &str,
+    db_pool: &PgPool,
+) -> Result<Option<(Uuid, Secret<String>)>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"SELECT user_id, password_hash FROM users WHERE username = $1"#,
+        username,
+    )
+    .fetch_optional(db_pool)
+    .await
+    .context("failed to fetch the stored credentials")?
+    .map(|row| (row.user_id, Secret::new(row.password_hash)));
+    Ok(row)
+}
+
+fn verify_password_hash(
+    expected_password_hash: Secret<String>,
+    password: Secret<String>,
+) -> Result<(), AuthError> {
+    let expected_password_hash = PasswordHash::new(expected_password_hash.expose_secret())
+        .map_err(|e| anyhow::anyhow!(e.to_string()))
+        .context("failed to parse the stored password hash")?;
+    Argon2::default()
+        .verify_password(password.expose_secret().as_bytes(), &expected_password_hash)
+        .map_err(|e| anyhow::anyhow!(e.to_string()))
+        .context("invalid password")
+        .map_err(AuthError::InvalidCredentials)
+}
+
+// middleware refusing the requests without the Basic credentials of an admin from the
+// `users` table
+pub struct AdminAuth;
+
+impl<S, B> Transform<S, ServiceRequest> for AdminAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AdminAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AdminAuthMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AdminAuthMiddleware<S> {
+    // shared with the futures, the request is only forwarded once the credentials are checked
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AdminAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            let db_pool = req
+                .app_data::<web::Data<PgPool>>()
+                .cloned()
+                .expect("the database pool is not registered");
+            let authenticated = match basic_authentication(req.headers()) {
+                Ok(credentials) => validate_credentials(credentials, &db_pool).await,
+                Err(e) => Err(e),
+            };
+            match authenticated {
+                Ok(user_id) => {
+                    tracing::debug!(%user_id, "admin authenticated");
+                    let response = service.call(req).await?;
+                    Ok(response.map_into_left_body())
+                }
+                Err(e) => {
+                    tracing::warn!(error = ?e, "admin request refused");
+                    let response = e.error_response();
+                    Ok(req.into_response(response).map_into_right_body())
+                }
+            }
+        })
+    }
+}
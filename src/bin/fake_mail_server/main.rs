@@ -0,0 +1,67 @@
+use actix_server::configuration::TelemetrySettings;
+use actix_server::telemetry::{
+    get_tracer_provider, get_tracing_subscriber, init_tracing_subscriber,
+};
+use clap::Parser;
+use server::FailureSettings;
+use std::net::TcpListener;
+
+mod server;
+
+/// Postmark-compatible email provider for local development: it keeps the emails instead of
+/// delivering them, and shows them at its address.
+#[derive(clap::Parser, Debug)]
+#[command(name = "fake_mail_server")]
+struct Cli {
+    #[arg(long, env = "FAKE_MAIL_SERVER_HOST", default_value = "127.0.0.1")]
+    host: String,
+    #[arg(long, env = "FAKE_MAIL_SERVER_PORT", default_value_t = 8025)]
+    port: u16,
+    /// Delay added to every response.
+    #[arg(long, default_value_t = 0)]
+    latency_ms: u64,
+    /// Share of the requests that fail, between 0 and 1.
+    #[arg(long, default_value_t = 0.0)]
+    failure_rate: f64,
+    /// Status of the failed requests, e.g. 429 or 500.
+    #[arg(long, default_value_t = 500)]
+    failure_status: u16,
+    /// Delay the 429 responses ask to wait, in seconds.
+    #[arg(long, default_value_t = 1)]
+    retry_after: u64,
+    /// Number of emails kept, the oldest ones are dropped first.
+    #[arg(long, default_value_t = 1000)]
+    max_messages: usize,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let tracer_provider = get_tracer_provider(
+        "fake_mail_server".to_string(),
+        &TelemetrySettings::default(),
+    )?;
+    let tracing_subscriber = get_tracing_subscriber(
+        "fake_mail_server".to_string(),
+        "info".to_string(),
+        std::io::stdout,
+        &tracer_provider,
+    );
+    init_tracing_subscriber(tracing_subscriber, tracer_provider);
+
+    let listener = TcpListener::bind((cli.host.as_str(), cli.port))?;
+    tracing::info!(address = %listener.local_addr()?, "fake mail server listening");
+    server::run(
+        listener,
+        FailureSettings {
+            latency_milliseconds: cli.latency_ms,
+            rate: cli.failure_rate,
+            status: cli.failure_status,
+            retry_after_seconds: cli.retry_after,
+        },
+        cli.max_messages,
+    )?
+    .await?;
+    Ok(())
+}
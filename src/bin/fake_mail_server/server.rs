@@ -0,0 +1,455 @@
+// a Postmark-compatible email provider for local development and end-to-end tests:
+// it keeps the emails it receives and shows them instead of delivering them
+use actix_web::dev::Server;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use chrono::{DateTime, Utc};
+use minijinja::{context, Environment};
+use rand::Rng;
+use regex::Regex;
+use std::collections::VecDeque;
+use std::net::TcpListener;
+use std::sync::Mutex;
+use tracing_actix_web::TracingLogger;
+use uuid::Uuid;
+
+const TEMPLATES: [(&str, &str); 2] = [
+    (
+        "messages.html",
+        include_str!("../../../templates/fake_mail_server/messages.html"),
+    ),
+    (
+        "message.html",
+        include_str!("../../../templates/fake_mail_server/message.html"),
+    ),
+];
+
+// the most messages Postmark accepts in a batch
+const MAX_BATCH_SIZE: usize = 500;
+
+const INVALID_API_TOKEN: i64 = 10;
+const INVALID_EMAIL_REQUEST: i64 = 300;
+
+// failures injected in the responses of `/email` and `/email/batch`, they can be
+// changed while the server runs through `/api/failures`
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct FailureSettings {
+    // added to every response
+    pub latency_milliseconds: u64,
+    // share of the requests that fail, between 0 and 1
+    pub rate: f64,
+    // the status of the failed requests, 429 responses ask to retry after `retry_after_seconds`
+    pub status: u16,
+    pub retry_after_seconds: u64,
+}
+
+impl Default for FailureSettings {
+    fn default() -> Self {
+        Self {
+            latency_milliseconds: 0,
+            rate: 0.0,
+            status: 500,
+            retry_after_seconds: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct StoredMessage {
+    id: Uuid,
+    received_at: DateTime<Utc>,
+    from: String,
+    to: String,
+    subject: String,
+    html_body: Option<String>,
+    text_body: Option<String>,
+    tag: Option<String>,
+    message_stream: Option<String>,
+    // the links of both bodies, in order of appearance
+    links: Vec<String>,
+}
+
+// listed without the bodies
+#[derive(serde::Serialize)]
+struct MessageSummary<'a> {
+    id: Uuid,
+    received_at: DateTime<Utc>,
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+}
+
+impl StoredMessage {
+    fn summary(&self) -> MessageSummary<'_> {
+        MessageSummary {
+            id: self.id,
+            received_at: self.received_at,
+            from: &self.from,
+            to: &self.to,
+            subject: &self.subject,
+        }
+    }
+}
+
+struct Mailbox {
+    // the oldest messages are dropped past `max_messages`
+    messages: Mutex<VecDeque<StoredMessage>>,
+    max_messages: usize,
+    failures: Mutex<FailureSettings>,
+    templates: Environment<'static>,
+}
+
+// the fields `EmailClient` sends, and a few others Postmark accepts
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EmailRequest {
+    from: Option<String>,
+    to: Option<String>,
+    subject: Option<String>,
+    html_body: Option<String>,
+    text_body: Option<String>,
+    tag: Option<String>,
+    message_stream: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EmailResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    submitted_at: Option<DateTime<Utc>>,
+    #[serde(rename = "MessageID", skip_serializing_if = "Option::is_none")]
+    message_id: Option<Uuid>,
+    error_code: i64,
+    message: String,
+}
+
+impl EmailResponse {
+    fn error(error_code: i64, message: &str) -> Self {
+        Self {
+            to: None,
+            submitted_at: None,
+            message_id: None,
+            error_code,
+            message: message.to_string(),
+        }
+    }
+}
+
+pub fn run(
+    listener: TcpListener,
+    failures: FailureSettings,
+    max_messages: usize,
+) -> Result<Server, std::io::Error> {
+    let mut templates = Environment::new();
+    for (name, source) in TEMPLATES {
+        templates
+            .add_template(name, source)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    }
+    let mailbox = web::Data::new(Mailbox {
+        messages: Mutex::new(VecDeque::new()),
+        max_messages,
+        failures: Mutex::new(failures),
+        templates,
+    });
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(TracingLogger::default())
+            .route("/email", web::post().to(send_email))
+            .route("/email/batch", web::post().to(send_batch))
+            .route("/api/messages", web::get().to(list_messages))
+            .route("/api/messages", web::delete().to(clear_messages))
+            .route("/api/messages/{id}", web::get().to(message))
+            .route("/api/failures", web::get().to(get_failures))
+            .route("/api/failures", web::put().to(set_failures))
+            .route("/", web::get().to(messages_page))
+            .route("/messages/{id}", web::get().to(message_page))
+            .route("/messages/{id}/html", web::get().to(message_html))
+            .app_data(mailbox.clone())
+    })
+    .listen(listener)?
+    .run();
+    Ok(server)
+}
+
+async fn send_email(
+    request: HttpRequest,
+    body: web::Json<EmailRequest>,
+    mailbox: web::Data<Mailbox>,
+) -> HttpResponse {
+    if let Some(response) = reject(&request, &mailbox).await {
+        return response;
+    }
+    let response = mailbox.store(body.into_inner());
+    if response.error_code == 0 {
+        HttpResponse::Ok().json(response)
+    } else {
+        HttpResponse::UnprocessableEntity().json(response)
+    }
+}
+
+// Postmark accepts the batch, each message reports its own outcome
+async fn send_batch(
+    request: HttpRequest,
+    body: web::Json<Vec<EmailRequest>>,
+    mailbox: web::Data<Mailbox>,
+) -> HttpResponse {
+    if let Some(response) = reject(&request, &mailbox).await {
+        return response;
+    }
+    if body.len() > MAX_BATCH_SIZE {
+        return HttpResponse::UnprocessableEntity().json(EmailResponse::error(
+            INVALID_EMAIL_REQUEST,
+            &format!(
+                "A batch can't contain more than {} messages.",
+                MAX_BATCH_SIZE
+            ),
+        ));
+    }
+    let responses: Vec<_> = body
+        .into_inner()
+        .into_iter()
+        .map(|email| mailbox.store(email))
+        .collect();
+    HttpResponse::Ok().json(responses)
+}
+
+// the response to send instead of accepting the emails: the missing token, or an injected failure
+async fn reject(request: &HttpRequest, mailbox: &Mailbox) -> Option<HttpResponse> {
+    let failures = mailbox.failures.lock().unwrap().clone();
+    tokio::time::sleep(std::time::Duration::from_millis(
+        failures.latency_milliseconds,
+    ))
+    .await;
+
+    let has_token = request
+        .headers()
+        .get("X-Postmark-Server-Token")
+        .is_some_and(|token| !token.is_empty());
+    if !has_token {
+        return Some(HttpResponse::Unauthorized().json(EmailResponse::error(
+            INVALID_API_TOKEN,
+            "No Account or Server API tokens were supplied in the HTTP headers.",
+        )));
+    }
+
+    if failures.rate <= 0.0 || rand::thread_rng().gen::<f64>() >= failures.rate {
+        return None;
+    }
+    let status = StatusCode::from_u16(failures.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut response = HttpResponse::build(status);
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        response.insert_header((
+            header::RETRY_AFTER,
+            failures.retry_after_seconds.to_string(),
+        ));
+    }
+    Some(response.body("failure injected by the fake mail server"))
+}
+
+impl Mailbox {
+    fn store(&self, email: EmailRequest) -> EmailResponse {
+        let to = match email.to {
+            Some(to) if !to.trim().is_empty() => to,
+            _ => return EmailResponse::error(INVALID_EMAIL_REQUEST, "Invalid 'To' address: ''."),
+        };
+        let from = match email.from {
+            Some(from) if !from.trim().is_empty() => from,
+            _ => return EmailResponse::error(INVALID_EMAIL_REQUEST, "Invalid 'From' address: ''."),
+        };
+        if email.html_body.is_none() && email.text_body.is_none() {
+            return EmailResponse::error(
+                INVALID_EMAIL_REQUEST,
+                "Provide either email TextBody or HtmlBody or both.",
+            );
+        }
+
+        let links = extract_links(
+            email
+                .html_body
+                .iter()
+                .chain(email.text_body.iter())
+                .map(String::as_str),
+        );
+        let message = StoredMessage {
+            id: Uuid::new_v4(),
+            received_at: Utc::now(),
+            from,
+            to,
+            subject: email.subject.unwrap_or_default(),
+            html_body: email.html_body,
+            text_body: email.text_body,
+            tag: email.tag,
+            message_stream: email.message_stream,
+            links,
+        };
+        tracing::info!(message_id = %message.id, to = %message.to, subject = %message.subject, "email received");
+        let response = EmailResponse {
+            to: Some(message.to.clone()),
+            submitted_at: Some(message.received_at),
+            message_id: Some(message.id),
+            error_code: 0,
+            message: "OK".into(),
+        };
+        let mut messages = self.messages.lock().unwrap();
+        messages.push_back(message);
+        while messages.len() > self.max_messages {
+            messages.pop_front();
+        }
+        response
+    }
+
+    fn find(&self, id: Uuid) -> Option<StoredMessage> {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|message| message.id == id)
+            .cloned()
+    }
+}
+
+// the URLs in the bodies, without duplicates
+fn extract_links<'a>(bodies: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let link_regex = Regex::new(r#"https?://[^\s"'<>]+"#).unwrap();
+    let mut links: Vec<String> = Vec::new();
+    for body in bodies {
+        for found in link_regex.find_iter(body) {
+            // the links of the HTML body have their `&` escaped
+            let link = found.as_str().replace("&amp;", "&");
+            if !links.contains(&link) {
+                links.push(link);
+            }
+        }
+    }
+    links
+}
+
+#[derive(serde::Deserialize)]
+struct ListParameters {
+    // only the messages sent to this address
+    to: Option<String>,
+}
+
+// the most recent messages first
+async fn list_messages(
+    parameters: web::Query<ListParameters>,
+    mailbox: web::Data<Mailbox>,
+) -> HttpResponse {
+    let messages = mailbox.messages.lock().unwrap();
+    let summaries: Vec<_> = messages
+        .iter()
+        .rev()
+        .filter(|message| parameters.to.as_ref().is_none_or(|to| &message.to == to))
+        .map(StoredMessage::summary)
+        .collect();
+    HttpResponse::Ok().json(serde_json::json!({ "messages": summaries }))
+}
+
+async fn clear_messages(mailbox: web::Data<Mailbox>) -> HttpResponse {
+    mailbox.messages.lock().unwrap().clear();
+    HttpResponse::NoContent().finish()
+}
+
+async fn message(id: web::Path<Uuid>, mailbox: web::Data<Mailbox>) -> HttpResponse {
+    match mailbox.find(id.into_inner()) {
+        Some(message) => HttpResponse::Ok().json(message),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn get_failures(mailbox: web::Data<Mailbox>) -> HttpResponse {
+    HttpResponse::Ok().json(mailbox.failures.lock().unwrap().clone())
+}
+
+async fn set_failures(
+    failures: web::Json<FailureSettings>,
+    mailbox: web::Data<Mailbox>,
+) -> HttpResponse {
+    let failures = failures.into_inner();
+    if !(0.0..=1.0).contains(&failures.rate) {
+        return HttpResponse::BadRequest().body("the failure rate must be between 0 and 1");
+    }
+    tracing::info!(?failures, "failures injected");
+    *mailbox.failures.lock().unwrap() = failures.clone();
+    HttpResponse::Ok().json(failures)
+}
+
+async fn messages_page(mailbox: web::Data<Mailbox>) -> HttpResponse {
+    let messages: Vec<_> = mailbox
+        .messages
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .cloned()
+        .collect();
+    let summaries: Vec<_> = messages.iter().map(StoredMessage::summary).collect();
+    render(
+        &mailbox,
+        "messages.html",
+        context! { messages => summaries, failures => mailbox.failures.lock().unwrap().clone() },
+    )
+}
+
+async fn message_page(id: web::Path<Uuid>, mailbox: web::Data<Mailbox>) -> HttpResponse {
+    match mailbox.find(id.into_inner()) {
+        Some(message) => render(&mailbox, "message.html", context! { message }),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// the HTML body on its own, the message page shows it in a sandboxed frame
+async fn message_html(id: web::Path<Uuid>, mailbox: web::Data<Mailbox>) -> HttpResponse {
+    match mailbox
+        .find(id.into_inner())
+        .and_then(|message| message.html_body)
+    {
+        Some(html_body) => HttpResponse::Ok()
+            .content_type(mime::TEXT_HTML_UTF_8)
+            .insert_header((header::CONTENT_SECURITY_POLICY, "sandbox"))
+            .body(html_body),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+fn render(mailbox: &Mailbox, name: &str, context: minijinja::Value) -> HttpResponse {
+    match mailbox
+        .templates
+        .get_template(name)
+        .and_then(|template| template.render(context))
+    {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(mime::TEXT_HTML_UTF_8)
+            .body(body),
+        Err(e) => {
+            tracing::error!(error = ?e, template = name, "failed to render the page");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_links;
+
+    #[test]
+    fn links_are_extracted_from_both_bodies_once() {
+        let html = r#"<a href="https://example.com/confirm?a=1&amp;b=2">Confirm</a>"#;
+        let text = "Confirm at https://example.com/confirm?a=1&b=2 or visit http://example.com/";
+
+        let links = extract_links([html, text].into_iter());
+
+        assert_eq!(
+            links,
+            vec![
+                "https://example.com/confirm?a=1&b=2".to_string(),
+                "http://example.com/".to_string()
+            ]
+        );
+    }
+}
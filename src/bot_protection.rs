@@ -0,0 +1,572 @@
+use crate::configuration::BotProtectionSettings;
+use crate::signing::{sign, verify};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use secrecy::Secret;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+// name of the field hidden from humans, only bots fill it in
+pub const HONEYPOT_FIELD: &str = "website";
+
+// what the defences look at in a subscription request
+#[derive(Default)]
+pub struct Submission<'a> {
+    // as submitted, the proof of work is bound to it
+    pub email: &'a str,
+    pub honeypot: Option<&'a str>,
+    pub form_token: Option<&'a str>,
+    pub pow_challenge: Option<&'a str>,
+    pub pow_nonce: Option<&'a str>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum Rejection {
+    #[error("the honeypot field was filled in")]
+    HoneypotFilled,
+    #[error("the form token is missing")]
+    MissingFormToken,
+    #[error("the form token is not valid")]
+    InvalidFormToken,
+    #[error("the form was submitted too fast")]
+    SubmittedTooFast,
+    #[error("the form token expired")]
+    FormTokenExpired,
+    #[error("the form token was already used")]
+    FormTokenReused,
+    #[error("the proof of work is missing")]
+    MissingProofOfWork,
+    #[error("the proof of work challenge is not valid")]
+    InvalidChallenge,
+    #[error("the proof of work challenge expired")]
+    ChallengeExpired,
+    #[error("the proof of work does not solve the challenge")]
+    InvalidProofOfWork,
+    #[error("the proof of work challenge was already used")]
+    ChallengeReused,
+}
+
+// a single check against automated submissions
+pub trait BotDefence: Send + Sync {
+    // the hidden fields to embed in the form, sent back on submission
+    fn issue(&self, _now: DateTime<Utc>) -> BTreeMap<&'static str, String> {
+        BTreeMap::new()
+    }
+
+    // verifies the submission without changing any state, so that it can be sent again
+    // if another check fails
+    fn check(&self, submission: &Submission, now: DateTime<Utc>) -> Result<(), Rejection>;
+
+    // marks the single-use values of a submission as used, once it's accepted
+    fn spend(&self, _submission: &Submission, _now: DateTime<Utc>) -> Result<(), Rejection> {
+        Ok(())
+    }
+}
+
+pub struct Honeypot;
+
+impl BotDefence for Honeypot {
+    fn check(&self, submission: &Submission, _now: DateTime<Utc>) -> Result<(), Rejection> {
+        match submission.honeypot {
+            Some(value) if !value.is_empty() => Err(Rejection::HoneypotFilled),
+            _ => Ok(()),
+        }
+    }
+}
+
+// the salts of the tokens already used, kept until the tokens expire so that each token
+// is accepted once. They are kept in memory: each instance of the application only knows
+// the tokens it accepted itself.
+#[derive(Default)]
+struct SpentSalts {
+    expires_at: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl SpentSalts {
+    fn is_spent(&self, salt: &str, now: DateTime<Utc>) -> bool {
+        self.expires_at
+            .lock()
+            .unwrap()
+            .get(salt)
+            .is_some_and(|expires_at| *expires_at > now)
+    }
+
+    // `false` if the salt was already spent
+    fn spend(&self, salt: &str, expires_at: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        let mut spent = self.expires_at.lock().unwrap();
+        spent.retain(|_, expires_at| *expires_at > now);
+        spent.insert(salt.to_string(), expires_at).is_none()
+    }
+}
+
+// a token signed when the form is served, humans need some time to fill the form in
+pub struct MinimumTimeToSubmit {
+    signing_key: Secret<String>,
+    min_time: Duration,
+    max_age: Duration,
+    spent: SpentSalts,
+}
+
+impl MinimumTimeToSubmit {
+    pub fn new(signing_key: Secret<String>, min_time: Duration, max_age: Duration) -> Self {
+        Self {
+            signing_key,
+            min_time,
+            max_age,
+            spent: SpentSalts::default(),
+        }
+    }
+
+    // the salt of a valid token, and when it stops being accepted
+    fn verify_token(
+        &self,
+        submission: &Submission,
+        now: DateTime<Utc>,
+    ) -> Result<(String, DateTime<Utc>), Rejection> {
+        let token = submission.form_token.ok_or(Rejection::MissingFormToken)?;
+        let (issued_at, salt) = verify(&self.signing_key, token)
+            .and_then(|payload| parse_salted_timestamp(payload.strip_prefix("form:")?))
+            .ok_or(Rejection::InvalidFormToken)?;
+
+        let elapsed = now - issued_at;
+        if elapsed < self.min_time {
+            Err(Rejection::SubmittedTooFast)
+        } else if elapsed > self.max_age {
+            Err(Rejection::FormTokenExpired)
+        } else if self.spent.is_spent(&salt, now) {
+            Err(Rejection::FormTokenReused)
+        } else {
+            Ok((salt, issued_at + self.max_age))
+        }
+    }
+}
+
+impl BotDefence for MinimumTimeToSubmit {
+    fn issue(&self, now: DateTime<Utc>) -> BTreeMap<&'static str, String> {
+        let token = sign(
+            &self.signing_key,
+            &format!("form:{}:{}", now.timestamp_millis(), salt()),
+        );
+        BTreeMap::from([("form_token", token)])
+    }
+
+    fn check(&self, submission: &Submission, now: DateTime<Utc>) -> Result<(), Rejection> {
+        self.verify_token(submission, now).map(|_| ())
+    }
+
+    fn spend(&self, submission: &Submission, now: DateTime<Utc>) -> Result<(), Rejection> {
+        let (salt, expires_at) = self.verify_token(submission, now)?;
+        // another submission of the same form may have been accepted since it was checked
+        if self.spent.spend(&salt, expires_at, now) {
+            Ok(())
+        } else {
+            Err(Rejection::FormTokenReused)
+        }
+    }
+}
+
+// hashcash-like challenge: the client looks for a nonce such that
+// `SHA-256("<challenge>:<email>:<nonce>")` starts with `difficulty` zero bits, so that
+// a solution only works for the address it was computed for.
+// The challenge is signed, so it's verified without storing it, and it's accepted once.
+pub struct ProofOfWork {
+    signing_key: Secret<String>,
+    difficulty: u32,
+    max_age: Duration,
+    spent: SpentSalts,
+}
+
+impl ProofOfWork {
+    pub fn new(signing_key: Secret<String>, difficulty: u32, max_age: Duration) -> Self {
+        Self {
+            signing_key,
+            difficulty,
+            max_age,
+            spent: SpentSalts::default(),
+        }
+    }
+
+    // the reference solver, what clients are expected to run
+    pub fn solve(challenge: &str, email: &str, difficulty: u32) -> String {
+        (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| leading_zero_bits(challenge, email, nonce) >= difficulty)
+            .unwrap()
+    }
+
+    // the salt of a solved challenge, and when it stops being accepted
+    fn verify_solution(
+        &self,
+        submission: &Submission,
+        now: DateTime<Utc>,
+    ) -> Result<(String, DateTime<Utc>), Rejection> {
+        let (challenge, nonce) = submission
+            .pow_challenge
+            .zip(submission.pow_nonce)
+            .ok_or(Rejection::MissingProofOfWork)?;
+        let (issued_at, salt) = verify(&self.signing_key, challenge)
+            .and_then(|payload| parse_salted_timestamp(payload.strip_prefix("pow:")?))
+            .ok_or(Rejection::InvalidChallenge)?;
+
+        if now - issued_at > self.max_age {
+            Err(Rejection::ChallengeExpired)
+        } else if leading_zero_bits(challenge, submission.email, nonce) < self.difficulty {
+            Err(Rejection::InvalidProofOfWork)
+        } else if self.spent.is_spent(&salt, now) {
+            Err(Rejection::ChallengeReused)
+        } else {
+            Ok((salt, issued_at + self.max_age))
+        }
+    }
+}
+
+impl BotDefence for ProofOfWork {
+    fn issue(&self, now: DateTime<Utc>) -> BTreeMap<&'static str, String> {
+        let challenge = sign(
+            &self.signing_key,
+            &format!("pow:{}:{}", now.timestamp_millis(), salt()),
+        );
+        BTreeMap::from([
+            ("pow_challenge", challenge),
+            ("pow_difficulty", self.difficulty.to_string()),
+        ])
+    }
+
+    fn check(&self, submission: &Submission, now: DateTime<Utc>) -> Result<(), Rejection> {
+        self.verify_solution(submission, now).map(|_| ())
+    }
+
+    fn spend(&self, submission: &Submission, now: DateTime<Utc>) -> Result<(), Rejection> {
+        let (salt, expires_at) = self.verify_solution(submission, now)?;
+        if self.spent.spend(&salt, expires_at, now) {
+            Ok(())
+        } else {
+            Err(Rejection::ChallengeReused)
+        }
+    }
+}
+
+// tells apart the tokens issued in the same millisecond
+fn salt() -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .map(char::from)
+        .take(16)
+        .collect()
+}
+
+// `<timestamp in milliseconds>:<salt>`
+fn parse_salted_timestamp(payload: &str) -> Option<(DateTime<Utc>, String)> {
+    let (millis, salt) = payload.split_once(':')?;
+    let issued_at = Utc.timestamp_millis_opt(millis.parse().ok()?).single()?;
+    Some((issued_at, salt.to_string()))
+}
+
+fn leading_zero_bits(challenge: &str, email: &str, nonce: &str) -> u32 {
+    let hash = Sha256::digest(format!("{}:{}:{}", challenge, email, nonce).as_bytes());
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+// the defences enabled for the signup form, a submission must pass all of them
+pub struct BotProtection {
+    defences: Vec<Box<dyn BotDefence>>,
+}
+
+impl BotProtection {
+    pub fn new(defences: Vec<Box<dyn BotDefence>>) -> Self {
+        Self { defences }
+    }
+
+    pub fn from_settings(settings: &BotProtectionSettings) -> Self {
+        let max_age = Duration::seconds(settings.form_token_max_age_seconds as i64);
+        let mut defences: Vec<Box<dyn BotDefence>> = Vec::new();
+        if settings.honeypot {
+            defences.push(Box::new(Honeypot));
+        }
+        if let Some(min_time) = settings.min_submit_milliseconds {
+            defences.push(Box::new(MinimumTimeToSubmit::new(
+                settings.signing_key.clone(),
+                Duration::milliseconds(min_time as i64),
+                max_age,
+            )));
+        }
+        if let Some(difficulty) = settings.proof_of_work_difficulty {
+            defences.push(Box::new(ProofOfWork::new(
+                settings.signing_key.clone(),
+                difficulty,
+                max_age,
+            )));
+        }
+        Self::new(defences)
+    }
+
+    pub fn issue(&self, now: DateTime<Utc>) -> BTreeMap<&'static str, String> {
+        self.defences
+            .iter()
+            .flat_map(|defence| defence.issue(now))
+            .collect()
+    }
+
+    pub fn check(&self, submission: &Submission, now: DateTime<Utc>) -> Result<(), Rejection> {
+        self.defences
+            .iter()
+            .try_for_each(|defence| defence.check(submission, now))
+    }
+
+    // to call once the submission is accepted, a rejected one can be corrected and sent again
+    pub fn spend(&self, submission: &Submission, now: DateTime<Utc>) -> Result<(), Rejection> {
+        self.defences
+            .iter()
+            .try_for_each(|defence| defence.spend(submission, now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BotDefence, Honeypot, MinimumTimeToSubmit, ProofOfWork, Rejection, SpentSalts, Submission,
+    };
+    use chrono::{Duration, Utc};
+    use claim::assert_ok;
+    use secrecy::Secret;
+
+    const EMAIL: &str = "alpha@smail.com";
+
+    fn key() -> Secret<String> {
+        Secret::new("a-very-secret-key".to_string())
+    }
+
+    #[test]
+    fn a_filled_honeypot_is_rejected() {
+        let now = Utc::now();
+        assert_ok!(Honeypot.check(&Submission::default(), now));
+        assert_ok!(Honeypot.check(
+            &Submission {
+                honeypot: Some(""),
+                ..Submission::default()
+            },
+            now
+        ));
+        assert_eq!(
+            Honeypot.check(
+                &Submission {
+                    honeypot: Some("https://spam.example"),
+                    ..Submission::default()
+                },
+                now
+            ),
+            Err(Rejection::HoneypotFilled)
+        );
+    }
+
+    #[test]
+    fn the_form_token_must_be_old_enough_but_not_expired() {
+        let defence =
+            MinimumTimeToSubmit::new(key(), Duration::seconds(3), Duration::seconds(3600));
+        let issued_at = Utc::now();
+        let fields = defence.issue(issued_at);
+        let submission = Submission {
+            form_token: Some(&fields["form_token"]),
+            ..Submission::default()
+        };
+
+        assert_eq!(
+            defence.check(&submission, issued_at + Duration::seconds(1)),
+            Err(Rejection::SubmittedTooFast)
+        );
+        assert_ok!(defence.check(&submission, issued_at + Duration::seconds(5)));
+        assert_eq!(
+            defence.check(&submission, issued_at + Duration::hours(2)),
+            Err(Rejection::FormTokenExpired)
+        );
+    }
+
+    #[test]
+    fn a_forged_form_token_is_rejected() {
+        let defence = MinimumTimeToSubmit::new(key(), Duration::zero(), Duration::seconds(3600));
+        let forged = MinimumTimeToSubmit::new(
+            Secret::new("another-key".into()),
+            Duration::zero(),
+            Duration::seconds(3600),
+        )
+        .issue(Utc::now() - Duration::minutes(1));
+
+        assert_eq!(
+            defence.check(&Submission::default(), Utc::now()),
+            Err(Rejection::MissingFormToken)
+        );
+        assert_eq!(
+            defence.check(
+                &Submission {
+                    form_token: Some(&forged["form_token"]),
+                    ..Submission::default()
+                },
+                Utc::now()
+            ),
+            Err(Rejection::InvalidFormToken)
+        );
+    }
+
+    #[test]
+    fn a_solved_challenge_is_accepted() {
+        let defence = ProofOfWork::new(key(), 8, Duration::seconds(3600));
+        let now = Utc::now();
+        let fields = defence.issue(now);
+        let nonce = ProofOfWork::solve(&fields["pow_challenge"], EMAIL, 8);
+
+        assert_eq!(fields["pow_difficulty"], "8");
+        assert_ok!(defence.check(
+            &Submission {
+                email: EMAIL,
+                pow_challenge: Some(&fields["pow_challenge"]),
+                pow_nonce: Some(&nonce),
+                ..Submission::default()
+            },
+            now
+        ));
+    }
+
+    #[test]
+    fn an_unsolved_challenge_is_rejected() {
+        let defence = ProofOfWork::new(key(), 16, Duration::seconds(3600));
+        let now = Utc::now();
+        let fields = defence.issue(now);
+        let challenge = &fields["pow_challenge"];
+        // find a nonce that doesn't solve the challenge
+        let nonce = (0u64..)
+            .map(|n| n.to_string())
+            .find(|n| super::leading_zero_bits(challenge, EMAIL, n) < 16)
+            .unwrap();
+
+        assert_eq!(
+            defence.check(
+                &Submission {
+                    email: EMAIL,
+                    pow_challenge: Some(challenge),
+                    pow_nonce: Some(&nonce),
+                    ..Submission::default()
+                },
+                now
+            ),
+            Err(Rejection::InvalidProofOfWork)
+        );
+        assert_eq!(
+            defence.check(&Submission::default(), now),
+            Err(Rejection::MissingProofOfWork)
+        );
+        assert_eq!(
+            defence.check(
+                &Submission {
+                    email: EMAIL,
+                    pow_challenge: Some(challenge),
+                    pow_nonce: Some(&ProofOfWork::solve(challenge, EMAIL, 16)),
+                    ..Submission::default()
+                },
+                now + Duration::hours(2)
+            ),
+            Err(Rejection::ChallengeExpired)
+        );
+    }
+
+    #[test]
+    fn a_form_token_is_accepted_once() {
+        let defence = MinimumTimeToSubmit::new(key(), Duration::zero(), Duration::seconds(3600));
+        let now = Utc::now();
+        let fields = defence.issue(now);
+        let submission = Submission {
+            form_token: Some(&fields["form_token"]),
+            ..Submission::default()
+        };
+
+        // checking doesn't use the token up
+        assert_ok!(defence.check(&submission, now));
+        assert_ok!(defence.check(&submission, now));
+        assert_ok!(defence.spend(&submission, now));
+        assert_eq!(
+            defence.check(&submission, now),
+            Err(Rejection::FormTokenReused)
+        );
+        assert_eq!(
+            defence.spend(&submission, now),
+            Err(Rejection::FormTokenReused)
+        );
+        // another form served at the same time gets its own token
+        assert_ok!(defence.check(
+            &Submission {
+                form_token: Some(&defence.issue(now)["form_token"]),
+                ..Submission::default()
+            },
+            now
+        ));
+    }
+
+    #[test]
+    fn a_replayed_challenge_is_rejected() {
+        let defence = ProofOfWork::new(key(), 8, Duration::seconds(3600));
+        let now = Utc::now();
+        let fields = defence.issue(now);
+        let challenge = &fields["pow_challenge"];
+        let submission = Submission {
+            email: EMAIL,
+            pow_challenge: Some(challenge),
+            pow_nonce: Some(&ProofOfWork::solve(challenge, EMAIL, 8)),
+            ..Submission::default()
+        };
+
+        assert_ok!(defence.check(&submission, now));
+        assert_ok!(defence.spend(&submission, now));
+        assert_eq!(
+            defence.check(&submission, now + Duration::seconds(1)),
+            Err(Rejection::ChallengeReused)
+        );
+        assert_eq!(
+            defence.spend(&submission, now + Duration::seconds(1)),
+            Err(Rejection::ChallengeReused)
+        );
+    }
+
+    #[test]
+    fn a_solution_only_works_for_its_email() {
+        let defence = ProofOfWork::new(key(), 16, Duration::seconds(3600));
+        let now = Utc::now();
+        let fields = defence.issue(now);
+        let challenge = &fields["pow_challenge"];
+        let nonce = ProofOfWork::solve(challenge, EMAIL, 16);
+        let other_email = (0u64..)
+            .map(|n| format!("beta{}@smail.com", n))
+            .find(|email| super::leading_zero_bits(challenge, email, &nonce) < 16)
+            .unwrap();
+
+        assert_eq!(
+            defence.check(
+                &Submission {
+                    email: &other_email,
+                    pow_challenge: Some(challenge),
+                    pow_nonce: Some(&nonce),
+                    ..Submission::default()
+                },
+                now
+            ),
+            Err(Rejection::InvalidProofOfWork)
+        );
+    }
+
+    #[test]
+    fn spent_salts_are_forgotten_once_expired() {
+        let spent = SpentSalts::default();
+        let now = Utc::now();
+
+        assert!(spent.spend("salt", now + Duration::seconds(1), now));
+        assert!(!spent.spend("salt", now + Duration::seconds(1), now));
+        assert!(spent.spend("other", now, now + Duration::seconds(2)));
+        assert!(spent.expires_at.lock().unwrap().get("salt").is_none());
+    }
+}
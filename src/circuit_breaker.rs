@@ -0,0 +1,268 @@
+use crate::configuration::CircuitBreakerSettings;
+use crate::metrics::METRICS;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    // the requests go through, their outcomes are recorded
+    Closed,
+    // too many requests failed, they are refused until the cool-down is over
+    Open,
+    // the cool-down is over, a single trial request decides whether the circuit closes again
+    HalfOpen,
+}
+
+impl CircuitState {
+    const ALL: [CircuitState; 3] = [
+        CircuitState::Closed,
+        CircuitState::Open,
+        CircuitState::HalfOpen,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+// stops calling a dependency that keeps failing, so that the callers fail fast
+// instead of waiting for each request to time out
+pub struct CircuitBreaker {
+    // used to label the metrics
+    name: String,
+    settings: CircuitBreakerSettings,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    state: CircuitState,
+    // the outcomes of the last requests while closed, `true` for the failures
+    outcomes: VecDeque<bool>,
+    opened_at: Instant,
+    // when the trial request was let through while half-open
+    trial_started_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: String, settings: CircuitBreakerSettings) -> Self {
+        let breaker = Self {
+            name,
+            settings,
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                outcomes: VecDeque::new(),
+                opened_at: Instant::now(),
+                trial_started_at: None,
+            }),
+        };
+        breaker.observe(CircuitState::Closed);
+        breaker
+    }
+
+    pub fn state(&self, now: Instant) -> CircuitState {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            // the next request will be the trial
+            CircuitState::Open if now >= inner.opened_at + self.settings.cooldown() => {
+                CircuitState::HalfOpen
+            }
+            state => state,
+        }
+    }
+
+    // whether a request can be sent, otherwise the time left before the circuit lets one through
+    pub fn acquire(&self, now: Instant) -> Result<(), Duration> {
+        if !self.settings.enabled {
+            return Ok(());
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let cooldown = self.settings.cooldown();
+        match inner.state {
+            CircuitState::Closed => Ok(()),
+            CircuitState::Open => {
+                let reopens_at = inner.opened_at + cooldown;
+                if now < reopens_at {
+                    return Err(reopens_at - now);
+                }
+                inner.trial_started_at = Some(now);
+                self.transition(&mut inner, CircuitState::HalfOpen);
+                Ok(())
+            }
+            CircuitState::HalfOpen => match inner.trial_started_at {
+                // a trial that never reported back, e.g. because it was cancelled,
+                // doesn't keep the circuit half-open forever
+                Some(started_at) if now < started_at + cooldown => Err(started_at + cooldown - now),
+                _ => {
+                    inner.trial_started_at = Some(now);
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    pub fn record(&self, failed: bool, now: Instant) {
+        if !self.settings.enabled {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::HalfOpen if failed => {
+                inner.opened_at = now;
+                inner.trial_started_at = None;
+                self.transition(&mut inner, CircuitState::Open);
+            }
+            CircuitState::HalfOpen => {
+                inner.outcomes.clear();
+                inner.trial_started_at = None;
+                self.transition(&mut inner, CircuitState::Closed);
+            }
+            CircuitState::Closed => {
+                inner.outcomes.push_back(failed);
+                while inner.outcomes.len() > self.settings.window_size {
+                    inner.outcomes.pop_front();
+                }
+                let failures = inner.outcomes.iter().filter(|failed| **failed).count();
+                if inner.outcomes.len() >= self.settings.minimum_requests
+                    && failures as f64
+                        >= self.settings.failure_rate_threshold * inner.outcomes.len() as f64
+                {
+                    inner.outcomes.clear();
+                    inner.opened_at = now;
+                    self.transition(&mut inner, CircuitState::Open);
+                }
+            }
+            // the requests sent before the circuit opened don't change anything
+            CircuitState::Open => {}
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, state: CircuitState) {
+        tracing::warn!(
+            circuit = %self.name,
+            from = inner.state.as_str(),
+            to = state.as_str(),
+            "circuit breaker state changed"
+        );
+        inner.state = state;
+        self.observe(state);
+    }
+
+    fn observe(&self, current: CircuitState) {
+        for state in CircuitState::ALL {
+            METRICS
+                .circuit_breaker_state
+                .with_label_values(&[&self.name, state.as_str()])
+                .set((state == current) as i64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitState};
+    use crate::configuration::CircuitBreakerSettings;
+    use claim::{assert_err, assert_ok};
+    use std::time::{Duration, Instant};
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test".into(),
+            CircuitBreakerSettings {
+                enabled: true,
+                failure_rate_threshold: 0.5,
+                window_size: 4,
+                minimum_requests: 4,
+                cooldown_milliseconds: 1000,
+            },
+        )
+    }
+
+    #[test]
+    fn the_circuit_opens_when_the_failure_rate_reaches_the_threshold() {
+        let breaker = breaker();
+        let now = Instant::now();
+
+        breaker.record(true, now);
+        breaker.record(false, now);
+        breaker.record(false, now);
+        assert_eq!(breaker.state(now), CircuitState::Closed);
+        breaker.record(true, now);
+
+        assert_eq!(breaker.state(now), CircuitState::Open);
+        assert_eq!(
+            breaker.acquire(now + Duration::from_millis(400)),
+            Err(Duration::from_millis(600))
+        );
+    }
+
+    #[test]
+    fn the_circuit_needs_a_minimum_of_requests_to_open() {
+        let breaker = breaker();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            breaker.record(true, now);
+        }
+
+        assert_eq!(breaker.state(now), CircuitState::Closed);
+        assert_ok!(breaker.acquire(now));
+    }
+
+    #[test]
+    fn a_single_trial_is_let_through_after_the_cooldown() {
+        let breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..4 {
+            breaker.record(true, now);
+        }
+        let later = now + Duration::from_secs(1);
+
+        assert_eq!(breaker.state(later), CircuitState::HalfOpen);
+        assert_ok!(breaker.acquire(later));
+        assert_err!(breaker.acquire(later));
+
+        breaker.record(false, later);
+        assert_eq!(breaker.state(later), CircuitState::Closed);
+        assert_ok!(breaker.acquire(later));
+    }
+
+    #[test]
+    fn a_failed_trial_opens_the_circuit_again() {
+        let breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..4 {
+            breaker.record(true, now);
+        }
+        let later = now + Duration::from_secs(1);
+        assert_ok!(breaker.acquire(later));
+
+        breaker.record(true, later);
+
+        assert_eq!(breaker.state(later), CircuitState::Open);
+        assert_err!(breaker.acquire(later + Duration::from_millis(999)));
+    }
+
+    #[test]
+    fn a_disabled_breaker_lets_every_request_through() {
+        let breaker = CircuitBreaker::new(
+            "test".into(),
+            CircuitBreakerSettings {
+                enabled: false,
+                ..CircuitBreakerSettings::default()
+            },
+        );
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            breaker.record(true, now);
+        }
+
+        assert_ok!(breaker.acquire(now));
+    }
+}
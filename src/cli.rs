@@ -0,0 +1,175 @@
+use crate::authentication::create_admin;
+use crate::configuration::Settings;
+use crate::confirmation::{reissue_legacy_confirmations, Confirmations};
+use crate::startup::{get_connection_pool, Application, Worker, MIGRATOR};
+use anyhow::Context;
+use secrecy::Secret;
+use std::io::BufRead;
+
+/// Newsletter service: one binary for every role of the deployment.
+#[derive(clap::Parser, Debug)]
+#[command(name = "actix_server")]
+pub struct Cli {
+    /// Defaults to `serve`.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug, PartialEq)]
+pub enum Command {
+    /// Run the HTTP server.
+    Serve,
+    /// Run the background workers, without the HTTP server.
+    Worker,
+    /// Apply the pending database migrations.
+    ///
+    /// The subscribers still waiting to confirm with a token issued before the tokens were
+    /// stored hashed are then sent a new confirmation link.
+    Migrate,
+    /// Create an administrator account.
+    ///
+    /// The password is read from the `ADMIN_PASSWORD` environment variable if set,
+    /// otherwise from the first line of the standard input.
+    CreateAdmin {
+        #[arg(long)]
+        username: String,
+    },
+    /// Inspect the configuration.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(clap::Subcommand, Debug, PartialEq)]
+pub enum ConfigCommand {
+    /// Load and validate the configuration, then print it with the secrets redacted.
+    Check,
+}
+
+pub async fn serve(configuration: Settings) -> Result<(), anyhow::Error> {
+    let application = Application::build(configuration).await?;
+    application.run_until_stopped().await?;
+    Ok(())
+}
+
+pub async fn work(configuration: Settings) -> Result<(), anyhow::Error> {
+    let worker = Worker::build(configuration).await?;
+    worker.run_until_stopped().await?;
+    Ok(())
+}
+
+pub async fn migrate(configuration: Settings) -> Result<(), anyhow::Error> {
+    let db_pool = get_connection_pool(&configuration.database).await;
+    MIGRATOR
+        .run(&db_pool)
+        .await
+        .context("failed to apply the migrations")?;
+    tracing::info!("the database is up to date");
+
+    // the emails are queued, the relay of the server or of the workers delivers them
+    let confirmations = Confirmations::new(
+        configuration.confirmation,
+        configuration.application.base_url,
+    );
+    let reissued = reissue_legacy_confirmations(&db_pool, &confirmations).await?;
+    if reissued > 0 {
+        tracing::info!(reissued, "confirmation emails queued for the legacy tokens");
+    }
+    Ok(())
+}
+
+pub async fn add_admin(
+    configuration: Settings,
+    username: String,
+    password: Secret<String>,
+) -> Result<(), anyhow::Error> {
+    let db_pool = get_connection_pool(&configuration.database).await;
+    let user_id = create_admin(&db_pool, &username, password).await?;
+    tracing::info!(%user_id, %username, "admin created");
+    Ok(())
+}
+
+// never taken from the arguments, they end up in the shell history and in `ps`
+pub fn admin_password(
+    from_env: Option<String>,
+    mut stdin: impl BufRead,
+) -> Result<Secret<String>, anyhow::Error> {
+    if let Some(password) = from_env {
+        return Ok(Secret::new(password));
+    }
+    let mut line = String::new();
+    stdin
+        .read_line(&mut line)
+        .context("failed to read the password from the standard input")?;
+    Ok(Secret::new(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+// secrets are wrapped in `Secret`, so their debug representation is redacted
+pub fn check_config(configuration: &Settings) -> Result<String, anyhow::Error> {
+    configuration
+        .validate()
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("the configuration is not valid")?;
+    Ok(format!("{:#?}", configuration))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{admin_password, Cli, Command, ConfigCommand};
+    use clap::Parser;
+    use secrecy::ExposeSecret;
+
+    #[test]
+    fn serve_is_the_default_command() {
+        let cli = Cli::try_parse_from(["actix_server"]).unwrap();
+        assert_eq!(cli.command, None);
+    }
+
+    #[test]
+    fn create_admin_requires_a_username() {
+        assert!(Cli::try_parse_from(["actix_server", "create-admin"]).is_err());
+    }
+
+    #[test]
+    fn create_admin_does_not_take_the_password_as_an_argument() {
+        assert!(Cli::try_parse_from([
+            "actix_server",
+            "create-admin",
+            "--username",
+            "admin",
+            "--password",
+            "secret",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn the_admin_password_comes_from_the_environment_first() {
+        let password = admin_password(Some("from-env".into()), "from-stdin\n".as_bytes()).unwrap();
+        assert_eq!(password.expose_secret(), "from-env");
+
+        let password = admin_password(None, "from-stdin\r\nignored\n".as_bytes()).unwrap();
+        assert_eq!(password.expose_secret(), "from-stdin");
+    }
+
+    #[test]
+    fn subcommands_are_parsed() {
+        let cli =
+            Cli::try_parse_from(["actix_server", "create-admin", "--username", "admin"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::CreateAdmin {
+                username: "admin".into(),
+            })
+        );
+
+        let cli = Cli::try_parse_from(["actix_server", "config", "check"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Config {
+                command: ConfigCommand::Check
+            })
+        );
+    }
+}
@@ -7,32 +7,437 @@ use sqlx::{
     ConnectOptions,
 };
 
-#[derive(serde::Deserialize, Clone)]
+#[derive(serde::Deserialize, Clone, Debug)]
 pub struct Settings {
+    // set from `APP_ENVIRONMENT`
+    #[serde(default)]
+    pub environment: Environment,
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    #[serde(default)]
+    pub tracking: TrackingSettings,
+    #[serde(default)]
+    pub telemetry: TelemetrySettings,
+    #[serde(default)]
+    pub health: HealthSettings,
+    #[serde(default)]
+    pub bot_protection: BotProtectionSettings,
+    #[serde(default)]
+    pub email_policy: EmailPolicySettings,
+    #[serde(default)]
+    pub confirmation: ConfirmationSettings,
+    #[serde(default)]
+    pub outbox: OutboxSettings,
 }
 
-#[derive(serde::Deserialize, Clone)]
+impl Settings {
+    // checks what can't be expressed through the types of the settings
+    pub fn validate(&self) -> Result<(), String> {
+        self.email_client.sender()?;
+        self.email_client.validate()?;
+        // the development backends would drop the emails
+        if matches!(self.environment, Environment::Production)
+            && !matches!(self.email_client.backend, EmailBackendSettings::Postmark)
+        {
+            return Err(format!(
+                "the {} email backend is only for local development",
+                self.email_client.backend.name()
+            ));
+        }
+        if self.tracking.enabled && self.tracking.signing_key.expose_secret().is_empty() {
+            return Err("tracking is enabled but no signing key was configured".into());
+        }
+        if self.bot_protection.needs_signing_key()
+            && self.bot_protection.signing_key.expose_secret().is_empty()
+        {
+            return Err(
+                "bot protection tokens are enabled but no signing key was configured".into(),
+            );
+        }
+        self.confirmation.validate()?;
+        // otherwise an email could be sent twice, by its relay and by the one taking it over
+        let providers = 1 + self.email_client.fallbacks.len() as u32;
+        if self.outbox.lease() <= self.email_client.timeout() * providers {
+            return Err(
+                "the outbox lease must be longer than a delivery through every email provider"
+                    .into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
 pub struct EmailClientSettings {
     pub base_url: String,
     pub sender_email: String,
     pub authorization_token: Secret<String>,
     pub timeout_milliseconds: u64,
+    // whether the provider accepts internationalised mailboxes (RFC 6531)
+    #[serde(default)]
+    pub smtputf8: bool,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerSettings,
+    // where the emails go, Postmark unless a local transport is chosen for development
+    #[serde(default)]
+    pub backend: EmailBackendSettings,
+    // tried in order when the provider above fails with a retryable error
+    // or its circuit is open, they must accept the same API
+    #[serde(default)]
+    pub fallbacks: Vec<EmailTransportSettings>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EmailBackendSettings {
+    // the provider at `base_url`
+    #[default]
+    Postmark,
+    // prints the emails
+    Stdout,
+    // writes each email as an `.eml` file in the directory
+    FileDrop {
+        directory: std::path::PathBuf,
+    },
+    // keeps the emails in memory, for the tests
+    InMemory,
+}
+
+impl EmailBackendSettings {
+    // labels the backend in the logs, the metrics and the delivery log
+    pub fn name(&self) -> &'static str {
+        match self {
+            EmailBackendSettings::Postmark => crate::email_client::PROVIDER,
+            EmailBackendSettings::Stdout => "stdout",
+            EmailBackendSettings::FileDrop { .. } => "file_drop",
+            EmailBackendSettings::InMemory => "in_memory",
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct EmailTransportSettings {
+    // labels the provider in the logs, the metrics and the delivery log
+    pub name: String,
+    pub base_url: String,
+    pub authorization_token: Secret<String>,
 }
 
 impl EmailClientSettings {
     pub fn sender(&self) -> Result<SubscriberEmail, String> {
         SubscriberEmail::parse(self.sender_email.clone())
+            .map_err(|e| format!("the sender email {}", e))
+    }
+
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout_milliseconds)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut names = std::collections::HashSet::from([self.backend.name()]);
+        for fallback in &self.fallbacks {
+            if !names.insert(fallback.name.as_str()) {
+                return Err(format!(
+                    "the email provider name {} is used more than once",
+                    fallback.name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CircuitBreakerSettings {
+    pub enabled: bool,
+    // share of failed requests, among the last `window_size`, that opens the circuit
+    pub failure_rate_threshold: f64,
+    pub window_size: usize,
+    // the circuit doesn't open before this number of requests was recorded
+    pub minimum_requests: usize,
+    // how long the circuit stays open before a trial request is let through
+    pub cooldown_milliseconds: u64,
+}
+
+impl CircuitBreakerSettings {
+    pub fn cooldown(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.cooldown_milliseconds)
     }
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            failure_rate_threshold: 0.5,
+            window_size: 20,
+            minimum_requests: 10,
+            cooldown_milliseconds: 30_000,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct TrackingSettings {
+    // open and click tracking is opt-in, both for the deployment and for each subscriber
+    pub enabled: bool,
+    // key used to sign the tracking links, so they can't be forged or used as open redirects
+    pub signing_key: Secret<String>,
+}
+
+impl Default for TrackingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            signing_key: Secret::new(String::new()),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TelemetrySettings {
+    // OTLP/HTTP traces endpoint of the collector, e.g. `http://localhost:4318/v1/traces`.
+    // Spans are not exported if it's missing.
+    pub otlp_endpoint: Option<String>,
+    pub otlp_timeout_milliseconds: u64,
+}
+
+impl TelemetrySettings {
+    pub fn otlp_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.otlp_timeout_milliseconds)
+    }
+}
+
+impl Default for TelemetrySettings {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            otlp_timeout_milliseconds: 3000,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HealthSettings {
+    // upper bound of each readiness check
+    pub timeout_milliseconds: u64,
+    // the email provider is not critical, so it's only reported and doesn't affect readiness
+    pub probe_email_provider: bool,
+}
 
+impl HealthSettings {
     pub fn timeout(&self) -> std::time::Duration {
         std::time::Duration::from_millis(self.timeout_milliseconds)
     }
 }
 
-#[derive(serde::Deserialize, Clone)]
+impl Default for HealthSettings {
+    fn default() -> Self {
+        Self {
+            timeout_milliseconds: 1000,
+            probe_email_provider: false,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BotProtectionSettings {
+    // reject the submissions filling in the hidden honeypot field
+    pub honeypot: bool,
+    // require the signed token issued with the form, sent back not sooner than this.
+    // Disabled if missing.
+    pub min_submit_milliseconds: Option<u64>,
+    // zero bits required by the proof of work challenge, disabled if missing
+    pub proof_of_work_difficulty: Option<u32>,
+    // validity of the form tokens and of the challenges
+    pub form_token_max_age_seconds: u64,
+    pub signing_key: Secret<String>,
+}
+
+impl BotProtectionSettings {
+    pub fn needs_signing_key(&self) -> bool {
+        self.min_submit_milliseconds.is_some() || self.proof_of_work_difficulty.is_some()
+    }
+}
+
+impl Default for BotProtectionSettings {
+    fn default() -> Self {
+        Self {
+            honeypot: true,
+            min_submit_milliseconds: None,
+            proof_of_work_difficulty: None,
+            form_token_max_age_seconds: 3600,
+            signing_key: Secret::new(String::new()),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct EmailPolicySettings {
+    // file listing the disposable email domains, one per line
+    pub disposable_domains_file: Option<String>,
+    // reject addresses like `noreply@` or `postmaster@`
+    pub reject_role_accounts: bool,
+    // if not empty, only these domains and their subdomains can subscribe
+    pub allowed_domains: Vec<String>,
+    pub denied_domains: Vec<String>,
+    // reject the domains without MX records
+    pub mx_lookup: bool,
+    // `ip:port` of the DNS server used for the MX lookups, the system one if missing
+    pub dns_resolver: Option<String>,
+    pub mx_lookup_timeout_milliseconds: u64,
+}
+
+impl EmailPolicySettings {
+    pub fn mx_lookup_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.mx_lookup_timeout_milliseconds)
+    }
+}
+
+impl Default for EmailPolicySettings {
+    fn default() -> Self {
+        Self {
+            disposable_domains_file: None,
+            reject_role_accounts: false,
+            allowed_domains: Vec::new(),
+            denied_domains: Vec::new(),
+            mx_lookup: false,
+            dns_resolver: None,
+            mx_lookup_timeout_milliseconds: 2000,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfirmationMode {
+    // a random token, stored in `subscription_tokens`
+    Token,
+    // the link carries the subscriber id and an expiry signed with HMAC-SHA256,
+    // nothing is stored
+    Signed,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ConfirmationSettings {
+    pub mode: ConfirmationMode,
+    // key of the HMAC the subscription tokens are stored as, changing it invalidates
+    // the pending confirmations
+    pub token_hash_key: Secret<String>,
+    // keys accepted for the signed links, the first one signs the new links.
+    // A new key is rotated in at the top of the list, the old one is removed once
+    // the links it signed expired.
+    pub signing_keys: Vec<Secret<String>>,
+    // validity of the signed links
+    pub link_max_age_seconds: u64,
+    // submit the confirmation form with a script as soon as the page is loaded, so that humans
+    // confirm in one click while the mail scanners, that don't run scripts, don't
+    pub auto_submit: bool,
+    // signing up again within this interval doesn't send another confirmation email,
+    // so that concurrent or repeated submissions don't flood the subscriber
+    pub resend_interval_seconds: u64,
+}
+
+impl ConfirmationSettings {
+    fn validate(&self) -> Result<(), String> {
+        match self.mode {
+            ConfirmationMode::Token if self.token_hash_key.expose_secret().is_empty() => {
+                Err("no key was configured to hash the subscription tokens".into())
+            }
+            ConfirmationMode::Signed
+                if self.signing_keys.is_empty()
+                    || self
+                        .signing_keys
+                        .iter()
+                        .any(|k| k.expose_secret().is_empty()) =>
+            {
+                Err(
+                    "signed confirmation links are enabled but no signing key was configured"
+                        .into(),
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn link_max_age(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.link_max_age_seconds)
+    }
+
+    pub fn resend_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.resend_interval_seconds)
+    }
+}
+
+impl Default for ConfirmationSettings {
+    fn default() -> Self {
+        Self {
+            mode: ConfirmationMode::Token,
+            token_hash_key: Secret::new(String::new()),
+            signing_keys: Vec::new(),
+            link_max_age_seconds: 48 * 60 * 60,
+            auto_submit: false,
+            resend_interval_seconds: 60,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct OutboxSettings {
+    // the server delivers the emails too, turn it off when the `worker` processes do it
+    pub relay_in_server: bool,
+    // how often the relay looks for emails to deliver while there are none
+    pub poll_interval_milliseconds: u64,
+    // an email is given up on after this number of failed attempts
+    pub max_attempts: i32,
+    // delay before the first retry, doubled at each attempt up to `max_retry_delay_seconds`
+    pub retry_delay_milliseconds: u64,
+    pub max_retry_delay_seconds: u64,
+    // how long an email is reserved for the relay delivering it, another relay takes it over
+    // afterwards. Must outlast a delivery through every email provider.
+    pub lease_seconds: u64,
+}
+
+impl OutboxSettings {
+    pub fn poll_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.poll_interval_milliseconds)
+    }
+
+    pub fn retry_delay(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.retry_delay_milliseconds)
+    }
+
+    pub fn max_retry_delay(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.max_retry_delay_seconds)
+    }
+
+    pub fn lease(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.lease_seconds)
+    }
+}
+
+impl Default for OutboxSettings {
+    fn default() -> Self {
+        Self {
+            relay_in_server: true,
+            poll_interval_milliseconds: 1000,
+            max_attempts: 8,
+            retry_delay_milliseconds: 5000,
+            max_retry_delay_seconds: 60 * 60,
+            lease_seconds: 60,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
 pub struct DatabaseSettings {
     pub host: String,
     pub username: String,
@@ -43,15 +448,72 @@ pub struct DatabaseSettings {
     pub require_ssl: bool,
 }
 
-#[derive(serde::Deserialize, Clone)]
+#[derive(serde::Deserialize, Clone, Debug)]
 pub struct ApplicationSettings {
     pub host: String,
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub base_url: String,
+    // time given to the in-flight requests and to the workers to complete on shutdown
+    #[serde(default = "default_shutdown_timeout_seconds")]
+    pub shutdown_timeout_seconds: u64,
+    // look of the pages served to the subscribers
+    #[serde(default)]
+    pub branding: BrandingSettings,
+    // pages hosted elsewhere, e.g. on the marketing site, the subscribers are redirected to
+    // instead of the ones rendered here
+    #[serde(default)]
+    pub redirects: PageRedirects,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BrandingSettings {
+    pub name: String,
+    pub logo_url: Option<String>,
+    // CSS color of the headings and of the buttons
+    pub primary_color: String,
+    // directory with the templates replacing the default ones, with the same file names
+    // (`base.html`, `signup.html`, `check_inbox.html`, `confirm.html`, `confirmed.html`,
+    // `invalid_link.html`, `unsubscribed.html`). The missing ones keep the default.
+    pub templates_dir: Option<String>,
+}
+
+impl Default for BrandingSettings {
+    fn default() -> Self {
+        Self {
+            name: "Newsletter".into(),
+            logo_url: None,
+            primary_color: "#2b6cb0".into(),
+            templates_dir: None,
+        }
+    }
 }
 
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PageRedirects {
+    pub signup: Option<String>,
+    pub check_inbox: Option<String>,
+    pub confirmed: Option<String>,
+    pub invalid_link: Option<String>,
+    pub unsubscribed: Option<String>,
+}
+
+fn default_shutdown_timeout_seconds() -> u64 {
+    30
+}
+
+impl ApplicationSettings {
+    pub fn shutdown_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.shutdown_timeout_seconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(try_from = "String")]
 pub enum Environment {
+    #[default]
     Local,
     Production,
 }
@@ -88,7 +550,7 @@ impl DatabaseSettings {
         PgConnectOptions::new()
             .host(&self.host)
             .username(&self.username)
-            .password(&self.password.expose_secret())
+            .password(self.password.expose_secret())
             .port(self.port)
             .database("postgres")
             .ssl_mode(ssl_mode)
@@ -111,7 +573,7 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
     let environment: Environment = std::env::var("APP_ENVIRONMENT")
         .unwrap_or_else(|_| "local".into())
         .try_into()
-        .expect("failed to parse APP_ENVIRONMENT");
+        .map_err(config::ConfigError::Message)?;
 
     // Initialise our configuration reader
     let conf = Config::builder()
@@ -124,12 +586,10 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
             configuration_dir.join(environment.as_str()),
         ))
         .add_source(config::Environment::with_prefix("app").separator("__"))
-        .build()
-        .unwrap();
+        .set_override("environment", environment.as_str())?
+        .build()?;
 
     // Try to convert the configuration values it read into
     // our Settings type
-    let settings = conf.try_deserialize::<Settings>();
-
-    settings
+    conf.try_deserialize::<Settings>()
 }
@@ -0,0 +1,266 @@
+use crate::configuration::{ConfirmationMode, ConfirmationSettings};
+use crate::domain::{SubscriberEmail, SubscriptionToken};
+use crate::routes::{queue_confirmation_email, store_token};
+use crate::signing::{sign, verify};
+use anyhow::Context;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use secrecy::Secret;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum SignedLinkError {
+    #[error("the confirmation link is not valid")]
+    Invalid,
+    #[error("the confirmation link expired")]
+    Expired,
+}
+
+// builds the links sent in the confirmation emails and checks the ones that are visited
+pub struct Confirmations {
+    mode: ConfirmationMode,
+    base_url: String,
+    token_hash_key: Secret<String>,
+    signing_keys: Vec<Secret<String>>,
+    max_age: Duration,
+    auto_submit: bool,
+    resend_interval: Duration,
+}
+
+impl Confirmations {
+    pub fn new(settings: ConfirmationSettings, base_url: String) -> Self {
+        Self {
+            mode: settings.mode,
+            max_age: Duration::from_std(settings.link_max_age())
+                .unwrap_or_else(|_| Duration::max_value()),
+            resend_interval: Duration::from_std(settings.resend_interval())
+                .unwrap_or_else(|_| Duration::max_value()),
+            base_url,
+            token_hash_key: settings.token_hash_key,
+            signing_keys: settings.signing_keys,
+            auto_submit: settings.auto_submit,
+        }
+    }
+
+    pub fn mode(&self) -> ConfirmationMode {
+        self.mode
+    }
+
+    pub fn auto_submit(&self) -> bool {
+        self.auto_submit
+    }
+
+    // a new confirmation email is sent only if the last one was sent before this
+    pub fn resend_cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now.checked_sub_signed(self.resend_interval)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC)
+    }
+
+    // what `subscription_tokens` stores in place of the token
+    pub fn token_hash(&self, token: &SubscriptionToken) -> String {
+        token.hash(&self.token_hash_key)
+    }
+
+    pub fn token_link(&self, token: &SubscriptionToken) -> String {
+        format!(
+            "{}/subscriptions/confirm?subscription_token={}",
+            self.base_url,
+            token.as_ref()
+        )
+    }
+
+    pub fn signed_link(&self, subscriber_id: Uuid, now: DateTime<Utc>) -> String {
+        let expires_at = now + self.max_age;
+        let signature = sign(
+            &self.signing_keys[0],
+            &format!("confirm:{}:{}", subscriber_id, expires_at.timestamp()),
+        );
+        format!(
+            "{}/subscriptions/confirm?confirmation={}",
+            self.base_url, signature
+        )
+    }
+
+    // returns the subscriber the link was signed for, with any of the active keys
+    pub fn verify_signed(
+        &self,
+        confirmation: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Uuid, SignedLinkError> {
+        let payload = self
+            .signing_keys
+            .iter()
+            .find_map(|key| verify(key, confirmation))
+            .ok_or(SignedLinkError::Invalid)?;
+        let (subscriber_id, expires_at) = payload
+            .strip_prefix("confirm:")
+            .and_then(|payload| payload.split_once(':'))
+            .ok_or(SignedLinkError::Invalid)?;
+        let subscriber_id = Uuid::parse_str(subscriber_id).map_err(|_| SignedLinkError::Invalid)?;
+        let expires_at = expires_at
+            .parse()
+            .ok()
+            .and_then(|timestamp| Utc.timestamp_opt(timestamp, 0).single())
+            .ok_or(SignedLinkError::Invalid)?;
+
+        if now > expires_at {
+            Err(SignedLinkError::Expired)
+        } else {
+            Ok(subscriber_id)
+        }
+    }
+}
+
+// the tokens issued before they were hashed can't be accepted anymore: sends a new link to
+// the subscribers still waiting to confirm with one of them, unless they were sent another
+// link since, then deletes the raw tokens. Returns the number of confirmation emails queued.
+// Run by `migrate`, once the key to hash the new tokens is known.
+#[tracing::instrument(name = "reissue legacy confirmations", skip(db_pool, confirmations))]
+pub async fn reissue_legacy_confirmations(
+    db_pool: &PgPool,
+    confirmations: &Confirmations,
+) -> Result<u64, anyhow::Error> {
+    let mut transaction = db_pool
+        .begin()
+        .await
+        .context("failed to get a Postgres connection from the pool")?;
+    let subscribers = sqlx::query!(
+        r#"SELECT DISTINCT s.id, s.email FROM legacy_subscription_tokens l
+        JOIN subscriptions s ON s.id = l.subscriber_id
+        WHERE s.status = 'pending_confirmation'
+            AND s.confirmation_sent_at < l.retired_at"#
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("failed to fetch the subscribers with a legacy token")?;
+
+    let now = Utc::now();
+    let mut reissued = 0;
+    for subscriber in subscribers {
+        let email = match SubscriberEmail::parse(subscriber.email) {
+            Ok(email) => email,
+            Err(e) => {
+                tracing::warn!(subscriber_id = %subscriber.id, error = %e, "skipping an invalid address");
+                continue;
+            }
+        };
+        let confirmation_link = match confirmations.mode() {
+            ConfirmationMode::Token => {
+                let subscription_token = SubscriptionToken::new();
+                store_token(
+                    subscriber.id,
+                    &confirmations.token_hash(&subscription_token),
+                    &mut transaction,
+                )
+                .await
+                .context("failed to store the new confirmation token")?;
+                confirmations.token_link(&subscription_token)
+            }
+            ConfirmationMode::Signed => confirmations.signed_link(subscriber.id, now),
+        };
+        queue_confirmation_email(&mut transaction, &email, &confirmation_link)
+            .await
+            .context("failed to queue the confirmation email")?;
+        sqlx::query!(
+            r#"UPDATE subscriptions SET confirmation_sent_at = $2 WHERE id = $1"#,
+            subscriber.id,
+            now
+        )
+        .execute(&mut transaction)
+        .await
+        .context("failed to record the confirmation email")?;
+        reissued += 1;
+    }
+    // the raw tokens of the other subscribers aren't needed either
+    sqlx::query!(r#"DELETE FROM legacy_subscription_tokens"#)
+        .execute(&mut transaction)
+        .await
+        .context("failed to delete the legacy tokens")?;
+
+    transaction
+        .commit()
+        .await
+        .context("failed to commit the reissued confirmations")?;
+    Ok(reissued)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Confirmations, SignedLinkError};
+    use crate::configuration::{ConfirmationMode, ConfirmationSettings};
+    use chrono::{Duration, Utc};
+    use claim::assert_ok_eq;
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn confirmations(keys: &[&str]) -> Confirmations {
+        Confirmations::new(
+            ConfirmationSettings {
+                mode: ConfirmationMode::Signed,
+                signing_keys: keys.iter().map(|k| Secret::new(k.to_string())).collect(),
+                link_max_age_seconds: 3600,
+                ..ConfirmationSettings::default()
+            },
+            "http://127.0.0.1".to_string(),
+        )
+    }
+
+    fn confirmation_from(link: &str) -> String {
+        link.strip_prefix("http://127.0.0.1/subscriptions/confirm?confirmation=")
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn a_signed_link_is_verified_until_it_expires() {
+        let confirmations = confirmations(&["current-key"]);
+        let subscriber_id = Uuid::new_v4();
+        let now = Utc::now();
+        let confirmation = confirmation_from(&confirmations.signed_link(subscriber_id, now));
+
+        assert_ok_eq!(
+            confirmations.verify_signed(&confirmation, now + Duration::minutes(59)),
+            subscriber_id
+        );
+        assert_eq!(
+            confirmations.verify_signed(&confirmation, now + Duration::minutes(61)),
+            Err(SignedLinkError::Expired)
+        );
+    }
+
+    #[test]
+    fn links_signed_with_a_rotated_key_are_still_verified() {
+        let subscriber_id = Uuid::new_v4();
+        let now = Utc::now();
+        let confirmation =
+            confirmation_from(&confirmations(&["old-key"]).signed_link(subscriber_id, now));
+
+        assert_ok_eq!(
+            confirmations(&["new-key", "old-key"]).verify_signed(&confirmation, now),
+            subscriber_id
+        );
+        assert_eq!(
+            confirmations(&["new-key"]).verify_signed(&confirmation, now),
+            Err(SignedLinkError::Invalid)
+        );
+    }
+
+    #[test]
+    fn a_tampered_link_is_rejected() {
+        let confirmations = confirmations(&["current-key"]);
+        let now = Utc::now();
+        let confirmation = confirmation_from(&confirmations.signed_link(Uuid::new_v4(), now));
+        let (_, signature) = confirmation.split_once('.').unwrap();
+        let forged = confirmation_from(&confirmations.signed_link(Uuid::new_v4(), now));
+        let tampered = format!("{}.{}", forged.split_once('.').unwrap().0, signature);
+
+        assert_eq!(
+            confirmations.verify_signed(&tampered, now),
+            Err(SignedLinkError::Invalid)
+        );
+        assert_eq!(
+            confirmations.verify_signed("not-signed", now),
+            Err(SignedLinkError::Invalid)
+        );
+    }
+}
@@ -0,0 +1,174 @@
+// email transports for local development and the tests, they deliver nothing
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentEmail {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub html_body: String,
+    pub text_body: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+// keeps the emails in memory, the clones share them
+#[derive(Clone, Default)]
+pub struct InMemoryOutbox {
+    emails: Arc<Mutex<Vec<SentEmail>>>,
+}
+
+impl InMemoryOutbox {
+    pub fn push(&self, email: SentEmail) {
+        self.emails.lock().unwrap().push(email);
+    }
+
+    // in the order they were sent
+    pub fn sent(&self) -> Vec<SentEmail> {
+        self.emails.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.emails.lock().unwrap().clear();
+    }
+}
+
+pub fn print_email(email: &SentEmail) {
+    println!(
+        "---- email sent at {}\nFrom: {}\nTo: {}\nSubject: {}\n\n{}\n----",
+        email.sent_at.to_rfc3339(),
+        email.from,
+        email.to,
+        email.subject,
+        email.text_body
+    );
+}
+
+// writes the email to a new `.eml` file in the directory, returns its path
+pub fn drop_email(directory: &Path, email: &SentEmail) -> Result<PathBuf, std::io::Error> {
+    std::fs::create_dir_all(directory)?;
+    let id = Uuid::new_v4();
+    let path = directory.join(format!(
+        "{}-{}.eml",
+        email.sent_at.format("%Y%m%dT%H%M%S%.3fZ"),
+        id
+    ));
+    std::fs::write(&path, to_eml(email, id))?;
+    Ok(path)
+}
+
+// the email as an RFC 5322 message, with the text and HTML bodies as MIME alternatives
+pub fn to_eml(email: &SentEmail, id: Uuid) -> String {
+    let boundary = format!("boundary-{}", id.to_simple());
+    let domain = email.from.rsplit('@').next().unwrap_or("localhost");
+    let mut eml = String::new();
+    for (name, value) in [
+        ("Date", email.sent_at.to_rfc2822()),
+        ("From", email.from.clone()),
+        ("To", email.to.clone()),
+        ("Subject", encode_header(&email.subject)),
+        ("Message-ID", format!("<{}@{}>", id, domain)),
+        ("MIME-Version", "1.0".into()),
+        (
+            "Content-Type",
+            // folded, the line would be too long
+            format!("multipart/alternative;\r\n boundary=\"{}\"", boundary),
+        ),
+    ] {
+        eml.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    eml.push_str("\r\n");
+    for (content_type, body) in [
+        ("text/plain", &email.text_body),
+        ("text/html", &email.html_body),
+    ] {
+        eml.push_str(&format!(
+            "--{}\r\nContent-Type: {}; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n",
+            boundary, content_type
+        ));
+        eml.push_str(&encode_body(body));
+    }
+    eml.push_str(&format!("--{}--\r\n", boundary));
+    eml
+}
+
+// headers are ASCII only, the other values are encoded words (RFC 2047)
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+    format!("=?utf-8?B?{}?=", STANDARD.encode(value))
+}
+
+// base64 in lines of 76 characters (RFC 2045)
+fn encode_body(body: &str) -> String {
+    let encoded = STANDARD.encode(body);
+    let mut lines = String::new();
+    for line in encoded.as_bytes().chunks(76) {
+        // base64 is ASCII
+        lines.push_str(std::str::from_utf8(line).unwrap());
+        lines.push_str("\r\n");
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_eml, SentEmail};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use chrono::{TimeZone, Utc};
+    use uuid::Uuid;
+
+    fn email() -> SentEmail {
+        SentEmail {
+            from: "newsletter@example.com".into(),
+            to: "alpha@smail.com".into(),
+            subject: "Bienvenue à bord".into(),
+            html_body: "<p>Hello</p>".into(),
+            text_body: "Hello".into(),
+            sent_at: Utc.with_ymd_and_hms(2024, 10, 27, 9, 30, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn the_eml_headers_follow_rfc_5322() {
+        let id = Uuid::new_v4();
+        let eml = to_eml(&email(), id);
+
+        assert!(eml.starts_with("Date: Sun, 27 Oct 2024 09:30:00 +0000\r\n"));
+        assert!(eml.contains("\r\nFrom: newsletter@example.com\r\n"));
+        assert!(eml.contains("\r\nTo: alpha@smail.com\r\n"));
+        assert!(eml.contains(&format!("\r\nMessage-ID: <{}@example.com>\r\n", id)));
+        // the headers are separated from the body by an empty line
+        assert!(eml.contains("\r\n\r\n--boundary-"));
+        assert!(eml.lines().all(|line| line.len() <= 78));
+    }
+
+    #[test]
+    fn non_ascii_subjects_are_encoded() {
+        let eml = to_eml(&email(), Uuid::new_v4());
+
+        let encoded = STANDARD.encode("Bienvenue à bord");
+        assert!(eml.contains(&format!("\r\nSubject: =?utf-8?B?{}?=\r\n", encoded)));
+        assert!(eml.is_ascii());
+    }
+
+    #[test]
+    fn both_bodies_are_mime_alternatives() {
+        let id = Uuid::new_v4();
+        let eml = to_eml(&email(), id);
+
+        let boundary = format!("boundary-{}", id.to_simple());
+        assert!(eml.contains(&format!(
+            "Content-Type: multipart/alternative;\r\n boundary=\"{}\"",
+            boundary
+        )));
+        assert!(eml.contains("Content-Type: text/plain; charset=utf-8"));
+        assert!(eml.contains("Content-Type: text/html; charset=utf-8"));
+        assert!(eml.contains(&STANDARD.encode("<p>Hello</p>")));
+        assert!(eml.ends_with(&format!("--{}--\r\n", boundary)));
+    }
+}
@@ -2,8 +2,10 @@ mod new_subscriber;
 mod subscriber_email;
 mod subscriber_name;
 mod subscription_token;
+mod validation;
 
 pub use new_subscriber::NewSubscriber;
 pub use subscriber_email::SubscriberEmail;
 pub use subscriber_name::SubscriberName;
 pub use subscription_token::SubscriptionToken;
+pub use validation::{FieldError, FieldErrors, ValidationError};
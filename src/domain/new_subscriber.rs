@@ -4,4 +4,6 @@ use crate::domain::subscriber_name::SubscriberName;
 pub struct NewSubscriber {
     pub name: SubscriberName,
     pub email: SubscriberEmail,
+    // whether the subscriber opted in to open and click tracking
+    pub tracking_consent: bool,
 }
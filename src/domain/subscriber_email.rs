@@ -1,27 +1,116 @@
+use crate::domain::ValidationError;
 use validator::validate_email; // FIXME: update validator version and use it properly
-#[derive(Debug)]
-pub struct SubscriberEmail(String);
+
+// RFC 5321 limit on the length of the local part, in octets
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+// characters allowed in an unquoted local part besides letters and digits (RFC 5322 `atext`)
+const ATEXT_SYMBOLS: &str = "!#$%&'*+-/=?^_`{|}~";
+
+#[derive(Debug, Clone)]
+pub struct SubscriberEmail {
+    // as typed by the subscriber, used for display and delivery
+    address: String,
+    // used to tell whether two addresses belong to the same subscriber
+    canonical: String,
+    // the domain as punycode and as Unicode, e.g. `xn--bcher-kva.example` and `bücher.example`
+    ascii_domain: String,
+    unicode_domain: String,
+}
 
 impl SubscriberEmail {
-    // returns a SubscriberEmail instance if the constraints are satisfied
-    pub fn parse(s: String) -> Result<Self, String> {
-        if validate_email(&s) {
-            Ok(Self(s))
+    // returns a SubscriberEmail instance if the constraints are satisfied.
+    // Internationalised addresses (RFC 6531) are accepted, both in the local part and in the domain.
+    pub fn parse(s: String) -> Result<Self, ValidationError> {
+        if s.trim().is_empty() {
+            return Err(ValidationError::Empty);
+        }
+        let (local_part, domain) = s.rsplit_once('@').ok_or(ValidationError::InvalidFormat)?;
+        let is_valid = if local_part.is_ascii() {
+            validate_email(&s)
         } else {
-            Err(format!("{} is not a valid email address", s))
+            // the validator only knows about ASCII local parts, the domain is checked on its own
+            is_valid_utf8_local_part(local_part) && validate_email(format!("mailbox@{}", domain))
+        };
+        if !is_valid {
+            return Err(ValidationError::InvalidFormat);
+        }
+
+        let (ascii_domain, unicode_domain) = if domain.starts_with('[') {
+            // address literal, e.g. `[127.0.0.1]`
+            (domain.to_lowercase(), domain.to_lowercase())
+        } else {
+            let ascii =
+                idna::domain_to_ascii(domain).map_err(|_| ValidationError::InvalidFormat)?;
+            let (unicode, outcome) = idna::domain_to_unicode(&ascii);
+            outcome.map_err(|_| ValidationError::InvalidFormat)?;
+            (ascii, unicode)
+        };
+        Ok(Self {
+            canonical: format!("{}@{}", local_part.to_lowercase(), ascii_domain),
+            address: s,
+            ascii_domain,
+            unicode_domain,
+        })
+    }
+
+    pub fn canonical(&self) -> &str {
+        &self.canonical
+    }
+
+    pub fn ascii_domain(&self) -> &str {
+        &self.ascii_domain
+    }
+
+    pub fn unicode_domain(&self) -> &str {
+        &self.unicode_domain
+    }
+
+    fn local_part(&self) -> &str {
+        self.address
+            .rsplit_once('@')
+            .map(|(local_part, _)| local_part)
+            .unwrap_or_default()
+    }
+
+    // a non-ASCII local part can't be rewritten, only SMTPUTF8 capable transports can deliver it
+    pub fn requires_smtputf8(&self) -> bool {
+        !self.local_part().is_ascii()
+    }
+
+    // the address with a punycode domain, deliverable without SMTPUTF8 if the local part is ASCII
+    pub fn ascii_address(&self) -> Option<String> {
+        if self.requires_smtputf8() {
+            None
+        } else {
+            Some(format!("{}@{}", self.local_part(), self.ascii_domain))
         }
     }
 }
 
+// RFC 6531 extends `atext` with every non-ASCII UTF-8 character
+fn is_valid_utf8_local_part(local_part: &str) -> bool {
+    local_part.len() <= MAX_LOCAL_PART_LENGTH
+        && !local_part.starts_with('.')
+        && !local_part.ends_with('.')
+        && !local_part.contains("..")
+        && local_part.chars().all(|c| {
+            c.is_ascii_alphanumeric()
+                || c == '.'
+                || ATEXT_SYMBOLS.contains(c)
+                || (!c.is_ascii() && !c.is_control() && !c.is_whitespace())
+        })
+}
+
 impl AsRef<str> for SubscriberEmail {
     fn as_ref(&self) -> &str {
-        &self.0
+        &self.address
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::domain::subscriber_email::SubscriberEmail;
+    use crate::domain::ValidationError;
     use claim::assert_err;
     use fake::faker::internet::en::SafeEmail;
     use fake::Fake;
@@ -39,13 +128,19 @@ mod tests {
     #[test]
     fn empty_email_is_rejected() {
         let email = "".to_string();
-        assert_err!(SubscriberEmail::parse(email));
+        assert_eq!(
+            assert_err!(SubscriberEmail::parse(email)),
+            ValidationError::Empty
+        );
     }
 
     #[test]
     fn email_missing_at_symbol_is_rejected() {
         let email = "alphacentaurismail.com".to_string();
-        assert_err!(SubscriberEmail::parse(email));
+        assert_eq!(
+            assert_err!(SubscriberEmail::parse(email)),
+            ValidationError::InvalidFormat
+        );
     }
 
     #[test]
@@ -54,6 +149,66 @@ mod tests {
         assert_err!(SubscriberEmail::parse(email));
     }
 
+    #[test]
+    fn the_canonical_form_is_case_insensitive() {
+        let email = SubscriberEmail::parse("Alice@Example.COM".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "Alice@Example.COM");
+        assert_eq!(email.canonical(), "alice@example.com");
+    }
+
+    #[test]
+    fn internationalised_domains_are_converted_to_punycode() {
+        let email = SubscriberEmail::parse("alice@Bücher.example".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "alice@Bücher.example");
+        assert_eq!(email.canonical(), "alice@xn--bcher-kva.example");
+        assert_eq!(
+            SubscriberEmail::parse("alice@xn--bcher-kva.example".to_string())
+                .unwrap()
+                .canonical(),
+            email.canonical()
+        );
+    }
+
+    #[test]
+    fn internationalised_local_parts_are_accepted() {
+        for address in [
+            "用户@例子.广告",
+            "δοκιμή@παράδειγμα.δοκιμή",
+            "josé.pérez@example.com",
+        ] {
+            let email = SubscriberEmail::parse(address.to_string()).unwrap();
+            assert!(email.requires_smtputf8());
+            assert_eq!(email.ascii_address(), None);
+        }
+    }
+
+    #[test]
+    fn both_forms_of_the_domain_are_kept() {
+        let email = SubscriberEmail::parse("alice@xn--bcher-kva.example".to_string()).unwrap();
+        assert_eq!(email.ascii_domain(), "xn--bcher-kva.example");
+        assert_eq!(email.unicode_domain(), "bücher.example");
+        // an ASCII local part at an internationalised domain can be delivered without SMTPUTF8
+        assert!(!email.requires_smtputf8());
+        assert_eq!(
+            email.ascii_address().as_deref(),
+            Some("alice@xn--bcher-kva.example")
+        );
+    }
+
+    #[test]
+    fn malformed_internationalised_addresses_are_rejected() {
+        for address in [
+            "用户..名@example.com",
+            ".josé@example.com",
+            "jo sé@example.com",
+        ] {
+            assert_eq!(
+                assert_err!(SubscriberEmail::parse(address.to_string())),
+                ValidationError::InvalidFormat
+            );
+        }
+    }
+
     #[quickcheck_macros::quickcheck]
     fn valid_email_are_parsed_succesfully(valid_email: ValidEmailFixture) {
         let _ = SubscriberEmail::parse(valid_email.0).is_ok();
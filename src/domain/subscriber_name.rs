@@ -1,27 +1,31 @@
+use crate::domain::ValidationError;
 use unicode_segmentation::UnicodeSegmentation;
 
 #[derive(Debug)]
 pub struct SubscriberName(String);
 
 const FORBIDDEN_CHARACTERS: [char; 9] = ['/', '\\', '(', ')', '"', '{', '}', '<', '>'];
+const MAX_LENGTH: usize = 256;
 
 impl SubscriberName {
     // returns a SubscriberName instance if the constraints are satisfied
-    pub fn parse(s: String) -> Result<Self, String> {
-        let is_empty_or_whitespace = s.trim().is_empty();
+    pub fn parse(s: String) -> Result<Self, ValidationError> {
+        if s.trim().is_empty() {
+            return Err(ValidationError::Empty);
+        }
 
         // a grapheme is a "user-perceived" character (it can be formed by two character but is read as one, like å is `a` and `̊``).
         // graphemes returns an iterator over the graphemes of the input.
         // `true` specifies that we want to use the extended grapheme definition set, recommended.
-        let is_too_log = s.graphemes(true).count() > 256;
-
-        let contains_forbidden_characters = s.chars().any(|g| FORBIDDEN_CHARACTERS.contains(&g));
+        if s.graphemes(true).count() > MAX_LENGTH {
+            return Err(ValidationError::TooLong { max: MAX_LENGTH });
+        }
 
-        if is_empty_or_whitespace || is_too_log || contains_forbidden_characters {
-            Err(format!("{} is not a valid subscriber name", s))
-        } else {
-            Ok(Self(s))
+        if let Some(forbidden) = s.chars().find(|g| FORBIDDEN_CHARACTERS.contains(g)) {
+            return Err(ValidationError::ForbiddenCharacter(forbidden));
         }
+
+        Ok(Self(s))
     }
 }
 
@@ -34,6 +38,7 @@ impl AsRef<str> for SubscriberName {
 #[cfg(test)]
 mod tests {
     use crate::domain::subscriber_name::{SubscriberName, FORBIDDEN_CHARACTERS};
+    use crate::domain::ValidationError;
     use claim::{assert_err, assert_ok};
 
     #[test]
@@ -45,13 +50,19 @@ mod tests {
     #[test]
     fn a_257_grapheme_name_is_rejected() {
         let name = "a".repeat(257);
-        assert_err!(SubscriberName::parse(name));
+        assert_eq!(
+            assert_err!(SubscriberName::parse(name)),
+            ValidationError::TooLong { max: 256 }
+        );
     }
 
     #[test]
     fn whitespace_only_name_is_rejected() {
         let name = " ".to_string();
-        assert_err!(SubscriberName::parse(name));
+        assert_eq!(
+            assert_err!(SubscriberName::parse(name)),
+            ValidationError::Empty
+        );
     }
 
     #[test]
@@ -62,9 +73,12 @@ mod tests {
 
     #[test]
     fn names_containing_invalid_character_are_rejected() {
-        for name in &FORBIDDEN_CHARACTERS {
-            let name = name.to_string();
-            assert_err!(SubscriberName::parse(name));
+        for forbidden in &FORBIDDEN_CHARACTERS {
+            let name = format!("Alpha {}Centauri", forbidden);
+            assert_eq!(
+                assert_err!(SubscriberName::parse(name)),
+                ValidationError::ForbiddenCharacter(*forbidden)
+            );
         }
     }
 
@@ -1,24 +1,31 @@
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use crate::domain::ValidationError;
+use crate::signing::keyed_hash;
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
 use regex::Regex;
+use secrecy::Secret;
 
-const TOKEN_LENGTH: usize = 25;
+// ~256 bits of entropy
+const TOKEN_LENGTH: usize = 43;
 
 #[derive(Debug)]
 pub struct SubscriptionToken(String);
 
 impl SubscriptionToken {
     // returns a SubscriptionToken instance if the constraints are satisfied
-    pub fn parse(s: String) -> Result<Self, String> {
+    pub fn parse(s: String) -> Result<Self, ValidationError> {
         let token_regex = Regex::new(&format!(r"(?m)^[a-zA-Z0-9]{{{}}}$", TOKEN_LENGTH)).unwrap();
-        if token_regex.is_match(&s) {
+        if s.is_empty() {
+            Err(ValidationError::Empty)
+        } else if token_regex.is_match(&s) {
             Ok(Self(s))
         } else {
-            Err(format!("{} is not a valid token", s))
+            Err(ValidationError::InvalidFormat)
         }
     }
 
     pub fn new_token_string() -> String {
-        let mut rng = thread_rng();
+        // straight from the operating system CSPRNG
+        let mut rng = OsRng;
         std::iter::repeat_with(|| rng.sample(Alphanumeric))
             .map(char::from)
             .take(TOKEN_LENGTH)
@@ -31,6 +38,19 @@ impl SubscriptionToken {
     }
 }
 
+impl SubscriptionToken {
+    // only the hash is stored, so that the tokens can't be read back from the database
+    pub fn hash(&self, key: &Secret<String>) -> String {
+        keyed_hash(key, &self.0)
+    }
+}
+
+impl Default for SubscriptionToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AsRef<str> for SubscriptionToken {
     fn as_ref(&self) -> &str {
         &self.0
@@ -40,30 +60,37 @@ impl AsRef<str> for SubscriptionToken {
 #[cfg(test)]
 mod tests {
     use crate::domain::subscription_token::SubscriptionToken;
+    use crate::domain::ValidationError;
     use claim::assert_err;
 
     #[test]
     fn empty_token_is_rejected() {
         let token = "".to_string();
-        assert_err!(SubscriptionToken::parse(token));
+        assert_eq!(
+            assert_err!(SubscriptionToken::parse(token)),
+            ValidationError::Empty
+        );
     }
 
     #[test]
     fn too_long_token_is_rejected() {
-        let token = "KYu7R2TPDCAy1rT141uOExlVVg".to_string();
+        let token = "KYu7R2TPDCAy1rT141uOExlVVgKYu7R2TPDCAy1rT141uOE".to_string();
         assert_err!(SubscriptionToken::parse(token));
     }
 
     #[test]
     fn too_short_token_is_rejected() {
-        let token = "KYu7R2TPDCAy1rT141Gcy8rI".to_string();
+        let token = "KYu7R2TPDCAy1rT141Gcy8rIKYu7R2TPDCAy1rT141".to_string();
         assert_err!(SubscriptionToken::parse(token));
     }
 
     #[test]
     fn invalid_token_is_rejected() {
-        let token = "KYu7R2TPD_CAy1rT141Gcy8I".to_string();
-        assert_err!(SubscriptionToken::parse(token));
+        let token = "KYu7R2TPD_CAy1rT141Gcy8IKYu7R2TPDCAy1rT141uO".to_string();
+        assert_eq!(
+            assert_err!(SubscriptionToken::parse(token)),
+            ValidationError::InvalidFormat
+        );
     }
 
     #[test]
@@ -0,0 +1,125 @@
+// why a single value was rejected, the messages are meant to follow the field name
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Clone)]
+pub enum ValidationError {
+    #[error("must not be empty")]
+    Empty,
+    #[error("must be at most {max} characters long")]
+    TooLong { max: usize },
+    #[error("must not contain '{0}'")]
+    ForbiddenCharacter(char),
+    #[error("is not in a valid format")]
+    InvalidFormat,
+    #[error("belongs to a disposable email provider")]
+    DisposableDomain,
+    #[error("is a role account, not a personal address")]
+    RoleAccount,
+    #[error("is not from an allowed domain")]
+    DomainNotAllowed,
+    #[error("has no mail server")]
+    NoMailServer,
+    #[error("is an internationalised mailbox we can't deliver to")]
+    Undeliverable,
+}
+
+impl ValidationError {
+    // stable identifier, for clients that need to tell the failures apart
+    pub fn code(&self) -> &'static str {
+        match self {
+            ValidationError::Empty => "empty",
+            ValidationError::TooLong { .. } => "too_long",
+            ValidationError::ForbiddenCharacter(_) => "forbidden_character",
+            ValidationError::InvalidFormat => "invalid_format",
+            ValidationError::DisposableDomain => "disposable_domain",
+            ValidationError::RoleAccount => "role_account",
+            ValidationError::DomainNotAllowed => "domain_not_allowed",
+            ValidationError::NoMailServer => "no_mail_server",
+            ValidationError::Undeliverable => "undeliverable",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub error: ValidationError,
+}
+
+impl std::fmt::Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.field, self.error)
+    }
+}
+
+// collects the errors of every field, so that they can be reported all at once
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct FieldErrors(Vec<FieldError>);
+
+impl FieldErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // returns the parsed value, or records why the field is not valid
+    pub fn check<T>(
+        &mut self,
+        field: &'static str,
+        parsed: Result<T, ValidationError>,
+    ) -> Option<T> {
+        match parsed {
+            Ok(value) => Some(value),
+            Err(error) => {
+                self.0.push(FieldError { field, error });
+                None
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &FieldError> {
+        self.0.iter()
+    }
+}
+
+impl std::fmt::Display for FieldErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let errors: Vec<String> = self.0.iter().map(|e| e.to_string()).collect();
+        write!(f, "{}", errors.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FieldError, FieldErrors, ValidationError};
+
+    #[test]
+    fn every_field_error_is_collected() {
+        let mut errors = FieldErrors::new();
+
+        let name: Option<()> = errors.check("name", Err(ValidationError::Empty));
+        let email: Option<()> = errors.check("email", Err(ValidationError::InvalidFormat));
+        let valid = errors.check("other", Ok(42));
+
+        assert!(name.is_none() && email.is_none());
+        assert_eq!(valid, Some(42));
+        assert_eq!(
+            errors.iter().cloned().collect::<Vec<_>>(),
+            vec![
+                FieldError {
+                    field: "name",
+                    error: ValidationError::Empty
+                },
+                FieldError {
+                    field: "email",
+                    error: ValidationError::InvalidFormat
+                },
+            ]
+        );
+        assert_eq!(
+            errors.to_string(),
+            "name must not be empty, email is not in a valid format"
+        );
+    }
+}
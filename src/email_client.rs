@@ -1,56 +1,463 @@
+use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+use crate::configuration::{CircuitBreakerSettings, EmailBackendSettings, EmailClientSettings};
+use crate::dev_transports::{drop_email, print_email, InMemoryOutbox, SentEmail};
 use crate::domain::SubscriberEmail;
-use reqwest::Client;
+use crate::errors::EmailError;
+use crate::metrics::METRICS;
+use chrono::{DateTime, Utc};
+use opentelemetry::global;
+use opentelemetry_http::HeaderInjector;
+use reqwest::{Client, StatusCode};
 use secrecy::{ExposeSecret, Secret};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+// name of the primary provider, used to label it in the metrics and the delivery log
+pub const PROVIDER: &str = "postmark";
+
+// a provider the email was handed to, with its error if it failed
+#[derive(Debug, PartialEq)]
+pub struct ProviderAttempt<'a> {
+    pub provider: &'a str,
+    pub attempted_at: DateTime<Utc>,
+    pub error: Option<String>,
+}
+
+// the providers tried, in order, and the outcome of the delivery
+#[derive(Debug)]
+pub struct Delivery<'a> {
+    pub attempts: Vec<ProviderAttempt<'a>>,
+    // the name of the provider that accepted the email
+    pub outcome: Result<&'a str, EmailError>,
+}
+
+#[derive(Clone)]
 pub struct EmailClient {
-    http_client: Client,
-    base_url: String,
     sender: SubscriberEmail,
-    authorization_token: Secret<String>,
+    smtputf8: bool,
+    timeout: Duration,
+    circuit_breaker: CircuitBreakerSettings,
+    // the primary provider first, then the fallbacks in order
+    transports: Vec<Transport>,
+}
+
+#[derive(Clone)]
+struct Transport {
+    name: String,
+    backend: Backend,
+    // shared by the clones, they all call the same provider
+    circuit_breaker: Arc<CircuitBreaker>,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Postmark {
+        http_client: Client,
+        base_url: String,
+        authorization_token: Secret<String>,
+    },
+    // the local transports deliver nothing, for development and the tests
+    Stdout,
+    FileDrop(PathBuf),
+    InMemory(InMemoryOutbox),
+}
+
+impl Transport {
+    fn new(name: String, backend: Backend, circuit_breaker: CircuitBreakerSettings) -> Self {
+        Self {
+            circuit_breaker: Arc::new(CircuitBreaker::new(name.clone(), circuit_breaker)),
+            name,
+            backend,
+        }
+    }
+
+    fn postmark(
+        name: String,
+        base_url: String,
+        authorization_token: Secret<String>,
+        timeout: Duration,
+        circuit_breaker: CircuitBreakerSettings,
+    ) -> Self {
+        let backend = Backend::Postmark {
+            http_client: Client::builder().timeout(timeout).build().unwrap(),
+            base_url,
+            authorization_token,
+        };
+        Self::new(name, backend, circuit_breaker)
+    }
 }
 
 impl EmailClient {
+    pub fn from_settings(settings: &EmailClientSettings) -> Self {
+        let mut email_client = Self::new(
+            settings.base_url.clone(),
+            settings
+                .sender()
+                .expect("invalid sender email for email client"),
+            settings.authorization_token.clone(),
+            settings.timeout(),
+            settings.smtputf8,
+        )
+        .with_circuit_breaker(settings.circuit_breaker.clone());
+        let local_backend = match &settings.backend {
+            EmailBackendSettings::Postmark => None,
+            EmailBackendSettings::Stdout => Some(Backend::Stdout),
+            EmailBackendSettings::FileDrop { directory } => {
+                Some(Backend::FileDrop(directory.clone()))
+            }
+            EmailBackendSettings::InMemory => Some(Backend::InMemory(InMemoryOutbox::default())),
+        };
+        if let Some(backend) = local_backend {
+            email_client.transports[0] = Transport::new(
+                settings.backend.name().into(),
+                backend,
+                settings.circuit_breaker.clone(),
+            );
+        }
+        for fallback in &settings.fallbacks {
+            email_client = email_client.with_fallback(
+                fallback.name.clone(),
+                fallback.base_url.clone(),
+                fallback.authorization_token.clone(),
+            );
+        }
+        email_client
+    }
+
     pub fn new(
         base_url: String,
         sender: SubscriberEmail,
         authorization_token: Secret<String>,
-        timeout: std::time::Duration,
+        timeout: Duration,
+        smtputf8: bool,
     ) -> Self {
-        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        let circuit_breaker = CircuitBreakerSettings::default();
         Self {
-            http_client,
-            base_url,
+            transports: vec![Transport::postmark(
+                PROVIDER.into(),
+                base_url,
+                authorization_token,
+                timeout,
+                circuit_breaker.clone(),
+            )],
             sender,
+            smtputf8,
+            timeout,
+            circuit_breaker,
+        }
+    }
+
+    // applies to every provider, each one has its own circuit
+    pub fn with_circuit_breaker(mut self, settings: CircuitBreakerSettings) -> Self {
+        for transport in &mut self.transports {
+            transport.circuit_breaker = Arc::new(CircuitBreaker::new(
+                transport.name.clone(),
+                settings.clone(),
+            ));
+        }
+        self.circuit_breaker = settings;
+        self
+    }
+
+    // a provider tried after the ones already configured
+    pub fn with_fallback(
+        mut self,
+        name: String,
+        base_url: String,
+        authorization_token: Secret<String>,
+    ) -> Self {
+        self.transports.push(Transport::postmark(
+            name,
+            base_url,
             authorization_token,
+            self.timeout,
+            self.circuit_breaker.clone(),
+        ));
+        self
+    }
+
+    // the state of the primary provider's circuit
+    pub fn circuit_state(&self) -> CircuitState {
+        self.transports[0].circuit_breaker.state(Instant::now())
+    }
+
+    // the emails sent through the in-memory backend, if it's the primary one
+    pub fn in_memory_outbox(&self) -> Option<InMemoryOutbox> {
+        match &self.transports[0].backend {
+            Backend::InMemory(outbox) => Some(outbox.clone()),
+            _ => None,
+        }
+    }
+
+    // whether the circuit of at least one provider lets the requests through
+    pub fn has_available_transport(&self) -> bool {
+        let now = Instant::now();
+        self.transports
+            .iter()
+            .any(|t| t.circuit_breaker.state(now) != CircuitState::Open)
+    }
+
+    // whether internationalised mailboxes (RFC 6531) can be delivered
+    pub fn supports_smtputf8(&self) -> bool {
+        self.smtputf8
+    }
+
+    // the form of the address the transport can deliver to
+    fn deliverable_address(&self, recipient: &SubscriberEmail) -> Result<String, EmailError> {
+        if self.smtputf8 {
+            return Ok(recipient.as_ref().to_string());
         }
+        recipient
+            .ascii_address()
+            .ok_or_else(|| EmailError::Smtputf8NotSupported(recipient.as_ref().to_string()))
     }
 
+    // returns the name of the provider that accepted the email
+    #[tracing::instrument(
+        name = "send email",
+        skip_all,
+        fields(email_provider = tracing::field::Empty)
+    )]
     pub async fn send_email(
         &self,
         recipient: SubscriberEmail,
         subject: &str,
         html_content: &str,
         text_content: &str,
-    ) -> Result<(), reqwest::Error> {
-        let url = format!("{}/email", self.base_url); // FIXME, make it a reqwest::url type
+    ) -> Result<&str, EmailError> {
+        self.deliver(recipient, subject, html_content, text_content)
+            .await
+            .outcome
+    }
+
+    // like `send_email`, also reporting every provider that was tried
+    pub async fn deliver(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Delivery<'_> {
+        let mut attempts = Vec::new();
+        // refuse upfront what the provider would bounce
+        let to = match self.deliverable_address(&recipient) {
+            Ok(to) => to,
+            Err(e) => {
+                return Delivery {
+                    attempts,
+                    outcome: Err(e),
+                }
+            }
+        };
         let request_body = SendEmailRequest {
             from: self.sender.as_ref(),
-            to: recipient.as_ref(),
+            to: &to,
             subject,
             html_body: html_content,
             text_body: text_content,
         };
-        let _builder = self
-            .http_client
-            .post(&url)
-            .header(
-                "X-Postmark-Server-Token",
-                self.authorization_token.expose_secret(),
-            )
-            .json(&request_body)
-            .send()
-            .await?
-            .error_for_status()?;
+
+        let mut last_error = None;
+        let mut retry_after: Option<Duration> = None;
+        for transport in &self.transports {
+            // skip the providers known to be failing
+            if let Err(wait) = transport.circuit_breaker.acquire(Instant::now()) {
+                retry_after = Some(retry_after.map_or(wait, |r| r.min(wait)));
+                continue;
+            }
+            let attempted_at = Utc::now();
+            let sent = self.send_with(transport, &request_body).await;
+            attempts.push(ProviderAttempt {
+                provider: &transport.name,
+                attempted_at,
+                error: sent.as_ref().err().map(|e| e.to_string()),
+            });
+            match sent {
+                Ok(()) => {
+                    tracing::Span::current().record("email_provider", transport.name.as_str());
+                    return Delivery {
+                        attempts,
+                        outcome: Ok(&transport.name),
+                    };
+                }
+                // another provider would refuse the email the same way
+                Err(e) if e.is_permanent() => {
+                    return Delivery {
+                        attempts,
+                        outcome: Err(e),
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(email_provider = %transport.name, error = ?e, "the email provider failed, trying the next one");
+                    last_error = Some(e);
+                }
+            }
+        }
+        Delivery {
+            attempts,
+            outcome: Err(last_error.unwrap_or(EmailError::CircuitOpen {
+                retry_after: retry_after.unwrap_or_default(),
+            })),
+        }
+    }
+
+    async fn send_with(
+        &self,
+        transport: &Transport,
+        request_body: &SendEmailRequest<'_>,
+    ) -> Result<(), EmailError> {
+        METRICS
+            .email_send_attempts_total
+            .with_label_values(&[&transport.name])
+            .inc();
+        let timer = METRICS
+            .email_send_duration_seconds
+            .with_label_values(&[&transport.name])
+            .start_timer();
+        let outcome = match &transport.backend {
+            Backend::Postmark {
+                http_client,
+                base_url,
+                authorization_token,
+            } => post(http_client, base_url, authorization_token, request_body).await,
+            Backend::Stdout => {
+                print_email(&request_body.to_sent_email());
+                Ok(())
+            }
+            Backend::FileDrop(directory) => {
+                let directory = directory.clone();
+                let email = request_body.to_sent_email();
+                match tokio::task::spawn_blocking(move || drop_email(&directory, &email)).await {
+                    Ok(Ok(path)) => {
+                        tracing::info!(path = %path.display(), "email written");
+                        Ok(())
+                    }
+                    Ok(Err(e)) => Err(EmailError::FileDrop(e)),
+                    Err(e) => Err(EmailError::FileDrop(std::io::Error::other(e))),
+                }
+            }
+            Backend::InMemory(outbox) => {
+                outbox.push(request_body.to_sent_email());
+                Ok(())
+            }
+        };
+        timer.observe_duration();
+        // a refused email says nothing about the provider's health
+        transport.circuit_breaker.record(
+            matches!(&outcome, Err(e) if e.is_provider_failure()),
+            Instant::now(),
+        );
+
+        if let Err(e) = &outcome {
+            METRICS
+                .email_send_failures_total
+                .with_label_values(&[&transport.name])
+                .inc();
+            if e.needs_attention() {
+                tracing::error!(email_provider = %transport.name, error = ?e, "the email provider needs attention");
+            }
+        }
+        outcome
+    }
+}
+
+async fn post(
+    http_client: &Client,
+    base_url: &str,
+    authorization_token: &Secret<String>,
+    request_body: &SendEmailRequest<'_>,
+) -> Result<(), EmailError> {
+    let url = format!("{}/email", base_url); // FIXME, make it a reqwest::url type
+                                             // propagate the current trace context to the provider
+    let mut trace_headers = reqwest::header::HeaderMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(
+            &tracing::Span::current().context(),
+            &mut HeaderInjector(&mut trace_headers),
+        )
+    });
+    let response = http_client
+        .post(url)
+        .headers(trace_headers)
+        .header(
+            "X-Postmark-Server-Token",
+            authorization_token.expose_secret(),
+        )
+        .json(request_body)
+        .send()
+        .await?;
+    if response.status().is_success() {
+        return Ok(());
+    }
+    Err(provider_error(response).await)
+}
+
+// Postmark describes its errors with a JSON body, see
+// https://postmarkapp.com/developer/api/overview#error-codes
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ErrorResponse {
+    error_code: i64,
+    message: String,
+}
+
+const INVALID_API_TOKEN: i64 = 10;
+const INVALID_EMAIL_REQUEST: i64 = 300;
+const INACTIVE_RECIPIENT: i64 = 406;
+
+async fn provider_error(response: reqwest::Response) -> EmailError {
+    let status = response.status();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .map(std::time::Duration::from_secs);
+        return EmailError::RateLimited { retry_after };
+    }
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(e) => return e.into(),
+    };
+
+    match serde_json::from_str::<ErrorResponse>(&body) {
+        Ok(error) => match error.error_code {
+            INVALID_API_TOKEN => EmailError::AuthenticationFailed,
+            INACTIVE_RECIPIENT => EmailError::InactiveRecipient(error.message),
+            // the code covers any invalid request, only the message tells the recipient apart
+            INVALID_EMAIL_REQUEST if error.message.contains("'To'") => {
+                EmailError::InvalidRecipient(error.message)
+            }
+            code => EmailError::ProviderError {
+                status,
+                code: Some(code),
+                message: error.message,
+            },
+        },
+        Err(_) if status == StatusCode::UNAUTHORIZED => EmailError::AuthenticationFailed,
+        Err(_) => EmailError::ProviderError {
+            status,
+            code: None,
+            message: body.chars().take(200).collect(),
+        },
+    }
+}
+
+impl EmailClient {
+    // checks that the primary provider can be reached, any HTTP response is good enough.
+    // The local backends are always reachable
+    pub async fn probe(&self) -> Result<(), reqwest::Error> {
+        if let Backend::Postmark {
+            http_client,
+            base_url,
+            ..
+        } = &self.transports[0].backend
+        {
+            http_client.get(base_url).send().await?;
+        }
         Ok(())
     }
 }
@@ -65,9 +472,23 @@ struct SendEmailRequest<'a> {
     text_body: &'a str,
 }
 
+impl SendEmailRequest<'_> {
+    fn to_sent_email(&self) -> SentEmail {
+        SentEmail {
+            from: self.from.to_string(),
+            to: self.to.to_string(),
+            subject: self.subject.to_string(),
+            html_body: self.html_body.to_string(),
+            text_body: self.text_body.to_string(),
+            sent_at: Utc::now(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::domain::SubscriberEmail;
+    use crate::errors::EmailError;
     use claim::{assert_err, assert_ok};
     use fake::faker::internet::en::SafeEmail;
     use fake::faker::lorem::en::Sentence;
@@ -78,6 +499,8 @@ mod test {
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
     use super::EmailClient;
+    use crate::circuit_breaker::CircuitState;
+    use crate::configuration::CircuitBreakerSettings;
 
     struct SendEmailBodyMatcher;
 
@@ -113,6 +536,7 @@ mod test {
             email(),
             Secret::new(Faker.fake()),
             std::time::Duration::from_millis(200),
+            false,
         )
     }
 
@@ -138,6 +562,80 @@ mod test {
         //the assert part is made by the expect on the Mock, which will verify the number of requests received!
     }
 
+    #[tokio::test]
+    async fn internationalised_domains_are_sent_as_punycode_without_smtputf8() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let recipient = SubscriberEmail::parse("alice@bücher.example".into()).unwrap();
+        assert_ok!(
+            email_client
+                .send_email(recipient, &subject(), &content(), &content())
+                .await
+        );
+
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["To"], "alice@xn--bcher-kva.example");
+    }
+
+    #[tokio::test]
+    async fn internationalised_mailboxes_fail_without_smtputf8() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let recipient = SubscriberEmail::parse("josé@example.com".into()).unwrap();
+        let outcome = email_client
+            .send_email(recipient, &subject(), &content(), &content())
+            .await;
+
+        assert!(matches!(
+            assert_err!(outcome),
+            EmailError::Smtputf8NotSupported(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn internationalised_mailboxes_are_sent_as_typed_with_smtputf8() {
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::new(
+            mock_server.uri(),
+            email(),
+            Secret::new(Faker.fake()),
+            std::time::Duration::from_millis(200),
+            true,
+        );
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let recipient = SubscriberEmail::parse("josé@bücher.example".into()).unwrap();
+        assert_ok!(
+            email_client
+                .send_email(recipient, &subject(), &content(), &content())
+                .await
+        );
+
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["To"], "josé@bücher.example");
+    }
+
     #[tokio::test]
     async fn send_email_succeeds_if_the_server_returns_200() {
         let mock_server = MockServer::start().await;
@@ -171,7 +669,10 @@ mod test {
             .send_email(email(), &subject(), &content(), &content())
             .await;
 
-        assert_err!(outcome);
+        assert!(matches!(
+            assert_err!(outcome),
+            EmailError::ProviderError { status, code: None, .. } if status.as_u16() == 500
+        ));
     }
 
     #[tokio::test]
@@ -191,6 +692,182 @@ mod test {
             .send_email(email(), &subject(), &content(), &content())
             .await;
 
-        assert_err!(outcome);
+        assert!(matches!(assert_err!(outcome), EmailError::Timeout(_)));
+    }
+
+    async fn send_email_with_response(response: ResponseTemplate) -> EmailError {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await
+            .unwrap_err()
+    }
+
+    fn postmark_error(status: u16, error_code: i64, message: &str) -> ResponseTemplate {
+        ResponseTemplate::new(status).set_body_json(serde_json::json!({
+            "ErrorCode": error_code,
+            "Message": message
+        }))
+    }
+
+    #[tokio::test]
+    async fn inactive_recipients_are_reported() {
+        let error = send_email_with_response(postmark_error(
+            422,
+            406,
+            "You tried to send to a recipient that has been marked as inactive.",
+        ))
+        .await;
+
+        assert!(matches!(error, EmailError::InactiveRecipient(_)));
+        assert!(error.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn invalid_recipients_are_reported() {
+        let error = send_email_with_response(postmark_error(
+            422,
+            300,
+            "Error parsing 'To': Illegal email address 'nobody'.",
+        ))
+        .await;
+
+        assert!(matches!(error, EmailError::InvalidRecipient(_)));
+        assert!(error.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_are_reported() {
+        let error = send_email_with_response(postmark_error(
+            401,
+            10,
+            "The Server Token you provided in the X-Postmark-Server-Token request header was invalid.",
+        ))
+        .await;
+
+        assert!(matches!(error, EmailError::AuthenticationFailed));
+        assert!(!error.is_permanent());
+        assert!(error.needs_attention());
+    }
+
+    #[tokio::test]
+    async fn rate_limiting_is_reported_with_the_delay_to_wait() {
+        let error =
+            send_email_with_response(ResponseTemplate::new(429).insert_header("Retry-After", "30"))
+                .await;
+
+        assert_eq!(
+            error.retry_after(),
+            Some(std::time::Duration::from_secs(30))
+        );
+        assert!(!error.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn other_provider_errors_keep_their_code_and_message() {
+        let error = send_email_with_response(postmark_error(
+            422,
+            412,
+            "Your account is pending approval.",
+        ))
+        .await;
+
+        match error {
+            EmailError::ProviderError {
+                status,
+                code,
+                message,
+            } => {
+                assert_eq!(status.as_u16(), 422);
+                assert_eq!(code, Some(412));
+                assert_eq!(message, "Your account is pending approval.");
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_providers_are_reported_as_network_errors() {
+        // nothing listens on the port
+        let email_client = email_client("http://127.0.0.1:1".into());
+
+        let error = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, EmailError::Network(_)));
+    }
+
+    fn email_client_with_circuit_breaker(base_url: String) -> EmailClient {
+        email_client(base_url).with_circuit_breaker(CircuitBreakerSettings {
+            enabled: true,
+            failure_rate_threshold: 0.5,
+            window_size: 2,
+            minimum_requests: 2,
+            cooldown_milliseconds: 60_000,
+        })
+    }
+
+    #[tokio::test]
+    async fn the_circuit_opens_when_the_provider_keeps_failing() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_circuit_breaker(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        for _ in 0..2 {
+            assert_err!(
+                email_client
+                    .send_email(email(), &subject(), &content(), &content())
+                    .await
+            );
+        }
+        let error = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, EmailError::CircuitOpen { .. }));
+        assert!(!error.is_permanent());
+        assert_eq!(email_client.circuit_state(), CircuitState::Open);
+        // the clones share the circuit
+        assert_eq!(email_client.clone().circuit_state(), CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn refused_recipients_do_not_open_the_circuit() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_circuit_breaker(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(postmark_error(
+                422,
+                406,
+                "You tried to send to a recipient that has been marked as inactive.",
+            ))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        for _ in 0..3 {
+            let error = email_client
+                .send_email(email(), &subject(), &content(), &content())
+                .await
+                .unwrap_err();
+            assert!(matches!(error, EmailError::InactiveRecipient(_)));
+        }
+        assert_eq!(email_client.circuit_state(), CircuitState::Closed);
     }
 }
@@ -0,0 +1,282 @@
+use crate::configuration::EmailPolicySettings;
+use crate::domain::{SubscriberEmail, ValidationError};
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+// mailboxes of the organisation rather than of a person, they don't subscribe to newsletters
+const ROLE_ACCOUNTS: [&str; 10] = [
+    "abuse",
+    "admin",
+    "administrator",
+    "hostmaster",
+    "mailer-daemon",
+    "no-reply",
+    "noreply",
+    "postmaster",
+    "root",
+    "webmaster",
+];
+
+// deployment specific rules on which addresses can subscribe, on top of the syntax checks
+pub struct EmailPolicy {
+    disposable_domains: HashSet<String>,
+    reject_role_accounts: bool,
+    allowed_domains: HashSet<String>,
+    denied_domains: HashSet<String>,
+    resolver: Option<TokioAsyncResolver>,
+}
+
+impl EmailPolicy {
+    pub fn from_settings(settings: &EmailPolicySettings) -> Result<Self, std::io::Error> {
+        let disposable_domains = match &settings.disposable_domains_file {
+            Some(path) => parse_domain_list(&std::fs::read_to_string(path)?),
+            None => HashSet::new(),
+        };
+        let resolver = if settings.mx_lookup {
+            Some(build_resolver(settings)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            disposable_domains,
+            reject_role_accounts: settings.reject_role_accounts,
+            allowed_domains: normalise_domains(&settings.allowed_domains),
+            denied_domains: normalise_domains(&settings.denied_domains),
+            resolver,
+        })
+    }
+
+    // the domains are compared as punycode, so that `bücher.example` and
+    // `xn--bcher-kva.example` get the same answer
+    pub async fn check(&self, email: &SubscriberEmail) -> Result<(), ValidationError> {
+        let (local_part, _) = email
+            .as_ref()
+            .rsplit_once('@')
+            .ok_or(ValidationError::InvalidFormat)?;
+        let domain = email.ascii_domain().trim_end_matches('.');
+
+        if !self.allowed_domains.is_empty() && !matches_any(domain, &self.allowed_domains) {
+            return Err(ValidationError::DomainNotAllowed);
+        }
+        if matches_any(domain, &self.denied_domains) {
+            return Err(ValidationError::DomainNotAllowed);
+        }
+        if matches_any(domain, &self.disposable_domains) {
+            return Err(ValidationError::DisposableDomain);
+        }
+        if self.reject_role_accounts && is_role_account(local_part) {
+            return Err(ValidationError::RoleAccount);
+        }
+        if let Some(resolver) = &self.resolver {
+            check_mail_server(resolver, domain).await?;
+        }
+        Ok(())
+    }
+}
+
+fn build_resolver(settings: &EmailPolicySettings) -> Result<TokioAsyncResolver, std::io::Error> {
+    let mut options = ResolverOpts::default();
+    options.timeout = settings.mx_lookup_timeout();
+    options.attempts = 1;
+
+    match &settings.dns_resolver {
+        Some(address) => {
+            let address: SocketAddr = address.parse().map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid DNS resolver address {}: {}", address, e),
+                )
+            })?;
+            let name_servers =
+                NameServerConfigGroup::from_ips_clear(&[address.ip()], address.port(), true);
+            Ok(TokioAsyncResolver::tokio(
+                ResolverConfig::from_parts(None, vec![], name_servers),
+                options,
+            ))
+        }
+        None => {
+            let (config, _) = hickory_resolver::system_conf::read_system_conf()?;
+            Ok(TokioAsyncResolver::tokio(config, options))
+        }
+    }
+}
+
+// the domain must declare a mail server, a lookup failure is not the subscriber's fault
+// so the address is accepted if the DNS can't be queried
+async fn check_mail_server(
+    resolver: &TokioAsyncResolver,
+    domain: &str,
+) -> Result<(), ValidationError> {
+    // the trailing dot makes the name absolute, so that no search domain is appended
+    match resolver.mx_lookup(format!("{}.", domain)).await {
+        // a "null MX" (RFC 7505) declares that the domain doesn't accept mail
+        Ok(lookup) if lookup.iter().any(|mx| !mx.exchange().is_root()) => Ok(()),
+        Ok(_) => Err(ValidationError::NoMailServer),
+        Err(e) => match e.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => Err(ValidationError::NoMailServer),
+            _ => {
+                tracing::warn!(error = %e, domain, "MX lookup failed, accepting the address");
+                Ok(())
+            }
+        },
+    }
+}
+
+// one domain per line, `#` starts a comment
+fn parse_domain_list(list: &str) -> HashSet<String> {
+    list.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(normalise_domain)
+        .collect()
+}
+
+fn normalise_domains(domains: &[String]) -> HashSet<String> {
+    domains
+        .iter()
+        .map(|domain| normalise_domain(domain))
+        .collect()
+}
+
+// as punycode, like `SubscriberEmail::ascii_domain`. An entry that isn't a valid domain
+// can't match any address, it's kept as written
+fn normalise_domain(domain: &str) -> String {
+    let domain = domain.trim().trim_end_matches('.');
+    idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_lowercase())
+}
+
+// a listed domain matches its subdomains too
+fn matches_any(domain: &str, domains: &HashSet<String>) -> bool {
+    let mut candidate = domain;
+    loop {
+        if domains.contains(candidate) {
+            return true;
+        }
+        match candidate.split_once('.') {
+            Some((_, parent)) => candidate = parent,
+            None => return false,
+        }
+    }
+}
+
+fn is_role_account(local_part: &str) -> bool {
+    // `noreply+newsletter@` is still `noreply@`
+    let mailbox = local_part.split('+').next().unwrap_or_default();
+    ROLE_ACCOUNTS.contains(&mailbox.to_lowercase().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_domain_list, EmailPolicy};
+    use crate::configuration::EmailPolicySettings;
+    use crate::domain::{SubscriberEmail, ValidationError};
+    use claim::assert_ok;
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.to_string()).unwrap()
+    }
+
+    fn policy(settings: EmailPolicySettings) -> EmailPolicy {
+        EmailPolicy::from_settings(&settings).unwrap()
+    }
+
+    #[test]
+    fn the_blocklist_skips_comments_and_blank_lines() {
+        let domains = parse_domain_list("# disposable\nMailinator.com\n\n  yopmail.com # free\n");
+        assert_eq!(domains.len(), 2);
+        assert!(domains.contains("mailinator.com") && domains.contains("yopmail.com"));
+    }
+
+    #[tokio::test]
+    async fn disposable_domains_and_their_subdomains_are_rejected() {
+        let mut policy = policy(EmailPolicySettings::default());
+        policy.disposable_domains = parse_domain_list("mailinator.com");
+
+        for address in ["a@mailinator.com", "a@eu.Mailinator.com"] {
+            assert_eq!(
+                policy.check(&email(address)).await,
+                Err(ValidationError::DisposableDomain)
+            );
+        }
+        assert_ok!(policy.check(&email("a@notmailinator.com")).await);
+    }
+
+    #[tokio::test]
+    async fn role_accounts_are_rejected_only_if_configured() {
+        let address = email("NoReply+news@example.com");
+        assert_ok!(policy(EmailPolicySettings::default()).check(&address).await);
+
+        let policy = policy(EmailPolicySettings {
+            reject_role_accounts: true,
+            ..EmailPolicySettings::default()
+        });
+        assert_eq!(
+            policy.check(&address).await,
+            Err(ValidationError::RoleAccount)
+        );
+        assert_ok!(policy.check(&email("alpha@example.com")).await);
+    }
+
+    #[tokio::test]
+    async fn allowed_and_denied_domains_are_enforced() {
+        let policy = policy(EmailPolicySettings {
+            allowed_domains: vec!["example.com".into()],
+            denied_domains: vec!["spam.example.com".into()],
+            ..EmailPolicySettings::default()
+        });
+
+        assert_ok!(policy.check(&email("a@example.com")).await);
+        assert_ok!(policy.check(&email("a@eu.example.com")).await);
+        assert_eq!(
+            policy.check(&email("a@example.org")).await,
+            Err(ValidationError::DomainNotAllowed)
+        );
+        assert_eq!(
+            policy.check(&email("a@spam.example.com")).await,
+            Err(ValidationError::DomainNotAllowed)
+        );
+    }
+
+    #[tokio::test]
+    async fn the_unicode_and_punycode_forms_of_a_domain_are_the_same_domain() {
+        let policy = policy(EmailPolicySettings {
+            denied_domains: vec!["xn--bcher-kva.example".into(), "Straße.example".into()],
+            ..EmailPolicySettings::default()
+        });
+
+        for address in [
+            "alice@bücher.example",
+            "alice@BÜCHER.example",
+            "alice@xn--bcher-kva.example",
+            "alice@xn--strae-oqa.example",
+            "alice@straße.example",
+        ] {
+            assert_eq!(
+                policy.check(&email(address)).await,
+                Err(ValidationError::DomainNotAllowed),
+                "{}",
+                address
+            );
+        }
+        assert_ok!(policy.check(&email("alice@buecher.example")).await);
+    }
+
+    #[tokio::test]
+    async fn an_allowed_unicode_domain_matches_its_punycode_form() {
+        let policy = policy(EmailPolicySettings {
+            allowed_domains: vec!["bücher.example".into()],
+            ..EmailPolicySettings::default()
+        });
+
+        assert_ok!(policy.check(&email("alice@xn--bcher-kva.example")).await);
+        assert_ok!(policy.check(&email("alice@bücher.example")).await);
+        assert_eq!(
+            policy.check(&email("alice@example.com")).await,
+            Err(ValidationError::DomainNotAllowed)
+        );
+    }
+}
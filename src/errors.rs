@@ -1,4 +1,11 @@
-use actix_web::{http::StatusCode, ResponseError};
+use crate::confirmation::SignedLinkError;
+use crate::domain::{FieldErrors, ValidationError};
+use actix_web::body::{BoxBody, EitherBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::{ErrorHandlerResponse, ErrorHandlers};
+use actix_web::{HttpMessage, HttpResponse, ResponseError};
+use tracing_actix_web::RequestId;
 
 fn error_chain_fmt(e: &impl std::error::Error, f: &mut std::fmt::Formatter) -> std::fmt::Result {
     writeln!(f, "{} \n", e)?;
@@ -10,50 +17,135 @@ fn error_chain_fmt(e: &impl std::error::Error, f: &mut std::fmt::Formatter) -> s
     Ok(())
 }
 
-//subscription errors -------------------------------------------------------------
-pub struct StoreTokenError(pub sqlx::Error);
+// problem details ----------------------------------------------------------------
 
-impl std::fmt::Display for StoreTokenError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "a database error was encountered while \
-            trying to store a subscription token."
-        )
-    }
+const PROBLEM_JSON: &str = "application/problem+json";
+
+// RFC 7807 body of the error responses, `code` is stable and meant for machines,
+// `detail` is only set for client errors, never with the internal error chain
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct Problem {
+    code: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<InvalidField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
-impl std::fmt::Debug for StoreTokenError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        error_chain_fmt(self, f)
-    }
+// one of the fields that failed validation, so that a form can highlight all of them
+#[derive(serde::Serialize, Clone, Debug)]
+struct InvalidField {
+    field: &'static str,
+    code: &'static str,
+    detail: String,
 }
 
-impl std::error::Error for StoreTokenError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        Some(&self.0)
+impl Problem {
+    pub fn new(status: StatusCode, code: &'static str, title: &'static str) -> Self {
+        Self {
+            code,
+            title,
+            status: status.as_u16(),
+            detail: None,
+            field: None,
+            errors: Vec::new(),
+            request_id: None,
+        }
     }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn field(mut self, field: &'static str) -> Self {
+        self.field = Some(field);
+        self
+    }
+
+    // `field` is the first invalid field, `errors` lists all of them
+    pub fn invalid_fields(mut self, errors: &FieldErrors) -> Self {
+        self.errors = errors
+            .iter()
+            .map(|e| InvalidField {
+                field: e.field,
+                code: e.error.code(),
+                detail: e.to_string(),
+            })
+            .collect();
+        self.field = self.errors.first().map(|e| e.field);
+        self.detail(errors.to_string())
+    }
+
+    fn body(&self) -> String {
+        serde_json::to_string(self).expect("failed to serialize a problem")
+    }
+
+    // the problem is also stored in the response extensions,
+    // `problem_details` completes it with the request id
+    pub fn response(self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut response = HttpResponse::build(status)
+            .insert_header((header::CONTENT_TYPE, PROBLEM_JSON))
+            .body(self.body());
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+fn internal_error() -> Problem {
+    Problem::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal_error",
+        "An unexpected error occurred",
+    )
+}
+
+// middleware adding the id of the request, as logged by `TracingLogger`, to the problem details
+pub fn problem_details<B: 'static>() -> ErrorHandlers<B> {
+    ErrorHandlers::new().default_handler(add_request_id)
 }
 
-pub struct CheckSubError(pub sqlx::Error);
+fn add_request_id<B>(response: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let problem = response.response().extensions().get::<Problem>().cloned();
+    let request_id = response.request().extensions().get::<RequestId>().copied();
 
-impl std::fmt::Display for CheckSubError {
+    let response = match (problem, request_id) {
+        (Some(mut problem), Some(request_id)) => {
+            problem.request_id = Some(request_id.to_string());
+            response.map_body(|_, _| EitherBody::right(BoxBody::new(problem.body())))
+        }
+        _ => response.map_into_left_body(),
+    };
+    Ok(ErrorHandlerResponse::Response(response))
+}
+
+//subscription errors -------------------------------------------------------------
+pub struct StoreTokenError(pub sqlx::Error);
+
+impl std::fmt::Display for StoreTokenError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
             "a database error was encountered while \
-            checking for subscriber existance."
+            trying to store a subscription token."
         )
     }
 }
 
-impl std::fmt::Debug for CheckSubError {
+impl std::fmt::Debug for StoreTokenError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         error_chain_fmt(self, f)
     }
 }
 
-impl std::error::Error for CheckSubError {
+impl std::error::Error for StoreTokenError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         Some(&self.0)
     }
@@ -62,7 +154,7 @@ impl std::error::Error for CheckSubError {
 #[derive(thiserror::Error)]
 pub enum SubscribeError {
     #[error("{0}")]
-    ValidationError(String),
+    ValidationError(FieldErrors),
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -80,6 +172,19 @@ impl ResponseError for SubscribeError {
             SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            SubscribeError::ValidationError(errors) => Problem::new(
+                self.status_code(),
+                "invalid_field",
+                "The subscription data is not valid",
+            )
+            .invalid_fields(errors),
+            SubscribeError::UnexpectedError(_) => internal_error(),
+        }
+        .response()
+    }
 }
 
 // confirmation errors ------------------------------------------------------------
@@ -156,8 +261,10 @@ impl std::error::Error for UnauthorizedConfirmationError {
 pub enum ConfirmError {
     #[error("{0}")]
     UnauthorizedError(String),
-    #[error("{0}")]
-    ValidationError(String),
+    #[error("subscription_token {0}")]
+    ValidationError(ValidationError),
+    #[error(transparent)]
+    SignedLinkError(#[from] SignedLinkError),
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -173,7 +280,286 @@ impl ResponseError for ConfirmError {
         match self {
             ConfirmError::ValidationError(_) => StatusCode::BAD_REQUEST,
             ConfirmError::UnauthorizedError(_) => StatusCode::UNAUTHORIZED,
+            ConfirmError::SignedLinkError(SignedLinkError::Invalid) => StatusCode::UNAUTHORIZED,
+            ConfirmError::SignedLinkError(SignedLinkError::Expired) => StatusCode::GONE,
             ConfirmError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ConfirmError::ValidationError(error) => {
+                let mut errors = FieldErrors::new();
+                errors.check::<()>("subscription_token", Err(error.clone()));
+                Problem::new(
+                    self.status_code(),
+                    "invalid_token",
+                    "The subscription token is not valid",
+                )
+                .invalid_fields(&errors)
+            }
+            ConfirmError::UnauthorizedError(message) => Problem::new(
+                self.status_code(),
+                "unknown_token",
+                "The subscription token does not belong to any subscriber",
+            )
+            .detail(message),
+            ConfirmError::SignedLinkError(SignedLinkError::Invalid) => Problem::new(
+                self.status_code(),
+                "invalid_link",
+                "The confirmation link is not valid",
+            ),
+            ConfirmError::SignedLinkError(SignedLinkError::Expired) => Problem::new(
+                self.status_code(),
+                "expired_link",
+                "The confirmation link expired, please subscribe again",
+            ),
+            ConfirmError::UnexpectedError(_) => internal_error(),
+        }
+        .response()
+    }
+}
+
+// tracking errors ----------------------------------------------------------------
+
+#[derive(thiserror::Error)]
+pub enum TrackingError {
+    #[error("{0}")]
+    InvalidLinkError(String),
+    #[error("open and click tracking is disabled")]
+    Disabled,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for TrackingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for TrackingError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TrackingError::InvalidLinkError(_) | TrackingError::Disabled => StatusCode::NOT_FOUND,
+            TrackingError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            TrackingError::InvalidLinkError(message) => Problem::new(
+                self.status_code(),
+                "invalid_tracking_link",
+                "The tracking link is not valid",
+            )
+            .detail(message),
+            TrackingError::Disabled => Problem::new(
+                self.status_code(),
+                "tracking_disabled",
+                "Open and click tracking is disabled",
+            ),
+            TrackingError::UnexpectedError(_) => internal_error(),
+        }
+        .response()
+    }
+}
+
+// authentication errors ----------------------------------------------------------
+
+#[derive(thiserror::Error)]
+pub enum AuthError {
+    #[error("{0}")]
+    MissingCredentials(String),
+    #[error("invalid credentials")]
+    InvalidCredentials(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::MissingCredentials(_) | AuthError::InvalidCredentials(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+            AuthError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = match self {
+            AuthError::MissingCredentials(message) => Problem::new(
+                self.status_code(),
+                "missing_credentials",
+                "The request needs admin credentials",
+            )
+            .detail(message),
+            // don't tell whether the username exists
+            AuthError::InvalidCredentials(_) => Problem::new(
+                self.status_code(),
+                "invalid_credentials",
+                "The credentials are not valid",
+            ),
+            AuthError::UnexpectedError(_) => internal_error(),
+        }
+        .response();
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static(r#"Basic realm="admin""#),
+            );
+        }
+        response
+    }
+}
+
+// dead letter errors -------------------------------------------------------------
+
+#[derive(thiserror::Error)]
+pub enum DeadLetterError {
+    #[error("there is no dead letter with id {0}")]
+    NotFound(uuid::Uuid),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for DeadLetterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for DeadLetterError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DeadLetterError::NotFound(_) => StatusCode::NOT_FOUND,
+            DeadLetterError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            DeadLetterError::NotFound(_) => Problem::new(
+                self.status_code(),
+                "dead_letter_not_found",
+                "The dead letter does not exist",
+            )
+            .detail(self.to_string()),
+            DeadLetterError::UnexpectedError(_) => internal_error(),
+        }
+        .response()
+    }
+}
+
+// email errors -------------------------------------------------------------------
+
+// what went wrong with a delivery, so that the caller can tell whether to retry,
+// to give up on the recipient or to alert
+#[derive(thiserror::Error)]
+pub enum EmailError {
+    #[error("the email transport doesn't support SMTPUTF8, {0} can't be delivered")]
+    Smtputf8NotSupported(String),
+    #[error("the email provider did not respond in time")]
+    Timeout(#[source] reqwest::Error),
+    #[error("the email provider could not be reached")]
+    Network(#[source] reqwest::Error),
+    #[error("the email provider is rate limiting the requests")]
+    RateLimited {
+        retry_after: Option<std::time::Duration>,
+    },
+    #[error("the recipient is not valid: {0}")]
+    InvalidRecipient(String),
+    // the provider stopped delivering to the recipient, e.g. after a hard bounce
+    // or a spam complaint
+    #[error("the recipient is inactive: {0}")]
+    InactiveRecipient(String),
+    #[error("the email provider rejected the credentials")]
+    AuthenticationFailed,
+    // `code` is the provider's own error code, if the response had one
+    #[error("the email provider responded {status}: {message}")]
+    ProviderError {
+        status: StatusCode,
+        code: Option<i64>,
+        message: String,
+    },
+    #[error("the email request could not be encoded")]
+    Serialization(#[source] reqwest::Error),
+    // the local file-drop backend couldn't write the email
+    #[error("the email could not be written")]
+    FileDrop(#[source] std::io::Error),
+    // the providers kept failing, they are not called until a circuit lets a request through
+    #[error("the circuit to the email provider is open")]
+    CircuitOpen { retry_after: std::time::Duration },
+}
+
+impl EmailError {
+    // retrying can't help, the email would fail the same way
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            EmailError::Smtputf8NotSupported(_)
+            | EmailError::InvalidRecipient(_)
+            | EmailError::InactiveRecipient(_)
+            | EmailError::Serialization(_) => true,
+            EmailError::ProviderError { status, .. } => {
+                status.is_client_error() && *status != StatusCode::TOO_MANY_REQUESTS
+            }
+            EmailError::Timeout(_)
+            | EmailError::Network(_)
+            | EmailError::RateLimited { .. }
+            | EmailError::AuthenticationFailed
+            | EmailError::FileDrop(_)
+            | EmailError::CircuitOpen { .. } => false,
+        }
+    }
+
+    // the provider itself is failing, as opposed to the email being refused
+    pub fn is_provider_failure(&self) -> bool {
+        match self {
+            EmailError::Timeout(_)
+            | EmailError::Network(_)
+            | EmailError::RateLimited { .. }
+            | EmailError::FileDrop(_) => true,
+            EmailError::ProviderError { status, .. } => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    // the deployment needs fixing, retrying alone won't help
+    pub fn needs_attention(&self) -> bool {
+        matches!(self, EmailError::AuthenticationFailed)
+    }
+
+    // the delay the provider asked to wait before the next request
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            EmailError::RateLimited { retry_after } => *retry_after,
+            EmailError::CircuitOpen { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for EmailError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            EmailError::Timeout(e)
+        } else if e.is_builder() || e.is_body() {
+            EmailError::Serialization(e)
+        } else {
+            EmailError::Network(e)
+        }
+    }
+}
+
+impl std::fmt::Debug for EmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
 }
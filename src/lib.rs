@@ -1,7 +1,21 @@
+pub mod authentication;
+pub mod bot_protection;
+pub mod circuit_breaker;
+pub mod cli;
 pub mod configuration;
+pub mod confirmation;
+pub mod dev_transports;
 pub mod domain;
 pub mod email_client;
+pub mod email_policy;
 pub mod errors;
+pub mod metrics;
+pub mod negotiation;
+pub mod outbox;
+pub mod pages;
 pub mod routes;
+pub mod shutdown;
+pub mod signing;
 pub mod startup;
 pub mod telemetry;
+pub mod tracking;
@@ -1,24 +1,58 @@
+use actix_server::cli::{self, Cli, Command, ConfigCommand};
 use actix_server::configuration::get_configuration;
-use actix_server::startup::Application;
-use actix_server::telemetry::{get_tracing_subscriber, init_tracing_subscriber};
+use actix_server::telemetry::{
+    get_tracer_provider, get_tracing_subscriber, init_tracing_subscriber, shutdown_tracing,
+};
+use anyhow::Context;
+use clap::Parser;
 
 #[tokio::main]
-pub async fn main() -> std::io::Result<()> {
+pub async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    // the configuration check only prints to the standard output,
+    // including why the configuration couldn't be loaded
+    if let Some(Command::Config {
+        command: ConfigCommand::Check,
+    }) = cli.command
+    {
+        let configuration = get_configuration().context("failed to load the configuration")?;
+        println!("{}", cli::check_config(&configuration)?);
+        return Ok(());
+    }
+
+    // read configuration
+    let configuration = get_configuration().expect("failed to load configuration");
+
     //----------------------------- LOG SETTINGS ------------------------------------------------------------------------------
+    let tracer_provider = get_tracer_provider("actix_server".to_string(), &configuration.telemetry)
+        .expect("failed to build the tracer provider");
     let tracing_subscriber = get_tracing_subscriber(
         "actix_server".to_string(),
         "info".to_string(),
         std::io::stdout,
+        &tracer_provider,
     );
-    init_tracing_subscriber(tracing_subscriber);
+    init_tracing_subscriber(tracing_subscriber, tracer_provider);
     //----------------------------- END LOG SETTINGS ------------------------------------------------------------------------------
 
-    // read configuration
-    let configuration = get_configuration().expect("failed to load configuration");
-
-    //build the application
-    let application = Application::build(configuration).await?;
-    application.run_until_stopped().await?;
+    let outcome = match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => cli::serve(configuration).await,
+        Command::Worker => cli::work(configuration).await,
+        Command::Migrate => cli::migrate(configuration).await,
+        Command::CreateAdmin { username } => {
+            async {
+                let password = cli::admin_password(
+                    std::env::var("ADMIN_PASSWORD").ok(),
+                    std::io::stdin().lock(),
+                )?;
+                cli::add_admin(configuration, username, password).await
+            }
+            .await
+        }
+        Command::Config { .. } => unreachable!("handled before the tracing initialization"),
+    };
 
-    Ok(())
+    shutdown_tracing();
+    outcome
 }
@@ -0,0 +1,246 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use sqlx::PgPool;
+use std::future::{ready, Ready};
+use std::time::Instant;
+
+// the metrics are process wide, like the tracing subscriber
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests_total: IntCounterVec,
+    pub http_request_duration_seconds: HistogramVec,
+    pub email_send_attempts_total: IntCounterVec,
+    pub email_send_failures_total: IntCounterVec,
+    pub email_send_duration_seconds: HistogramVec,
+    pub db_pool_connections: IntGaugeVec,
+    pub delivery_queue_depth: IntGauge,
+    pub email_dead_letters_total: IntCounter,
+    pub dead_letter_queue_depth: IntGauge,
+    pub circuit_breaker_state: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let http_requests_total = IntCounterVec::new(
+            Opts::new("http_requests_total", "Number of HTTP requests handled"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_request_duration_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency in seconds",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let email_send_attempts_total = IntCounterVec::new(
+            Opts::new("email_send_attempts_total", "Number of email send attempts"),
+            &["provider"],
+        )
+        .unwrap();
+        let email_send_failures_total = IntCounterVec::new(
+            Opts::new("email_send_failures_total", "Number of failed email sends"),
+            &["provider"],
+        )
+        .unwrap();
+        let email_send_duration_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "email_send_duration_seconds",
+                "Email provider latency in seconds",
+            ),
+            &["provider"],
+        )
+        .unwrap();
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new(
+                "db_pool_connections",
+                "Number of database pool connections by state",
+            ),
+            &["state"],
+        )
+        .unwrap();
+        let delivery_queue_depth = IntGauge::new(
+            "delivery_queue_depth",
+            "Number of background deliveries waiting to be processed",
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(http_requests_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(http_request_duration_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(email_send_attempts_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(email_send_failures_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(email_send_duration_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_pool_connections.clone()))
+            .unwrap();
+        let email_dead_letters_total = IntCounter::new(
+            "email_dead_letters_total",
+            "Number of emails moved to the dead letters",
+        )
+        .unwrap();
+        let dead_letter_queue_depth = IntGauge::new(
+            "dead_letter_queue_depth",
+            "Number of dead letters waiting to be replayed",
+        )
+        .unwrap();
+
+        let circuit_breaker_state = IntGaugeVec::new(
+            Opts::new(
+                "circuit_breaker_state",
+                "Current state of the circuit breakers, 1 for the state they are in",
+            ),
+            &["circuit", "state"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(delivery_queue_depth.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(circuit_breaker_state.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(email_dead_letters_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(dead_letter_queue_depth.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            http_requests_total,
+            http_request_duration_seconds,
+            email_send_attempts_total,
+            email_send_failures_total,
+            email_send_duration_seconds,
+            db_pool_connections,
+            delivery_queue_depth,
+            email_dead_letters_total,
+            dead_letter_queue_depth,
+            circuit_breaker_state,
+        }
+    }
+
+    // records the state of the connection pool, it's sampled when the metrics are scraped
+    pub fn observe_pool(&self, db_pool: &PgPool) {
+        let size = db_pool.size() as i64;
+        let idle = db_pool.num_idle() as i64;
+        self.db_pool_connections
+            .with_label_values(&["size"])
+            .set(size);
+        self.db_pool_connections
+            .with_label_values(&["idle"])
+            .set(idle);
+        self.db_pool_connections
+            .with_label_values(&["busy"])
+            .set(size - idle);
+    }
+
+    // records the number of emails waiting in the outbox and in the dead letters,
+    // whichever process delivers them
+    pub async fn observe_delivery_queue(&self, db_pool: &PgPool) {
+        match sqlx::query!(
+            r#"SELECT
+                (SELECT count(*) FROM outbox WHERE status IN ('pending', 'sending')) AS "pending!",
+                (SELECT count(*) FROM dead_letters) AS "dead_letters!""#
+        )
+        .fetch_one(db_pool)
+        .await
+        {
+            Ok(queue) => {
+                self.delivery_queue_depth.set(queue.pending);
+                self.dead_letter_queue_depth.set(queue.dead_letters);
+            }
+            Err(e) => tracing::warn!(error = ?e, "failed to sample the delivery queue"),
+        }
+    }
+
+    // renders all the metrics in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+// middleware recording count and latency of the HTTP requests, by route and status
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware { service }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        // use the route pattern and not the path, to keep the label cardinality bounded
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let response = fut.await?;
+            let status = response.status().as_u16().to_string();
+            let labels = [method.as_str(), route.as_str(), status.as_str()];
+            METRICS.http_requests_total.with_label_values(&labels).inc();
+            METRICS
+                .http_request_duration_seconds
+                .with_label_values(&labels)
+                .observe(start.elapsed().as_secs_f64());
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,136 @@
+use actix_web::dev::Payload;
+use actix_web::http::header::{Accept, Header};
+use actix_web::{error, web, FromRequest, HttpMessage, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+
+fn has_json_body(req: &HttpRequest) -> bool {
+    req.mime_type()
+        .ok()
+        .flatten()
+        .map(|media_type| {
+            media_type.subtype() == mime::JSON || media_type.suffix() == Some(mime::JSON)
+        })
+        .unwrap_or(false)
+}
+
+// body extractor accepting both `application/x-www-form-urlencoded` and `application/json`,
+// picked by the `Content-Type` of the request
+pub struct FormOrJson<T>(pub T);
+
+impl<T> FromRequest for FormOrJson<T>
+where
+    T: DeserializeOwned + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if has_json_body(req) {
+            let json = web::Json::<T>::from_request(req, payload);
+            return Box::pin(async move { Ok(FormOrJson(json.await?.into_inner())) });
+        }
+
+        let is_other_type = req
+            .mime_type()
+            .ok()
+            .flatten()
+            .map(|media_type| {
+                media_type.essence_str() != mime::APPLICATION_WWW_FORM_URLENCODED.essence_str()
+            })
+            .unwrap_or(false);
+        if is_other_type {
+            return Box::pin(async {
+                Err(error::ErrorUnsupportedMediaType(
+                    "expected a form or a JSON body",
+                ))
+            });
+        }
+
+        let form = web::Form::<T>::from_request(req, payload);
+        Box::pin(async move { Ok(FormOrJson(form.await?.into_inner())) })
+    }
+}
+
+// how a response should be rendered for the client that made the request
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ResponseFormat {
+    // browsers
+    Html,
+    // API clients
+    Json,
+    // clients that didn't express a preference
+    Empty,
+}
+
+impl ResponseFormat {
+    pub fn negotiate(req: &HttpRequest) -> Self {
+        let preferred = Accept::parse(req).ok().and_then(|accept| {
+            accept.ranked().into_iter().find_map(|media_type| {
+                if media_type.type_() == mime::TEXT && media_type.subtype() == mime::HTML {
+                    Some(ResponseFormat::Html)
+                } else if media_type.subtype() == mime::JSON {
+                    Some(ResponseFormat::Json)
+                } else {
+                    None
+                }
+            })
+        });
+
+        match preferred {
+            Some(format) => format,
+            // who sends JSON expects JSON back
+            None if has_json_body(req) => ResponseFormat::Json,
+            None => ResponseFormat::Empty,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResponseFormat;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn browsers_get_html() {
+        let req = TestRequest::default()
+            .insert_header((
+                "Accept",
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+            ))
+            .to_http_request();
+        assert_eq!(ResponseFormat::negotiate(&req), ResponseFormat::Html);
+    }
+
+    #[test]
+    fn api_clients_get_json() {
+        let req = TestRequest::default()
+            .insert_header(("Accept", "application/json"))
+            .to_http_request();
+        assert_eq!(ResponseFormat::negotiate(&req), ResponseFormat::Json);
+
+        let req = TestRequest::default()
+            .insert_header(("Content-Type", "application/json"))
+            .to_http_request();
+        assert_eq!(ResponseFormat::negotiate(&req), ResponseFormat::Json);
+    }
+
+    #[test]
+    fn the_quality_of_the_media_ranges_is_honoured() {
+        let req = TestRequest::default()
+            .insert_header(("Accept", "text/html;q=0.5, application/json"))
+            .to_http_request();
+        assert_eq!(ResponseFormat::negotiate(&req), ResponseFormat::Json);
+    }
+
+    #[test]
+    fn clients_without_preference_get_an_empty_body() {
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(ResponseFormat::negotiate(&req), ResponseFormat::Empty);
+
+        let req = TestRequest::default()
+            .insert_header(("Accept", "*/*"))
+            .to_http_request();
+        assert_eq!(ResponseFormat::negotiate(&req), ResponseFormat::Empty);
+    }
+}
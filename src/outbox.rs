@@ -0,0 +1,373 @@
+use crate::configuration::OutboxSettings;
+use crate::domain::SubscriberEmail;
+use crate::email_client::{Delivery, EmailClient};
+use crate::errors::EmailError;
+use crate::metrics::METRICS;
+use crate::shutdown::ShutdownSignal;
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use opentelemetry::global;
+use sqlx::{PgPool, Postgres};
+use std::collections::HashMap;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use uuid::Uuid;
+
+// writes the email in the transaction, it's delivered by `OutboxRelay` once committed
+#[tracing::instrument(
+    name = "queue email",
+    skip(transaction, recipient, html_body, text_body)
+)]
+pub async fn enqueue_email(
+    transaction: &mut sqlx::Transaction<'_, Postgres>,
+    recipient: &SubscriberEmail,
+    subject: &str,
+    html_body: &str,
+    text_body: &str,
+) -> Result<Uuid, sqlx::Error> {
+    // the delivery is recorded in the trace of the request that queued the email
+    let mut trace_context = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&tracing::Span::current().context(), &mut trace_context)
+    });
+
+    let id = Uuid::new_v4();
+    let now = Utc::now();
+    sqlx::query!(
+        r#"INSERT INTO outbox (id, recipient, subject, html_body, text_body, trace_context, created_at, next_attempt_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $7)"#,
+        id,
+        recipient.as_ref(),
+        subject,
+        html_body,
+        text_body,
+        serde_json::to_string(&trace_context).unwrap(),
+        now
+    )
+    .execute(transaction)
+    .await?;
+    Ok(id)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DeliveryOutcome {
+    // there is no email to deliver right now
+    QueueEmpty,
+    Delivered,
+    // the attempt failed, the email is retried later
+    Retrying,
+    // the email can't be delivered, it was moved to the dead letters
+    DeadLettered,
+    // the circuit to the provider is open, the email waits without using an attempt
+    Postponed,
+}
+
+// delivers the emails of the outbox, several relays can run at once: each email is leased
+// to the relay delivering it and skipped by the others until the lease expires
+pub struct OutboxRelay {
+    db_pool: PgPool,
+    email_client: EmailClient,
+    settings: OutboxSettings,
+}
+
+impl OutboxRelay {
+    pub fn new(db_pool: PgPool, email_client: EmailClient, settings: OutboxSettings) -> Self {
+        Self {
+            db_pool,
+            email_client,
+            settings,
+        }
+    }
+
+    // to be run as a background worker, it stops after the email it's delivering
+    pub async fn run(self, mut signal: ShutdownSignal) {
+        while !signal.is_triggered() {
+            match self.deliver_next().await {
+                Ok(DeliveryOutcome::QueueEmpty | DeliveryOutcome::Postponed) => {}
+                Ok(_) => continue,
+                Err(e) => tracing::error!(error = ?e, "failed to deliver the next email"),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(self.settings.poll_interval()) => {},
+                _ = signal.triggered() => {},
+            }
+        }
+    }
+
+    pub async fn deliver_next(&self) -> Result<DeliveryOutcome, anyhow::Error> {
+        // the email is claimed in its own short transaction, no lock is held while it's sent
+        let now = Utc::now();
+        let email = sqlx::query!(
+            r#"UPDATE outbox SET status = 'sending', locked_until = $2
+            WHERE id = (
+                SELECT id FROM outbox
+                WHERE (status = 'pending' AND next_attempt_at <= $1)
+                    OR (status = 'sending' AND locked_until <= $1)
+                ORDER BY next_attempt_at
+                FOR UPDATE SKIP LOCKED
+                LIMIT 1
+            )
+            RETURNING id, recipient, subject, html_body, text_body, trace_context, attempts,
+                locked_until AS "locked_until!""#,
+            now,
+            now.checked_add_signed(
+                Duration::from_std(self.settings.lease()).unwrap_or_else(|_| Duration::max_value())
+            )
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .context("failed to claim the next email to deliver")?;
+        let email = match email {
+            Some(email) => email,
+            None => return Ok(DeliveryOutcome::QueueEmpty),
+        };
+
+        let span = tracing::info_span!(
+            "delivering email",
+            outbox_id = %email.id,
+            email_provider = tracing::field::Empty
+        );
+        let trace_context: HashMap<String, String> =
+            serde_json::from_str(&email.trace_context).unwrap_or_default();
+        span.set_parent(global::get_text_map_propagator(|propagator| {
+            propagator.extract(&trace_context)
+        }));
+        let delivery = async {
+            match SubscriberEmail::parse(email.recipient.clone()) {
+                Ok(recipient) => {
+                    self.email_client
+                        .deliver(
+                            recipient,
+                            &email.subject,
+                            &email.html_body,
+                            &email.text_body,
+                        )
+                        .await
+                }
+                Err(e) => Delivery {
+                    attempts: Vec::new(),
+                    outcome: Err(EmailError::InvalidRecipient(e.to_string())),
+                },
+            }
+        }
+        .instrument(span.clone())
+        .await;
+        let sent = delivery.outcome;
+        if let Ok(provider) = &sent {
+            span.record("email_provider", provider);
+        }
+
+        // the outcome is recorded in a second transaction, if the email is still ours
+        let mut transaction = self
+            .db_pool
+            .begin()
+            .await
+            .context("failed to get a Postgres connection from the pool")?;
+        let still_claimed = sqlx::query!(
+            r#"SELECT id FROM outbox WHERE id = $1 AND status = 'sending' AND locked_until = $2
+            FOR UPDATE"#,
+            email.id,
+            email.locked_until
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .context("failed to lock the claimed email")?
+        .is_some();
+        if !still_claimed {
+            anyhow::bail!(
+                "the lease on the email {} expired before its delivery was recorded",
+                email.id
+            );
+        }
+
+        if let Err(EmailError::CircuitOpen { retry_after }) = sent {
+            sqlx::query!(
+                r#"UPDATE outbox SET status = 'pending', locked_until = NULL, next_attempt_at = $2 WHERE id = $1"#,
+                email.id,
+                Utc::now()
+                    .checked_add_signed(
+                        Duration::from_std(retry_after).unwrap_or_else(|_| Duration::max_value())
+                    )
+                    .unwrap_or(DateTime::<Utc>::MAX_UTC)
+            )
+            .execute(&mut transaction)
+            .await
+            .context("failed to postpone the email")?;
+            transaction
+                .commit()
+                .await
+                .context("failed to commit the postponement")?;
+            return Ok(DeliveryOutcome::Postponed);
+        }
+
+        let attempts = email.attempts + 1;
+        let now = Utc::now();
+        // one row per provider tried, a single one without provider if the email was
+        // refused before reaching any
+        let mut provider_attempts: Vec<_> = delivery
+            .attempts
+            .into_iter()
+            .map(|attempt| (Some(attempt.provider), attempt.attempted_at, attempt.error))
+            .collect();
+        if provider_attempts.is_empty() {
+            provider_attempts.push((None, now, sent.as_ref().err().map(|e| e.to_string())));
+        }
+        for (provider, attempted_at, error) in provider_attempts {
+            sqlx::query!(
+                r#"INSERT INTO email_delivery_attempts (email_id, attempted_at, error, provider) VALUES ($1, $2, $3, $4)"#,
+                email.id,
+                attempted_at,
+                error,
+                provider
+            )
+            .execute(&mut transaction)
+            .await
+            .context("failed to record the delivery attempt")?;
+        }
+
+        let outcome = match sent {
+            Ok(_) => {
+                sqlx::query!(
+                    r#"UPDATE outbox SET status = 'delivered', locked_until = NULL, attempts = $2, last_error = NULL, delivered_at = $3 WHERE id = $1"#,
+                    email.id,
+                    attempts,
+                    now
+                )
+                .execute(&mut transaction)
+                .await
+                .context("failed to record the delivery")?;
+                DeliveryOutcome::Delivered
+            }
+            Err(e) if e.is_permanent() || attempts >= self.settings.max_attempts => {
+                tracing::error!(outbox_id = %email.id, attempts, error = ?e, "giving up on an email");
+                dead_letter(&mut transaction, email.id, attempts, &e.to_string(), now)
+                    .await
+                    .context("failed to move the email to the dead letters")?;
+                METRICS.email_dead_letters_total.inc();
+                DeliveryOutcome::DeadLettered
+            }
+            Err(e) => {
+                tracing::warn!(outbox_id = %email.id, attempts, error = ?e, "failed to deliver an email, it will be retried");
+                sqlx::query!(
+                    r#"UPDATE outbox SET status = 'pending', locked_until = NULL, attempts = $2, last_error = $3, next_attempt_at = $4 WHERE id = $1"#,
+                    email.id,
+                    attempts,
+                    e.to_string(),
+                    now.checked_add_signed(retry_delay(&self.settings, attempts, e.retry_after()))
+                        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+                )
+                .execute(&mut transaction)
+                .await
+                .context("failed to record the failed attempt")?;
+                DeliveryOutcome::Retrying
+            }
+        };
+
+        transaction
+            .commit()
+            .await
+            .context("failed to commit the delivery")?;
+        Ok(outcome)
+    }
+}
+
+// the email leaves the outbox, the relay won't try it again until it's replayed
+async fn dead_letter(
+    transaction: &mut sqlx::Transaction<'_, Postgres>,
+    email_id: Uuid,
+    attempts: i32,
+    last_error: &str,
+    now: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO dead_letters (id, recipient, subject, html_body, text_body, trace_context, attempts, last_error, created_at, dead_lettered_at)
+        SELECT id, recipient, subject, html_body, text_body, trace_context, $2, $3, created_at, $4 FROM outbox WHERE id = $1"#,
+        email_id,
+        attempts,
+        last_error,
+        now
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(r#"DELETE FROM outbox WHERE id = $1"#, email_id)
+        .execute(transaction)
+        .await?;
+    Ok(())
+}
+
+// puts the dead letters back in the outbox, all of them if no id is given.
+// Their attempts start over, the history of the previous ones is kept
+#[tracing::instrument(name = "replay dead letters", skip(db_pool))]
+pub async fn replay_dead_letters(db_pool: &PgPool, id: Option<Uuid>) -> Result<u64, sqlx::Error> {
+    let replayed = sqlx::query!(
+        r#"WITH replayed AS (
+            DELETE FROM dead_letters WHERE $1::uuid IS NULL OR id = $1
+            RETURNING id, recipient, subject, html_body, text_body, trace_context, created_at
+        )
+        INSERT INTO outbox (id, recipient, subject, html_body, text_body, trace_context, created_at, next_attempt_at)
+        SELECT id, recipient, subject, html_body, text_body, trace_context, created_at, $2 FROM replayed"#,
+        id,
+        Utc::now()
+    )
+    .execute(db_pool)
+    .await?;
+    Ok(replayed.rows_affected())
+}
+
+// exponential backoff, after the given number of failed attempts.
+// The provider may ask to wait longer, e.g. when it's rate limiting
+fn retry_delay(
+    settings: &OutboxSettings,
+    attempts: i32,
+    retry_after: Option<std::time::Duration>,
+) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 31) as u32;
+    let delay = settings
+        .retry_delay()
+        .saturating_mul(2u32.saturating_pow(exponent))
+        .min(settings.max_retry_delay())
+        .max(retry_after.unwrap_or_default());
+    Duration::from_std(delay).unwrap_or_else(|_| Duration::max_value())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::retry_delay;
+    use crate::configuration::OutboxSettings;
+    use chrono::Duration;
+
+    #[test]
+    fn the_retry_delay_doubles_up_to_the_maximum() {
+        let settings = OutboxSettings {
+            retry_delay_milliseconds: 1000,
+            max_retry_delay_seconds: 60,
+            ..OutboxSettings::default()
+        };
+
+        assert_eq!(retry_delay(&settings, 1, None), Duration::seconds(1));
+        assert_eq!(retry_delay(&settings, 2, None), Duration::seconds(2));
+        assert_eq!(retry_delay(&settings, 6, None), Duration::seconds(32));
+        assert_eq!(retry_delay(&settings, 7, None), Duration::seconds(60));
+        assert_eq!(retry_delay(&settings, 100, None), Duration::seconds(60));
+    }
+
+    #[test]
+    fn the_provider_can_ask_to_wait_longer() {
+        let settings = OutboxSettings {
+            retry_delay_milliseconds: 1000,
+            ..OutboxSettings::default()
+        };
+
+        let retry_after = Some(std::time::Duration::from_secs(30));
+
+        assert_eq!(
+            retry_delay(&settings, 1, retry_after),
+            Duration::seconds(30)
+        );
+        assert_eq!(
+            retry_delay(&settings, 7, retry_after),
+            Duration::seconds(64)
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use crate::configuration::{BrandingSettings, PageRedirects};
+use actix_web::http::{header, StatusCode};
+use actix_web::HttpResponse;
+use minijinja::{context, Environment};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+// the default templates, each of them can be replaced through `BrandingSettings::templates_dir`
+const TEMPLATES: [(&str, &str); 7] = [
+    ("base.html", include_str!("../templates/base.html")),
+    ("signup.html", include_str!("../templates/signup.html")),
+    (
+        "check_inbox.html",
+        include_str!("../templates/check_inbox.html"),
+    ),
+    ("confirm.html", include_str!("../templates/confirm.html")),
+    (
+        "confirmed.html",
+        include_str!("../templates/confirmed.html"),
+    ),
+    (
+        "invalid_link.html",
+        include_str!("../templates/invalid_link.html"),
+    ),
+    (
+        "unsubscribed.html",
+        include_str!("../templates/unsubscribed.html"),
+    ),
+];
+
+// the pages shown to the subscribers in a browser
+pub enum Page<'a> {
+    Signup {
+        honeypot_field: &'static str,
+        hidden_fields: &'a BTreeMap<&'static str, String>,
+    },
+    CheckInbox,
+    Confirm {
+        // the query parameter of the confirmation link, sent back by the form
+        parameter: &'static str,
+        value: &'a str,
+        auto_submit: bool,
+    },
+    Confirmed {
+        already_confirmed: bool,
+    },
+    InvalidLink {
+        expired: bool,
+    },
+    Unsubscribed,
+}
+
+#[derive(serde::Serialize)]
+struct Brand {
+    name: String,
+    logo_url: Option<String>,
+    primary_color: String,
+}
+
+pub struct Pages {
+    templates: Environment<'static>,
+    brand: Brand,
+    redirects: PageRedirects,
+}
+
+impl Pages {
+    pub fn from_settings(
+        branding: &BrandingSettings,
+        redirects: PageRedirects,
+    ) -> Result<Self, std::io::Error> {
+        let mut templates = Environment::new();
+        for (name, default) in TEMPLATES {
+            let source = match &branding.templates_dir {
+                Some(dir) if Path::new(dir).join(name).exists() => {
+                    std::fs::read_to_string(Path::new(dir).join(name))?
+                }
+                _ => default.to_string(),
+            };
+            // syntax errors are reported at startup rather than to the subscribers
+            templates
+                .add_template_owned(name, source)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        }
+
+        Ok(Self {
+            templates,
+            brand: Brand {
+                name: branding.name.clone(),
+                logo_url: branding.logo_url.clone(),
+                primary_color: branding.primary_color.clone(),
+            },
+            redirects,
+        })
+    }
+
+    // the rendered page, or a redirect to the one configured in its place
+    pub fn render(&self, page: Page, status: StatusCode) -> Result<HttpResponse, minijinja::Error> {
+        if let Some(url) = self.redirect(&page) {
+            return Ok(HttpResponse::SeeOther()
+                .insert_header((header::LOCATION, url.as_str()))
+                .finish());
+        }
+
+        let brand = &self.brand;
+        let (name, context) = match page {
+            Page::Signup {
+                honeypot_field,
+                hidden_fields,
+            } => (
+                "signup.html",
+                context! { brand, honeypot_field, hidden_fields },
+            ),
+            Page::CheckInbox => ("check_inbox.html", context! { brand }),
+            Page::Confirm {
+                parameter,
+                value,
+                auto_submit,
+            } => (
+                "confirm.html",
+                context! { brand, parameter, value, auto_submit },
+            ),
+            Page::Confirmed { already_confirmed } => {
+                ("confirmed.html", context! { brand, already_confirmed })
+            }
+            Page::InvalidLink { expired } => ("invalid_link.html", context! { brand, expired }),
+            Page::Unsubscribed => ("unsubscribed.html", context! { brand }),
+        };
+        let body = self.templates.get_template(name)?.render(context)?;
+
+        Ok(HttpResponse::build(status)
+            .content_type("text/html; charset=utf-8")
+            .body(body))
+    }
+
+    fn redirect(&self, page: &Page) -> Option<&String> {
+        match page {
+            Page::Signup { .. } => self.redirects.signup.as_ref(),
+            Page::CheckInbox => self.redirects.check_inbox.as_ref(),
+            // the form must be submitted from this page, the link can't be handed over
+            Page::Confirm { .. } => None,
+            Page::Confirmed { .. } => self.redirects.confirmed.as_ref(),
+            Page::InvalidLink { .. } => self.redirects.invalid_link.as_ref(),
+            Page::Unsubscribed => self.redirects.unsubscribed.as_ref(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Page, Pages};
+    use crate::configuration::{BrandingSettings, PageRedirects};
+    use actix_web::body::MessageBody;
+    use actix_web::http::StatusCode;
+    use actix_web::HttpResponse;
+
+    fn body(response: HttpResponse) -> String {
+        let bytes = response.into_body().try_into_bytes().unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn pages_are_rendered_with_the_branding() {
+        let pages = Pages::from_settings(
+            &BrandingSettings {
+                name: "Acme Weekly".into(),
+                logo_url: Some("https://acme.example/logo.png".into()),
+                ..BrandingSettings::default()
+            },
+            PageRedirects::default(),
+        )
+        .unwrap();
+
+        let response = pages.render(Page::CheckInbox, StatusCode::OK).unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let page = body(response);
+        assert!(page.contains("<title>Check your inbox - Acme Weekly</title>"));
+        // slashes are escaped too
+        assert!(page.contains(r#"<img src="https:&#x2f;&#x2f;acme.example&#x2f;logo.png""#));
+    }
+
+    #[test]
+    fn the_values_are_escaped() {
+        let pages =
+            Pages::from_settings(&BrandingSettings::default(), PageRedirects::default()).unwrap();
+
+        let response = pages
+            .render(
+                Page::Confirm {
+                    parameter: "subscription_token",
+                    value: r#""><script>alert(1)</script>"#,
+                    auto_submit: false,
+                },
+                StatusCode::OK,
+            )
+            .unwrap();
+
+        assert!(!body(response).contains("<script>alert(1)</script>"));
+    }
+
+    #[test]
+    fn a_template_can_be_replaced() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("confirmed.html"),
+            "<p>{{ brand.name }} is glad to have you</p>",
+        )
+        .unwrap();
+
+        let pages = Pages::from_settings(
+            &BrandingSettings {
+                templates_dir: Some(dir.to_string_lossy().into_owned()),
+                ..BrandingSettings::default()
+            },
+            PageRedirects::default(),
+        )
+        .unwrap();
+        let confirmed = pages
+            .render(
+                Page::Confirmed {
+                    already_confirmed: false,
+                },
+                StatusCode::OK,
+            )
+            .unwrap();
+        let check_inbox = pages.render(Page::CheckInbox, StatusCode::OK).unwrap();
+
+        assert_eq!(body(confirmed), "<p>Newsletter is glad to have you</p>");
+        assert!(body(check_inbox).contains("Check your inbox"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_page_can_be_replaced_by_a_redirect() {
+        let pages = Pages::from_settings(
+            &BrandingSettings::default(),
+            PageRedirects {
+                invalid_link: Some("https://acme.example/link-expired".into()),
+                ..PageRedirects::default()
+            },
+        )
+        .unwrap();
+
+        let response = pages
+            .render(Page::InvalidLink { expired: true }, StatusCode::GONE)
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response.headers().get("Location").unwrap(),
+            "https://acme.example/link-expired"
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{errors::DeadLetterError, outbox::replay_dead_letters};
+use actix_web::{web, HttpResponse, Result};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(serde::Deserialize)]
+pub struct ListParameters {
+    limit: Option<i64>,
+}
+
+// the payload is left out of the list, it's returned by `dead_letter`
+#[derive(serde::Serialize)]
+struct DeadLetterSummary {
+    id: Uuid,
+    recipient: String,
+    subject: String,
+    attempts: i32,
+    last_error: String,
+    created_at: DateTime<Utc>,
+    dead_lettered_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct DeadLetterList {
+    dead_letters: Vec<DeadLetterSummary>,
+}
+
+#[derive(serde::Serialize)]
+struct DeliveryAttempt {
+    attempted_at: DateTime<Utc>,
+    error: Option<String>,
+    // the provider the email was handed to, `None` if it was refused before reaching any
+    provider: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct DeadLetter {
+    id: Uuid,
+    recipient: String,
+    subject: String,
+    html_body: String,
+    text_body: String,
+    attempts: i32,
+    last_error: String,
+    created_at: DateTime<Utc>,
+    dead_lettered_at: DateTime<Utc>,
+    // every attempt since the email was queued, including the ones before a replay
+    history: Vec<DeliveryAttempt>,
+}
+
+#[derive(serde::Serialize)]
+struct ReplayReport {
+    replayed: u64,
+}
+
+// the most recent dead letters first
+#[tracing::instrument(name = "list dead letters", skip(parameters, db_pool))]
+pub async fn list_dead_letters(
+    parameters: web::Query<ListParameters>,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, DeadLetterError> {
+    let limit = parameters
+        .limit
+        .unwrap_or(DEFAULT_LIMIT)
+        .clamp(1, MAX_LIMIT);
+    let dead_letters = sqlx::query_as!(
+        DeadLetterSummary,
+        r#"SELECT id, recipient, subject, attempts, last_error, created_at, dead_lettered_at
+        FROM dead_letters ORDER BY dead_lettered_at DESC LIMIT $1"#,
+        limit
+    )
+    .fetch_all(db_pool.get_ref())
+    .await
+    .context("failed to list the dead letters")?;
+
+    Ok(HttpResponse::Ok().json(DeadLetterList { dead_letters }))
+}
+
+#[tracing::instrument(name = "inspect dead letter", skip(db_pool))]
+pub async fn dead_letter(
+    id: web::Path<Uuid>,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, DeadLetterError> {
+    let id = id.into_inner();
+    let saved = sqlx::query!(
+        r#"SELECT id, recipient, subject, html_body, text_body, attempts, last_error, created_at, dead_lettered_at
+        FROM dead_letters WHERE id = $1"#,
+        id
+    )
+    .fetch_optional(db_pool.get_ref())
+    .await
+    .context("failed to retrieve the dead letter")?
+    .ok_or(DeadLetterError::NotFound(id))?;
+    let history = sqlx::query_as!(
+        DeliveryAttempt,
+        r#"SELECT attempted_at, error, provider FROM email_delivery_attempts WHERE email_id = $1 ORDER BY attempted_at, id"#,
+        id
+    )
+    .fetch_all(db_pool.get_ref())
+    .await
+    .context("failed to retrieve the delivery attempts")?;
+
+    Ok(HttpResponse::Ok().json(DeadLetter {
+        id: saved.id,
+        recipient: saved.recipient,
+        subject: saved.subject,
+        html_body: saved.html_body,
+        text_body: saved.text_body,
+        attempts: saved.attempts,
+        last_error: saved.last_error,
+        created_at: saved.created_at,
+        dead_lettered_at: saved.dead_lettered_at,
+        history,
+    }))
+}
+
+#[tracing::instrument(name = "replay dead letter", skip(db_pool))]
+pub async fn replay_dead_letter(
+    id: web::Path<Uuid>,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, DeadLetterError> {
+    let id = id.into_inner();
+    let replayed = replay_dead_letters(&db_pool, Some(id))
+        .await
+        .context("failed to replay the dead letter")?;
+    if replayed == 0 {
+        return Err(DeadLetterError::NotFound(id));
+    }
+
+    Ok(HttpResponse::Ok().json(ReplayReport { replayed }))
+}
+
+#[tracing::instrument(name = "replay all dead letters", skip(db_pool))]
+pub async fn replay_all_dead_letters(
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, DeadLetterError> {
+    let replayed = replay_dead_letters(&db_pool, None)
+        .await
+        .context("failed to replay the dead letters")?;
+    tracing::info!(replayed, "dead letters replayed");
+
+    Ok(HttpResponse::Ok().json(ReplayReport { replayed }))
+}
@@ -1,5 +1,177 @@
-use actix_web::{HttpResponse, Responder};
+use crate::circuit_breaker::CircuitState;
+use crate::configuration::HealthSettings;
+use crate::email_client::EmailClient;
+use crate::startup::MIGRATOR;
+use actix_web::{web, HttpResponse, Responder};
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::Instant;
 
 pub async fn health_check() -> impl Responder {
     HttpResponse::Ok()
 }
+
+#[derive(serde::Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ComponentStatus {
+    Up,
+    Down,
+}
+
+#[derive(serde::Serialize)]
+pub struct ComponentHealth {
+    status: ComponentStatus,
+    // a failing critical component makes the whole service not ready
+    critical: bool,
+    latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    // set for the components guarded by a circuit breaker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    circuit: Option<CircuitState>,
+}
+
+#[derive(serde::Serialize)]
+pub struct HealthReport {
+    status: ComponentStatus,
+    components: BTreeMap<&'static str, ComponentHealth>,
+}
+
+// the process is running and able to serve requests, its dependencies are not checked
+pub async fn liveness() -> HttpResponse {
+    HttpResponse::Ok().json(HealthReport {
+        status: ComponentStatus::Up,
+        components: BTreeMap::new(),
+    })
+}
+
+#[tracing::instrument(name = "readiness check", skip(db_pool, email_client, settings))]
+pub async fn readiness(
+    db_pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    settings: web::Data<HealthSettings>,
+) -> HttpResponse {
+    let timeout = settings.timeout();
+    let mut components = BTreeMap::new();
+
+    components.insert(
+        "database",
+        check_component(true, timeout, check_database(&db_pool)).await,
+    );
+    components.insert(
+        "migrations",
+        check_component(true, timeout, check_migrations(&db_pool)).await,
+    );
+    if settings.probe_email_provider {
+        components.insert(
+            "email_provider",
+            check_component(false, timeout, async {
+                // the error names the provider's URL
+                email_client.probe().await.map_err(|e| {
+                    tracing::error!(error = %e, "failed to reach the email provider");
+                    "the email provider can not be reached".to_string()
+                })
+            })
+            .await,
+        );
+    }
+
+    // the state of the circuits, without calling the providers. The emails can still be
+    // sent while a fallback provider is available
+    let circuit = email_client.circuit_state();
+    let mut email_circuit = check_component(false, timeout, async {
+        if email_client.has_available_transport() {
+            Ok(())
+        } else {
+            Err("the circuit to every email provider is open".to_string())
+        }
+    })
+    .await;
+    email_circuit.circuit = Some(circuit);
+    components.insert("email_circuit", email_circuit);
+
+    let status = if components
+        .values()
+        .any(|c| c.critical && c.status == ComponentStatus::Down)
+    {
+        ComponentStatus::Down
+    } else {
+        ComponentStatus::Up
+    };
+    let report = HealthReport { status, components };
+
+    match status {
+        ComponentStatus::Up => HttpResponse::Ok().json(report),
+        ComponentStatus::Down => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
+
+// runs a single check, bounded by the timeout, measuring its latency
+async fn check_component(
+    critical: bool,
+    timeout: std::time::Duration,
+    check: impl Future<Output = Result<(), String>>,
+) -> ComponentHealth {
+    let start = Instant::now();
+    let outcome = match tokio::time::timeout(timeout, check).await {
+        Ok(outcome) => outcome,
+        Err(_) => Err(format!("timed out after {}ms", timeout.as_millis())),
+    };
+    let latency_ms = start.elapsed().as_millis();
+
+    match outcome {
+        Ok(()) => ComponentHealth {
+            status: ComponentStatus::Up,
+            critical,
+            latency_ms,
+            error: None,
+            circuit: None,
+        },
+        Err(e) => {
+            tracing::warn!(error = %e, "health check failed");
+            ComponentHealth {
+                status: ComponentStatus::Down,
+                critical,
+                latency_ms,
+                error: Some(e),
+                circuit: None,
+            }
+        }
+    }
+}
+
+// the report is public, the sqlx errors are only logged since they can name the
+// database, its host or its users
+async fn check_database(db_pool: &PgPool) -> Result<(), String> {
+    sqlx::query("SELECT 1")
+        .execute(db_pool)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "failed to query the database");
+            "the database can not be queried".to_string()
+        })?;
+    Ok(())
+}
+
+// the database must be migrated at least up to the last migration embedded in the binary
+async fn check_migrations(db_pool: &PgPool) -> Result<(), String> {
+    let expected = MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+    let applied: Option<i64> =
+        sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+            .fetch_one(db_pool)
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %e, "failed to read the applied migrations");
+                "the applied migrations can not be read".to_string()
+            })?;
+
+    match applied {
+        Some(applied) if applied >= expected => Ok(()),
+        applied => Err(format!(
+            "the database is at migration {}, {} is expected",
+            applied.unwrap_or(0),
+            expected
+        )),
+    }
+}
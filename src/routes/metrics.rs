@@ -0,0 +1,11 @@
+use crate::metrics::METRICS;
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+pub async fn metrics(db_pool: web::Data<PgPool>) -> HttpResponse {
+    METRICS.observe_pool(&db_pool);
+    METRICS.observe_delivery_queue(&db_pool).await;
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
+}
@@ -1,7 +1,13 @@
+pub mod dead_letters;
 pub mod health_check;
+pub mod metrics;
 pub mod subscriptions;
 pub mod subscriptions_confirm;
+pub mod tracking;
 
+pub use dead_letters::*;
 pub use health_check::*;
+pub use metrics::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use tracking::*;
@@ -1,12 +1,21 @@
 use crate::{
-    domain::{NewSubscriber, SubscriberEmail, SubscriberName, SubscriptionToken},
+    bot_protection::{BotProtection, Submission, HONEYPOT_FIELD},
+    configuration::ConfirmationMode,
+    confirmation::Confirmations,
+    domain::{
+        FieldErrors, NewSubscriber, SubscriberEmail, SubscriberName, SubscriptionToken,
+        ValidationError,
+    },
     email_client::EmailClient,
-    errors::{CheckSubError, StoreTokenError, SubscribeError},
-    startup::ApplicationBaseUrl,
+    email_policy::EmailPolicy,
+    errors::{StoreTokenError, SubscribeError},
+    negotiation::{FormOrJson, ResponseFormat},
+    outbox::enqueue_email,
+    pages::{Page, Pages},
 };
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Result};
 use anyhow::Context;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use sqlx::{PgPool, Postgres};
 use uuid::Uuid;
 
@@ -14,127 +23,269 @@ use uuid::Uuid;
 pub struct FormData {
     email: String,
     name: String,
+    #[serde(default)]
+    tracking_consent: bool,
+    // bot protection, see `BotProtection`
+    #[serde(rename = "website")]
+    honeypot: Option<String>,
+    form_token: Option<String>,
+    pow_challenge: Option<String>,
+    pow_nonce: Option<String>,
 }
 
-#[tracing::instrument(name = "saving subscriber to the database", skip(new_sub, transaction))]
-pub async fn insert_subscriber(
+impl FormData {
+    fn submission(&self) -> Submission<'_> {
+        Submission {
+            email: &self.email,
+            honeypot: self.honeypot.as_deref(),
+            form_token: self.form_token.as_deref(),
+            pow_challenge: self.pow_challenge.as_deref(),
+            pow_nonce: self.pow_nonce.as_deref(),
+        }
+    }
+}
+
+// inserts the subscriber or, if the address is already subscribed, returns the stored one.
+// Returns `None` if a confirmation email was sent after `resend_cutoff`: the row is locked
+// until the transaction ends, so concurrent signups for the same address wait for each other
+// and only the first one sends an email.
+#[tracing::instrument(
+    name = "saving subscriber to the database",
+    skip(new_sub, resend_cutoff, transaction)
+)]
+pub async fn upsert_subscriber(
     new_sub: &NewSubscriber,
+    resend_cutoff: DateTime<Utc>,
     transaction: &mut sqlx::Transaction<'_, Postgres>,
-) -> Result<Uuid, sqlx::Error> {
-    let subscriber_id = Uuid::new_v4();
-    sqlx::query!(r#"INSERT into public.subscriptions (id, email, name, subscribed_at, status) VALUES ($1, $2, $3, $4, 'pending_confirmation')"#,
-        subscriber_id,
+) -> Result<Option<(Uuid, NewSubscriber)>, sqlx::Error> {
+    let saved = sqlx::query!(
+        r#"INSERT into public.subscriptions (id, email, email_canonical, name, subscribed_at, status, tracking_consent, confirmation_sent_at)
+        VALUES ($1, $2, $3, $4, $5, 'pending_confirmation', $6, $5)
+        ON CONFLICT (email_canonical) DO UPDATE SET confirmation_sent_at = EXCLUDED.confirmation_sent_at
+        WHERE subscriptions.confirmation_sent_at <= $7
+        RETURNING id, name, email, tracking_consent"#,
+        Uuid::new_v4(),
         new_sub.email.as_ref(),
+        new_sub.email.canonical(),
         new_sub.name.as_ref(),
-        Utc::now()
+        Utc::now(),
+        new_sub.tracking_consent,
+        resend_cutoff
     )
-    .execute(transaction)
+    .fetch_optional(transaction)
     .await?;
-    Ok(subscriber_id)
+    Ok(saved.map(|subscriber| {
+        let saved_sub = NewSubscriber {
+            name: SubscriberName::parse(subscriber.name).unwrap(), // FIXME: this can be dangerous but it comes from the database so it must have passed this check during the insert operation
+            email: SubscriberEmail::parse(subscriber.email).unwrap(), // FIXME: this can be dangerous but it comes from the database so it must have passed this check during the insert operation
+            tracking_consent: subscriber.tracking_consent,
+        };
+        (subscriber.id, saved_sub)
+    }))
 }
 
-impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
+// collects the errors of every field, the email policy is checked only if the email is well-formed
+async fn parse_subscriber(
+    form: &FormData,
+    email_policy: &EmailPolicy,
+    email_client: &EmailClient,
+) -> Result<NewSubscriber, SubscribeError> {
+    let mut errors = FieldErrors::new();
+    let name = errors.check("name", SubscriberName::parse(form.name.clone()));
+    let email = match errors.check("email", SubscriberEmail::parse(form.email.clone())) {
+        // don't accept subscribers we would not be able to send the confirmation to
+        Some(email) if email.requires_smtputf8() && !email_client.supports_smtputf8() => {
+            errors.check("email", Err(ValidationError::Undeliverable))
+        }
+        Some(email) => errors.check("email", email_policy.check(&email).await.map(|()| email)),
+        None => None,
+    };
 
-    fn try_from(form: FormData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(form.name)?;
-        let email = SubscriberEmail::parse(form.email)?;
-        Ok(NewSubscriber { name, email })
+    match (name, email) {
+        (Some(name), Some(email)) => Ok(NewSubscriber {
+            name,
+            email,
+            tracking_consent: form.tracking_consent,
+        }),
+        _ => Err(SubscribeError::ValidationError(errors)),
     }
 }
 
+// the email is sent by the outbox relay once the transaction is committed
 #[tracing::instrument(
-    name = "sending confirmation email to the new subscriber",
-    skip(email_client, new_sub)
+    name = "queueing confirmation email to the new subscriber",
+    skip(transaction, recipient, confirmation_link)
 )]
-pub async fn send_confirmation_email(
-    email_client: &EmailClient,
-    new_sub: NewSubscriber,
-    base_url: &str,
-    subscription_token: &SubscriptionToken,
-) -> Result<(), reqwest::Error> {
-    let confirmation_link = &format!(
-        "{}/subscriptions/confirm?subscription_token={}",
-        base_url,
-        subscription_token.as_ref()
-    );
+pub async fn queue_confirmation_email(
+    transaction: &mut sqlx::Transaction<'_, Postgres>,
+    recipient: &SubscriberEmail,
+    confirmation_link: &str,
+) -> Result<(), sqlx::Error> {
     let html_body = format!("Welcome to our newsletter!<br /> Please, click <a href=\"{}\">here</a> to confirm your subscription.", confirmation_link);
     let plain_body = format!(
         "Welcome to our newsletter! Please, visit this link: {} to confirm your subscription.",
         confirmation_link
     );
 
-    email_client
-        .send_email(new_sub.email, "Welcome!", &html_body, &plain_body)
-        .await
+    enqueue_email(transaction, recipient, "Welcome!", &html_body, &plain_body).await?;
+    Ok(())
 }
 
+#[derive(serde::Serialize)]
+struct SubscriptionResponse {
+    status: &'static str,
+}
+
+// the response is the same for new and existing subscribers, not to disclose who is subscribed
+fn pending_confirmation_response(
+    format: ResponseFormat,
+    pages: &Pages,
+) -> Result<HttpResponse, SubscribeError> {
+    Ok(match format {
+        ResponseFormat::Json => HttpResponse::Ok().json(SubscriptionResponse {
+            status: "pending_confirmation",
+        }),
+        ResponseFormat::Html => pages
+            .render(Page::CheckInbox, StatusCode::OK)
+            .context("Failed to render the check inbox page")?,
+        ResponseFormat::Empty => HttpResponse::Ok().finish(),
+    })
+}
+
+// the hidden fields the signup form must send back, as a JSON object for API clients
+// or embedded in a ready-made form for browsers
+#[tracing::instrument(
+    name = "issuing the subscription form",
+    skip(req, bot_protection, pages)
+)]
+pub async fn subscription_form(
+    req: HttpRequest,
+    bot_protection: web::Data<BotProtection>,
+    pages: web::Data<Pages>,
+) -> Result<HttpResponse, SubscribeError> {
+    let fields = bot_protection.issue(Utc::now());
+    if ResponseFormat::negotiate(&req) != ResponseFormat::Html {
+        return Ok(HttpResponse::Ok().json(fields));
+    }
+
+    Ok(pages
+        .render(
+            Page::Signup {
+                honeypot_field: HONEYPOT_FIELD,
+                hidden_fields: &fields,
+            },
+            StatusCode::OK,
+        )
+        .context("Failed to render the signup page")?)
+}
+
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(
     name = "Adding new subscriber",
-    skip(form, db_pool, email_client)
+    skip(req, form, db_pool, email_client, bot_protection, email_policy, confirmations, pages)
     fields (
-        subscriber_email = %form.email,
-        subscriber_name = %form.name
+        subscriber_email = %form.0.email,
+        subscriber_name = %form.0.name
     )
 )]
 pub async fn subscribe(
-    form: web::Form<FormData>,
+    req: HttpRequest,
+    form: FormOrJson<FormData>,
     db_pool: web::Data<PgPool>,
     email_client: web::Data<EmailClient>,
-    base_url: web::Data<ApplicationBaseUrl>,
+    bot_protection: web::Data<BotProtection>,
+    email_policy: web::Data<EmailPolicy>,
+    confirmations: web::Data<Confirmations>,
+    pages: web::Data<Pages>,
 ) -> Result<HttpResponse, SubscribeError> {
-    // checking subscriber existance
-    if let Some((existing_sub, token)) = subscriber_existance_check(&form.0.email, &db_pool)
-        .await
-        .context("Failed to check user existance")?
-    {
-        send_confirmation_email(&email_client, existing_sub, &base_url.0, &token)
-            .await
-            .context("Failed to send confirmation email")?;
-        return Ok(HttpResponse::Ok().finish());
+    // bots get the same response as humans, without any email being sent,
+    // so that they can't tell which of their attempts worked
+    let form = form.0;
+    let submission = form.submission();
+    if let Err(rejection) = bot_protection.check(&submission, Utc::now()) {
+        tracing::warn!(reason = %rejection, "suspicious subscription rejected");
+        return pending_confirmation_response(ResponseFormat::negotiate(&req), &pages);
     }
 
-    // if the subscriber is new
-    let new_sub = form.0.try_into().map_err(SubscribeError::ValidationError)?;
+    let new_sub = parse_subscriber(&form, &email_policy, &email_client).await?;
+
+    // spent only now, so that a form with an invalid field can be corrected and sent again
+    if let Err(rejection) = bot_protection.spend(&submission, Utc::now()) {
+        tracing::warn!(reason = %rejection, "suspicious subscription rejected");
+        return pending_confirmation_response(ResponseFormat::negotiate(&req), &pages);
+    }
 
     let mut sql_transaction = db_pool
         .begin()
         .await
         .context("Failed to get Postrges connection from the pool")?;
 
-    let subscriber_id = insert_subscriber(&new_sub, &mut sql_transaction)
-        .await
-        .context("Failed to insert new subscriber")?;
+    // the subscriber is looked up on the canonical address, so that `Alice@Example.com`
+    // and `alice@example.com` are the same subscriber. For an existing subscriber the data
+    // already stored is kept.
+    let (subscriber_id, subscriber) = match upsert_subscriber(
+        &new_sub,
+        confirmations.resend_cutoff(Utc::now()),
+        &mut sql_transaction,
+    )
+    .await
+    .context("Failed to save the subscriber")?
+    {
+        Some(saved) => saved,
+        None => {
+            sql_transaction
+                .commit()
+                .await
+                .context("Failed to commit SQL transaction")?;
+            tracing::info!("a confirmation email was sent recently, not sending another one");
+            return pending_confirmation_response(ResponseFormat::negotiate(&req), &pages);
+        }
+    };
 
-    let subscription_token = SubscriptionToken::new();
-    store_token(subscriber_id, &subscription_token, &mut sql_transaction)
+    let confirmation_link = match confirmations.mode() {
+        ConfirmationMode::Token => {
+            // the stored token can't be read back, a new one replaces it
+            delete_tokens(subscriber_id, &mut sql_transaction)
+                .await
+                .context("Failed to delete the previous confirmation tokens")?;
+            let subscription_token = SubscriptionToken::new();
+            store_token(
+                subscriber_id,
+                &confirmations.token_hash(&subscription_token),
+                &mut sql_transaction,
+            )
+            .await
+            .context("Failed to store confimration token")?;
+            confirmations.token_link(&subscription_token)
+        }
+        // nothing to store, the link carries what's needed to confirm
+        ConfirmationMode::Signed => confirmations.signed_link(subscriber_id, Utc::now()),
+    };
+
+    queue_confirmation_email(&mut sql_transaction, &subscriber.email, &confirmation_link)
         .await
-        .context("Failed to store confimration token")?;
+        .context("Failed to queue the confirmation email")?;
 
     sql_transaction
         .commit()
         .await
         .context("Failed to commit SQL transaction")?;
 
-    send_confirmation_email(&email_client, new_sub, &base_url.0, &subscription_token)
-        .await
-        .context("Failed to send confirmation email")?;
-
-    Ok(HttpResponse::Ok().finish())
+    pending_confirmation_response(ResponseFormat::negotiate(&req), &pages)
 }
 
 #[tracing::instrument(
     name = "store subscription token",
-    skip(subscriber_id, token, transaction)
+    skip(subscriber_id, token_hash, transaction)
 )]
 pub async fn store_token(
     subscriber_id: Uuid,
-    token: &SubscriptionToken,
+    token_hash: &str,
     transaction: &mut sqlx::Transaction<'_, Postgres>,
 ) -> Result<(), StoreTokenError> {
-    sqlx::query!(r#"INSERT into public.subscription_tokens (subscriber_id, subscription_token) VALUES ($1, $2)"#,
+    sqlx::query!(r#"INSERT into public.subscription_tokens (subscriber_id, subscription_token_hash) VALUES ($1, $2)"#,
         subscriber_id,
-        token.as_ref()
+        token_hash
      )
     .execute(transaction)
     .await
@@ -144,29 +295,17 @@ pub async fn store_token(
     Ok(())
 }
 
-async fn subscriber_existance_check(
-    email: &str,
-    db_pool: &PgPool,
-) -> Result<Option<(NewSubscriber, SubscriptionToken)>, CheckSubError> {
-    let saved = sqlx::query!(
-        r#"SELECT name, email, subscription_token FROM public.subscriptions JOIN public.subscription_tokens ON id = subscriber_id WHERE email = $1"#,
-        email
+#[tracing::instrument(name = "delete subscription tokens", skip(subscriber_id, transaction))]
+async fn delete_tokens(
+    subscriber_id: Uuid,
+    transaction: &mut sqlx::Transaction<'_, Postgres>,
+) -> Result<(), StoreTokenError> {
+    sqlx::query!(
+        r#"DELETE FROM public.subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id
     )
-    .fetch_optional(db_pool)
+    .execute(transaction)
     .await
-    .map_err(|e| {
-        CheckSubError(e)
-    })?;
-    match saved {
-        None => Ok(None),
-        Some(subscriber) => {
-            let token =
-                SubscriptionToken::parse(subscriber.subscription_token.to_string()).unwrap(); // FIXME: this can be dangerous but it comes from the database so it must have passed this check during the insert operation
-            let existing_sub = NewSubscriber {
-                name: SubscriberName::parse(subscriber.name).unwrap(), // FIXME: this can be dangerous but it comes from the database so it must have passed this check during the insert operation
-                email: SubscriberEmail::parse(subscriber.email).unwrap(), // FIXME: this can be dangerous but it comes from the database so it must have passed this check during the insert operation
-            };
-            Ok(Some((existing_sub, token)))
-        }
-    }
+    .map_err(StoreTokenError)?;
+    Ok(())
 }
@@ -1,59 +1,237 @@
-use crate::{domain::SubscriptionToken, errors::ConfirmError};
-use actix_web::{web, HttpResponse, Result};
+use crate::{
+    confirmation::{Confirmations, SignedLinkError},
+    domain::SubscriptionToken,
+    errors::ConfirmError,
+    negotiation::{FormOrJson, ResponseFormat},
+    pages::{Page, Pages},
+};
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, ResponseError, Result};
 use anyhow::Context;
+use chrono::Utc;
 use sqlx::PgPool;
 use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
 pub struct Parameters {
-    subscription_token: String,
+    subscription_token: Option<String>,
+    // signed link, see `Confirmations::signed_link`
+    confirmation: Option<String>,
 }
 
-#[tracing::instrument(name = "confirm pending subscriber", skip(parameters, db_pool))]
-pub async fn confirm(
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ConfirmationStatus {
+    PendingConfirmation,
+    Confirmed,
+    AlreadyConfirmed,
+}
+
+#[derive(serde::Serialize)]
+struct ConfirmationResponse {
+    status: ConfirmationStatus,
+}
+
+// the subscriber a confirmation link belongs to, both kinds of links are accepted
+// so that switching mode doesn't break the links already sent
+async fn subscriber_from_link(
+    parameters: &Parameters,
+    db_pool: &PgPool,
+    confirmations: &Confirmations,
+) -> Result<Uuid, ConfirmError> {
+    let id = match (&parameters.confirmation, &parameters.subscription_token) {
+        (Some(confirmation), _) => Some(confirmations.verify_signed(confirmation, Utc::now())?),
+        (None, token) => {
+            let token = SubscriptionToken::parse(token.clone().unwrap_or_default())
+                .map_err(ConfirmError::ValidationError)?;
+            get_subscriber_id_from_token(db_pool, &confirmations.token_hash(&token))
+                .await
+                .context("failed to retrieve confirming subscriber")?
+        }
+    };
+    id.ok_or_else(|| {
+        ConfirmError::UnauthorizedError(
+            "The token received does not correspond to any user id".into(),
+        )
+    })
+}
+
+fn unknown_subscriber() -> ConfirmError {
+    ConfirmError::UnauthorizedError(
+        "The link received does not correspond to any subscriber".into(),
+    )
+}
+
+// browsers are shown a page explaining what's wrong with the link,
+// API clients get the problem details
+fn invalid_link_page(error: ConfirmError, pages: &Pages) -> Result<HttpResponse, ConfirmError> {
+    if let ConfirmError::UnexpectedError(_) = error {
+        return Err(error);
+    }
+    tracing::info!(reason = %error, "invalid confirmation link");
+    let expired = matches!(
+        error,
+        ConfirmError::SignedLinkError(SignedLinkError::Expired)
+    );
+    Ok(pages
+        .render(Page::InvalidLink { expired }, error.status_code())
+        .context("failed to render the invalid link page")?)
+}
+
+// links are followed by mail scanners too, so visiting one only shows the button that confirms
+#[tracing::instrument(
+    name = "show the confirmation page",
+    skip(req, parameters, db_pool, confirmations, pages)
+)]
+pub async fn confirmation_page(
+    req: HttpRequest,
     parameters: web::Query<Parameters>,
     db_pool: web::Data<PgPool>,
+    confirmations: web::Data<Confirmations>,
+    pages: web::Data<Pages>,
 ) -> Result<HttpResponse, ConfirmError> {
-    let token = SubscriptionToken::parse(parameters.subscription_token.to_owned())
-        .map_err(|e| ConfirmError::ValidationError(e))?;
-    let id = get_subscriber_id_from_token(&db_pool, token)
-        .await
-        .context("failed to retrieve confirming subscriber")?;
+    let format = ResponseFormat::negotiate(&req);
+    let status = match pending_status(&parameters, &db_pool, &confirmations).await {
+        Ok(status) => status,
+        Err(error) if format == ResponseFormat::Html => return invalid_link_page(error, &pages),
+        Err(error) => return Err(error),
+    };
 
-    match id {
-        None => Err(ConfirmError::UnauthorizedError(
-            "The token received does not correspond to any user id".into(),
-        )),
-        Some(id) => {
-            confirm_subscriber(id, &db_pool)
-                .await
-                .context("failed to confirm subscriber")?;
-            Ok(HttpResponse::Ok().finish())
+    let page = match (format, status) {
+        (ResponseFormat::Json, _) => {
+            return Ok(HttpResponse::Ok().json(ConfirmationResponse { status }))
         }
+        (_, ConfirmationStatus::PendingConfirmation) => match &parameters.confirmation {
+            Some(confirmation) => Page::Confirm {
+                parameter: "confirmation",
+                value: confirmation,
+                auto_submit: confirmations.auto_submit(),
+            },
+            // the values were checked, they are either an alphanumeric token or url-safe base64
+            None => Page::Confirm {
+                parameter: "subscription_token",
+                value: parameters.subscription_token.as_deref().unwrap_or_default(),
+                auto_submit: confirmations.auto_submit(),
+            },
+        },
+        (_, _) => Page::Confirmed {
+            already_confirmed: true,
+        },
+    };
+    Ok(pages
+        .render(page, StatusCode::OK)
+        .context("failed to render the confirmation page")?)
+}
+
+async fn pending_status(
+    parameters: &Parameters,
+    db_pool: &PgPool,
+    confirmations: &Confirmations,
+) -> Result<ConfirmationStatus, ConfirmError> {
+    let id = subscriber_from_link(parameters, db_pool, confirmations).await?;
+    match get_subscriber_status(id, db_pool)
+        .await
+        .context("failed to retrieve the subscriber status")?
+    {
+        None => Err(unknown_subscriber()),
+        Some(status) if status == "confirmed" => Ok(ConfirmationStatus::AlreadyConfirmed),
+        Some(_) => Ok(ConfirmationStatus::PendingConfirmation),
     }
 }
 
-#[tracing::instrument(name = "get subscriber id from token", skip(token, db_pool))]
+#[tracing::instrument(
+    name = "confirm pending subscriber",
+    skip(req, parameters, db_pool, confirmations, pages)
+)]
+pub async fn confirm(
+    req: HttpRequest,
+    parameters: FormOrJson<Parameters>,
+    db_pool: web::Data<PgPool>,
+    confirmations: web::Data<Confirmations>,
+    pages: web::Data<Pages>,
+) -> Result<HttpResponse, ConfirmError> {
+    let format = ResponseFormat::negotiate(&req);
+    let status = match confirm_link(&parameters.0, &db_pool, &confirmations).await {
+        Ok(status) => status,
+        Err(error) if format == ResponseFormat::Html => return invalid_link_page(error, &pages),
+        Err(error) => return Err(error),
+    };
+
+    Ok(match format {
+        ResponseFormat::Json => HttpResponse::Ok().json(ConfirmationResponse { status }),
+        ResponseFormat::Html => pages
+            .render(
+                Page::Confirmed {
+                    already_confirmed: status == ConfirmationStatus::AlreadyConfirmed,
+                },
+                StatusCode::OK,
+            )
+            .context("failed to render the confirmed page")?,
+        ResponseFormat::Empty => HttpResponse::Ok().finish(),
+    })
+}
+
+async fn confirm_link(
+    parameters: &Parameters,
+    db_pool: &PgPool,
+    confirmations: &Confirmations,
+) -> Result<ConfirmationStatus, ConfirmError> {
+    let id = subscriber_from_link(parameters, db_pool, confirmations).await?;
+    if confirm_subscriber(id, db_pool)
+        .await
+        .context("failed to confirm subscriber")?
+    {
+        return Ok(ConfirmationStatus::Confirmed);
+    }
+    // confirming again is not an error
+    match get_subscriber_status(id, db_pool)
+        .await
+        .context("failed to retrieve the subscriber status")?
+    {
+        None => Err(unknown_subscriber()),
+        Some(_) => Ok(ConfirmationStatus::AlreadyConfirmed),
+    }
+}
+
+#[tracing::instrument(name = "get subscriber id from token", skip(token_hash, db_pool))]
 pub async fn get_subscriber_id_from_token(
     db_pool: &PgPool,
-    token: SubscriptionToken,
+    token_hash: &str,
 ) -> Result<Option<Uuid>, sqlx::Error> {
+    // only the hashes are stored
     let result = sqlx::query!(
-        r#"SELECT subscriber_id from subscription_tokens WHERE subscription_token = $1"#,
-        token.as_ref()
+        r#"SELECT subscriber_id from subscription_tokens WHERE subscription_token_hash = $1"#,
+        token_hash
     )
     .fetch_optional(db_pool)
     .await?;
     Ok(result.map(|r| r.subscriber_id))
 }
 
+#[tracing::instrument(name = "get subscriber status", skip(subscriber_id, db_pool))]
+async fn get_subscriber_status(
+    subscriber_id: Uuid,
+    db_pool: &PgPool,
+) -> Result<Option<String>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT status FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_optional(db_pool)
+    .await?;
+    Ok(result.map(|r| r.status))
+}
+
+// returns false if the subscriber is missing or was already confirmed
 #[tracing::instrument(name = "update subscriber status", skip(subscriber_id, db_pool))]
-pub async fn confirm_subscriber(subscriber_id: Uuid, db_pool: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
+pub async fn confirm_subscriber(
+    subscriber_id: Uuid,
+    db_pool: &PgPool,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1 AND status <> 'confirmed'"#,
         subscriber_id
     )
     .execute(db_pool)
     .await?;
-    Ok(())
+    Ok(result.rows_affected() > 0)
 }
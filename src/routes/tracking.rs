@@ -0,0 +1,121 @@
+use crate::{errors::TrackingError, tracking::Tracker, tracking::TRACKING_PIXEL};
+use actix_web::{http::header, web, HttpResponse, Result};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Serialize)]
+pub struct TrackingReport {
+    newsletter_issue_id: Uuid,
+    unique_opens: i64,
+    unique_clicks: i64,
+}
+
+#[tracing::instrument(name = "track newsletter issue open", skip(id, tracker, db_pool))]
+pub async fn track_open(
+    id: web::Path<String>,
+    tracker: web::Data<Tracker>,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, TrackingError> {
+    if !tracker.is_enabled() {
+        return Err(TrackingError::Disabled);
+    }
+    // the pixel is always served, a broken image would not help anyone
+    if let Some(open) = tracker.parse_open(&id) {
+        if let Err(e) = store_event(
+            &db_pool,
+            open.newsletter_issue_id,
+            open.subscriber_id,
+            "open",
+            None,
+        )
+        .await
+        {
+            tracing::error!(error = ?e, "failed to store open tracking event");
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/gif")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(TRACKING_PIXEL))
+}
+
+#[tracing::instrument(name = "track newsletter issue click", skip(id, tracker, db_pool))]
+pub async fn track_click(
+    id: web::Path<String>,
+    tracker: web::Data<Tracker>,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, TrackingError> {
+    // the signed links are only followed while tracking is enabled
+    if !tracker.is_enabled() {
+        return Err(TrackingError::Disabled);
+    }
+    // only signed links are followed, otherwise we would be an open redirect
+    let click = tracker
+        .parse_click(&id)
+        .ok_or_else(|| TrackingError::InvalidLinkError("The tracking link is not valid".into()))?;
+
+    store_event(
+        &db_pool,
+        click.newsletter_issue_id,
+        click.subscriber_id,
+        "click",
+        Some(&click.url),
+    )
+    .await
+    .context("failed to store click tracking event")?;
+
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, click.url))
+        .finish())
+}
+
+#[tracing::instrument(name = "newsletter issue tracking report", skip(db_pool))]
+pub async fn tracking_report(
+    newsletter_issue_id: web::Path<Uuid>,
+    db_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, TrackingError> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let report = sqlx::query!(
+        r#"SELECT
+            COUNT(DISTINCT subscriber_id) FILTER (WHERE kind = 'open') AS "unique_opens!",
+            COUNT(DISTINCT subscriber_id) FILTER (WHERE kind = 'click') AS "unique_clicks!"
+        FROM tracking_events WHERE newsletter_issue_id = $1"#,
+        newsletter_issue_id
+    )
+    .fetch_one(db_pool.get_ref())
+    .await
+    .context("failed to compute the tracking report")?;
+
+    Ok(HttpResponse::Ok().json(TrackingReport {
+        newsletter_issue_id,
+        unique_opens: report.unique_opens,
+        unique_clicks: report.unique_clicks,
+    }))
+}
+
+// the event is stored only if the subscriber consented to be tracked
+#[tracing::instrument(name = "store tracking event", skip(db_pool, url))]
+pub async fn store_event(
+    db_pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    subscriber_id: Uuid,
+    kind: &str,
+    url: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO tracking_events (id, newsletter_issue_id, subscriber_id, kind, url, occurred_at)
+        SELECT $1, $2, id, $3, $4, $5 FROM subscriptions WHERE id = $6 AND tracking_consent"#,
+        Uuid::new_v4(),
+        newsletter_issue_id,
+        kind,
+        url,
+        Utc::now(),
+        subscriber_id
+    )
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
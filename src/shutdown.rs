@@ -0,0 +1,147 @@
+use actix_web::dev::ServerHandle;
+use std::future::Future;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// received by the background workers, they are expected to stop after the item they are processing
+#[derive(Clone)]
+pub struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    pub fn new(receiver: watch::Receiver<bool>) -> Self {
+        Self(receiver)
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    // completes once the shutdown is triggered, to be used in `tokio::select!` while idle
+    pub async fn triggered(&mut self) {
+        while !*self.0.borrow_and_update() {
+            if self.0.changed().await.is_err() {
+                // the application is gone, nobody can trigger the shutdown anymore
+                return;
+            }
+        }
+    }
+}
+
+// the background workers of a process, stopped together on shutdown
+pub struct BackgroundWorkers {
+    shutdown_sender: watch::Sender<bool>,
+    workers: Vec<JoinHandle<()>>,
+    shutdown_timeout: std::time::Duration,
+}
+
+impl BackgroundWorkers {
+    pub fn new(shutdown_timeout: std::time::Duration) -> Self {
+        let (shutdown_sender, _) = watch::channel(false);
+        Self {
+            shutdown_sender,
+            workers: Vec::new(),
+            shutdown_timeout,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    pub fn spawn<F, Fut>(&mut self, worker: F)
+    where
+        F: FnOnce(ShutdownSignal) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let signal = ShutdownSignal::new(self.shutdown_sender.subscribe());
+        self.workers.push(tokio::spawn(worker(signal)));
+    }
+
+    // signals the workers and waits for them to complete the item they are processing
+    pub async fn stop(self) {
+        self.shutdown_sender.send_replace(true);
+        let workers = self.workers;
+        let drained = tokio::time::timeout(self.shutdown_timeout, async move {
+            for worker in workers {
+                if let Err(e) = worker.await {
+                    tracing::error!(error = ?e, "a background worker failed");
+                }
+            }
+        })
+        .await;
+        if drained.is_err() {
+            tracing::warn!("background workers did not stop within the shutdown timeout");
+        }
+    }
+}
+
+// completes on SIGTERM or SIGINT, for processes that don't run the actix server
+pub async fn termination_signal() {
+    let interrupt = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = interrupt => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = interrupt.await;
+    }
+}
+
+// used to stop the application programmatically, like a SIGTERM would
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    server: ServerHandle,
+}
+
+impl ShutdownHandle {
+    pub fn new(server: ServerHandle) -> Self {
+        Self { server }
+    }
+
+    // stops accepting connections and waits for the in-flight requests to complete,
+    // the workers and the database pool are then stopped by `Application::run_until_stopped`
+    pub async fn shutdown(&self) {
+        self.server.stop(true).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShutdownSignal;
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn the_signal_is_received_by_every_worker() {
+        let (sender, receiver) = watch::channel(false);
+        let mut first = ShutdownSignal::new(receiver);
+        let mut second = first.clone();
+        assert!(!first.is_triggered());
+
+        sender.send_replace(true);
+
+        first.triggered().await;
+        second.triggered().await;
+        assert!(first.is_triggered() && second.is_triggered());
+    }
+
+    #[tokio::test]
+    async fn the_signal_completes_if_the_sender_is_dropped() {
+        let (sender, receiver) = watch::channel(false);
+        let mut signal = ShutdownSignal::new(receiver);
+
+        drop(sender);
+
+        signal.triggered().await;
+    }
+}
@@ -0,0 +1,96 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(key: &Secret<String>) -> HmacSha256 {
+    // HMAC accepts keys of any length, so this can't fail
+    HmacSha256::new_from_slice(key.expose_secret().as_bytes()).unwrap()
+}
+
+// returns the url-safe `<payload>.<signature>` representation of the payload, signed with HMAC-SHA256
+pub fn sign(key: &Secret<String>, payload: &str) -> String {
+    let mut mac = mac(key);
+    mac.update(payload.as_bytes());
+    let signature = mac.finalize().into_bytes();
+    format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(payload),
+        URL_SAFE_NO_PAD.encode(signature)
+    )
+}
+
+// returns the url-safe HMAC-SHA256 of the payload, to store secrets that only need to be compared
+pub fn keyed_hash(key: &Secret<String>, payload: &str) -> String {
+    let mut mac = mac(key);
+    mac.update(payload.as_bytes());
+    URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+// returns the original payload if the signature is valid for the given key
+pub fn verify(key: &Secret<String>, signed: &str) -> Option<String> {
+    let (payload, signature) = signed.split_once('.')?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+    let mut mac = mac(key);
+    mac.update(&payload);
+    // constant time comparison
+    mac.verify_slice(&signature).ok()?;
+
+    String::from_utf8(payload).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{keyed_hash, sign, verify};
+    use claim::{assert_none, assert_some_eq};
+    use secrecy::Secret;
+
+    fn key() -> Secret<String> {
+        Secret::new("a-very-secret-key".to_string())
+    }
+
+    #[test]
+    fn a_signed_payload_is_verified() {
+        let signed = sign(&key(), "some payload");
+        assert_some_eq!(verify(&key(), &signed), "some payload".to_string());
+    }
+
+    #[test]
+    fn a_payload_signed_with_another_key_is_rejected() {
+        let signed = sign(&Secret::new("another-key".to_string()), "some payload");
+        assert_none!(verify(&key(), &signed));
+    }
+
+    #[test]
+    fn a_tampered_payload_is_rejected() {
+        let signed = sign(&key(), "some payload");
+        let (_, signature) = signed.split_once('.').unwrap();
+        let tampered = format!(
+            "{}.{}",
+            sign(&key(), "other payload").split_once('.').unwrap().0,
+            signature
+        );
+        assert_none!(verify(&key(), &tampered));
+    }
+
+    #[test]
+    fn a_malformed_string_is_rejected() {
+        assert_none!(verify(&key(), "not-signed"));
+        assert_none!(verify(&key(), "not.base64!"));
+    }
+
+    #[test]
+    fn the_keyed_hash_depends_on_the_key() {
+        let hash = keyed_hash(&key(), "some token");
+        assert_eq!(hash, keyed_hash(&key(), "some token"));
+        assert_ne!(
+            hash,
+            keyed_hash(&Secret::new("another-key".to_string()), "some token")
+        );
+        assert!(!hash.contains("some token"));
+    }
+}
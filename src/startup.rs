@@ -1,16 +1,38 @@
-use crate::configuration::{DatabaseSettings, Settings};
+use crate::authentication::AdminAuth;
+use crate::bot_protection::BotProtection;
+use crate::configuration::{DatabaseSettings, HealthSettings, Settings};
+use crate::confirmation::Confirmations;
 use crate::email_client::EmailClient;
-use crate::routes::{confirm, health_check, subscribe};
+use crate::email_policy::EmailPolicy;
+use crate::errors::problem_details;
+use crate::metrics::RequestMetrics;
+use crate::outbox::OutboxRelay;
+use crate::pages::Pages;
+use crate::routes::{
+    confirm, confirmation_page, dead_letter, health_check, list_dead_letters, liveness, metrics,
+    readiness, replay_all_dead_letters, replay_dead_letter, subscribe, subscription_form,
+    track_click, track_open, tracking_report,
+};
+use crate::shutdown::{termination_signal, BackgroundWorkers, ShutdownHandle, ShutdownSignal};
+use crate::tracking::Tracker;
 use actix_web::dev::Server;
 use actix_web::{web, App, HttpServer, Result};
+use sqlx::migrate::Migrator;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
+use std::future::Future;
 use std::net::TcpListener;
 use tracing_actix_web::TracingLogger;
 
+// migrations embedded in the binary
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
 pub struct Application {
     port: u16,
     server: Server,
+    db_pool: PgPool,
+    email_client: EmailClient,
+    workers: BackgroundWorkers,
 }
 
 #[derive(Debug)]
@@ -21,21 +43,46 @@ impl Application {
         self.port
     }
 
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(self.server.handle())
+    }
+
+    // shares the circuit breaker of the server's client
+    pub fn email_client(&self) -> EmailClient {
+        self.email_client.clone()
+    }
+
+    // runs a background worker alongside the server, it's stopped on shutdown
+    pub fn spawn_worker<F, Fut>(&mut self, worker: F)
+    where
+        F: FnOnce(ShutdownSignal) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.workers.spawn(worker);
+    }
+
     pub async fn build(configuration: Settings) -> Result<Application, std::io::Error> {
+        configuration
+            .validate()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
         // get database connection
         let db_connection_pool = get_connection_pool(&configuration.database).await;
-        // set the address
-        let sender_email_address = configuration
-            .email_client
-            .sender()
-            .expect("invalid sender email for email client");
-        let client_timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email_address,
-            configuration.email_client.authorization_token,
-            client_timeout,
+        let email_client = EmailClient::from_settings(&configuration.email_client);
+        let tracker = Tracker::new(
+            configuration.tracking,
+            configuration.application.base_url.clone(),
+        )
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let confirmations = Confirmations::new(
+            configuration.confirmation,
+            configuration.application.base_url.clone(),
         );
+        let pages = Pages::from_settings(
+            &configuration.application.branding,
+            configuration.application.redirects.clone(),
+        )?;
+        let bot_protection = BotProtection::from_settings(&configuration.bot_protection);
+        let email_policy = EmailPolicy::from_settings(&configuration.email_policy)?;
         let address = format!(
             "{}:{}",
             configuration.application.host, configuration.application.port
@@ -44,18 +91,91 @@ impl Application {
         // start listener
         let listener = TcpListener::bind(address)?;
         let port = listener.local_addr().unwrap().port();
+        let shutdown_timeout = configuration.application.shutdown_timeout();
+        let mut workers = BackgroundWorkers::new(shutdown_timeout);
+        if configuration.outbox.relay_in_server {
+            let relay = OutboxRelay::new(
+                db_connection_pool.clone(),
+                email_client.clone(),
+                configuration.outbox,
+            );
+            workers.spawn(|signal| relay.run(signal));
+        }
         let server = run(
             listener,
-            db_connection_pool,
-            email_client,
+            db_connection_pool.clone(),
+            email_client.clone(),
             configuration.application.base_url,
+            tracker,
+            bot_protection,
+            email_policy,
+            confirmations,
+            pages,
+            configuration.health,
+            shutdown_timeout,
         )?;
+        Ok(Self {
+            port,
+            server,
+            db_pool: db_connection_pool,
+            email_client,
+            workers,
+        })
+    }
 
-        Ok(Self { port, server })
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        // the server stops on SIGTERM/SIGINT or through the shutdown handle, after it
+        // stopped accepting connections and the in-flight requests completed
+        let outcome = self.server.await;
+
+        // let the workers complete the item they are processing
+        self.workers.stop().await;
+
+        self.db_pool.close().await;
+        outcome
+    }
+}
+
+// runs the background workers without the HTTP server
+pub struct Worker {
+    db_pool: PgPool,
+    workers: BackgroundWorkers,
+}
+
+impl Worker {
+    pub async fn build(configuration: Settings) -> Result<Worker, std::io::Error> {
+        configuration
+            .validate()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let db_pool = get_connection_pool(&configuration.database).await;
+        let mut workers = BackgroundWorkers::new(configuration.application.shutdown_timeout());
+        let relay = OutboxRelay::new(
+            db_pool.clone(),
+            EmailClient::from_settings(&configuration.email_client),
+            configuration.outbox,
+        );
+        workers.spawn(|signal| relay.run(signal));
+
+        Ok(Self { db_pool, workers })
+    }
+
+    pub fn spawn_worker<F, Fut>(&mut self, worker: F)
+    where
+        F: FnOnce(ShutdownSignal) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.workers.spawn(worker);
     }
 
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        if self.workers.is_empty() {
+            tracing::warn!("there is no background worker to run");
+        }
+        termination_signal().await;
+
+        self.workers.stop().await;
+        self.db_pool.close().await;
+        Ok(())
     }
 }
 
@@ -67,29 +187,77 @@ pub async fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
         .expect("couldn't connect to the database")
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     listener: TcpListener,
     db_connection_pool: PgPool,
     email_client: EmailClient,
     base_url: String,
+    tracker: Tracker,
+    bot_protection: BotProtection,
+    email_policy: EmailPolicy,
+    confirmations: Confirmations,
+    pages: Pages,
+    health_settings: HealthSettings,
+    shutdown_timeout: std::time::Duration,
 ) -> Result<Server, std::io::Error> {
     // wrap the connection into an Arc (smart pointer) so we can clone it inside the closure
     //web::Data is another extractor that returns an Arc
     let db_pool = web::Data::new(db_connection_pool);
     let email_client = web::Data::new(email_client);
     let base_url = web::Data::new(ApplicationBaseUrl(base_url));
+    let tracker = web::Data::new(tracker);
+    let bot_protection = web::Data::new(bot_protection);
+    let email_policy = web::Data::new(email_policy);
+    let confirmations = web::Data::new(confirmations);
+    let pages = web::Data::new(pages);
+    let health_settings = web::Data::new(health_settings);
 
     let server = HttpServer::new(move || {
         App::new()
+            .wrap(problem_details())
             .wrap(TracingLogger::default())
+            .wrap(RequestMetrics)
             .route("/health_check", web::get().to(health_check))
+            .route("/health/live", web::get().to(liveness))
+            .route("/health/ready", web::get().to(readiness))
+            .route("/metrics", web::get().to(metrics))
             .route("/subscriptions", web::post().to(subscribe))
-            .route("/subscriptions/confirm", web::get().to(confirm))
+            .route("/subscriptions/form", web::get().to(subscription_form))
+            .route("/subscriptions/confirm", web::get().to(confirmation_page))
+            .route("/subscriptions/confirm", web::post().to(confirm))
+            .route("/t/o/{id}", web::get().to(track_open))
+            .route("/t/c/{id}", web::get().to(track_click))
+            .service(
+                web::scope("/admin")
+                    .wrap(AdminAuth)
+                    .route(
+                        "/issues/{newsletter_issue_id}/tracking",
+                        web::get().to(tracking_report),
+                    )
+                    .route("/dead_letters", web::get().to(list_dead_letters))
+                    .route(
+                        "/dead_letters/replay",
+                        web::post().to(replay_all_dead_letters),
+                    )
+                    .route("/dead_letters/{id}", web::get().to(dead_letter))
+                    .route(
+                        "/dead_letters/{id}/replay",
+                        web::post().to(replay_dead_letter),
+                    ),
+            )
             .app_data(db_pool.clone())
             .app_data(email_client.clone())
             .app_data(base_url.clone())
+            .app_data(tracker.clone())
+            .app_data(bot_protection.clone())
+            .app_data(email_policy.clone())
+            .app_data(confirmations.clone())
+            .app_data(pages.clone())
+            .app_data(health_settings.clone())
     })
     .listen(listener)?
+    .shutdown_timeout(shutdown_timeout.as_secs())
     .run();
 
     Ok(server)
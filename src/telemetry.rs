@@ -1,8 +1,45 @@
+use crate::configuration::TelemetrySettings;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{self, TracerProvider};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::{TraceError, TracerProvider as _};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
 use tracing::{subscriber::set_global_default, Subscriber};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
 use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry};
 
+// name of the instrumentation library reported along with the exported spans
+const INSTRUMENTATION_NAME: &str = "actix_server";
+
+/// Build the OpenTelemetry tracer provider.
+///
+/// Spans are always recorded, so that the trace context can be propagated
+/// to the services we call, but they are exported only if an OTLP
+/// collector endpoint is configured.
+pub fn get_tracer_provider(
+    name: String,
+    settings: &TelemetrySettings,
+) -> Result<TracerProvider, TraceError> {
+    let config =
+        trace::config().with_resource(Resource::new(vec![KeyValue::new("service.name", name)]));
+    let mut builder = TracerProvider::builder().with_config(config);
+
+    if let Some(endpoint) = &settings.otlp_endpoint {
+        let exporter = opentelemetry_otlp::SpanExporterBuilder::from(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint)
+                .with_timeout(settings.otlp_timeout()),
+        )
+        .build_span_exporter()?;
+        builder = builder.with_batch_exporter(exporter, opentelemetry::runtime::Tokio);
+    }
+
+    Ok(builder.build())
+}
+
 /// Compose multiple layers into a `tracing`'s subscriber.
 ///
 /// # Implementation Notes
@@ -17,6 +54,7 @@ pub fn get_tracing_subscriber<Sink>(
     name: String,
     env_filter: String,
     sink: Sink,
+    tracer_provider: &TracerProvider,
 ) -> impl Subscriber + Send + Sync
 where
     Sink: for<'a> MakeWriter<'a> + Send + Sync + 'static,
@@ -28,16 +66,84 @@ where
     //create an instance of the formatting layer
     let formatting_layer = BunyanFormattingLayer::new(name, sink);
 
+    // bridge the `tracing` spans to OpenTelemetry
+    let opentelemetry_layer =
+        tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer(INSTRUMENTATION_NAME));
+
     // instantiate the subscriber
     Registry::default()
         .with(env_filter)
         .with(JsonStorageLayer)
         .with(formatting_layer)
+        .with(opentelemetry_layer)
 }
 
-pub fn init_tracing_subscriber(subscriber: impl Subscriber + Send + Sync) {
+pub fn init_tracing_subscriber(
+    subscriber: impl Subscriber + Send + Sync,
+    tracer_provider: TracerProvider,
+) {
     // redirect all "log" events to the subscriber (the actix-web ones for example)
     LogTracer::init().expect("failed to set logger");
 
     set_global_default(subscriber).expect("fail to set subscriber");
+
+    // read and write the trace context using the W3C `traceparent` header
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    // keep the provider alive, the tracers only hold a weak reference to it
+    global::set_tracer_provider(tracer_provider);
+}
+
+// export the spans that are still buffered, to be called before exiting
+pub fn shutdown_tracing() {
+    global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_tracer_provider, get_tracing_subscriber};
+    use crate::configuration::TelemetrySettings;
+    use opentelemetry::trace::TracerProvider as _;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_are_exported_to_the_otlp_collector() {
+        // an in-process stand-in for the collector
+        let collector = MockServer::start().await;
+        Mock::given(path("/v1/traces"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1..)
+            .mount(&collector)
+            .await;
+
+        let settings = TelemetrySettings {
+            otlp_endpoint: Some(format!("{}/v1/traces", collector.uri())),
+            otlp_timeout_milliseconds: 1000,
+        };
+        let tracer_provider = get_tracer_provider("test".into(), &settings).unwrap();
+        let subscriber = get_tracing_subscriber(
+            "test".into(),
+            "info".into(),
+            std::io::sink,
+            &tracer_provider,
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("exported span").in_scope(|| {});
+        });
+        for result in tracer_provider.force_flush() {
+            result.unwrap();
+        }
+
+        let request = &collector.received_requests().await.unwrap()[0];
+        assert_eq!(request.headers["content-type"], "application/x-protobuf");
+    }
+
+    #[test]
+    fn spans_are_not_exported_without_endpoint() {
+        let tracer_provider =
+            get_tracer_provider("test".into(), &TelemetrySettings::default()).unwrap();
+        assert!(tracer_provider.force_flush().is_empty());
+    }
 }
@@ -0,0 +1,221 @@
+use crate::configuration::TrackingSettings;
+use crate::signing::{sign, verify};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use secrecy::{ExposeSecret, Secret};
+use uuid::Uuid;
+
+// the absolute links of an issue, compiled once
+static HREF_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?i)href\s*=\s*(["'])(https?://[^"']+)(["'])"#).unwrap());
+
+// a transparent 1x1 GIF, served by the open tracking endpoint
+pub const TRACKING_PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+#[derive(Debug, PartialEq)]
+pub struct TrackedOpen {
+    pub newsletter_issue_id: Uuid,
+    pub subscriber_id: Uuid,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TrackedClick {
+    pub newsletter_issue_id: Uuid,
+    pub subscriber_id: Uuid,
+    pub url: String,
+}
+
+pub struct Tracker {
+    enabled: bool,
+    base_url: String,
+    signing_key: Secret<String>,
+}
+
+impl Tracker {
+    // an empty key would let anyone sign their own redirects
+    pub fn new(settings: TrackingSettings, base_url: String) -> Result<Self, String> {
+        if settings.enabled && settings.signing_key.expose_secret().is_empty() {
+            return Err("no tracking signing key was configured".into());
+        }
+        Ok(Self {
+            enabled: settings.enabled,
+            base_url,
+            signing_key: settings.signing_key,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn open_pixel_url(&self, newsletter_issue_id: Uuid, subscriber_id: Uuid) -> String {
+        let id = sign(
+            &self.signing_key,
+            &format!("{}:{}", newsletter_issue_id, subscriber_id),
+        );
+        format!("{}/t/o/{}", self.base_url, id)
+    }
+
+    pub fn click_url(&self, newsletter_issue_id: Uuid, subscriber_id: Uuid, url: &str) -> String {
+        let id = sign(
+            &self.signing_key,
+            &format!("{}:{}:{}", newsletter_issue_id, subscriber_id, url),
+        );
+        format!("{}/t/c/{}", self.base_url, id)
+    }
+
+    // rewrites the links of an issue into tracked redirects and appends the open tracking pixel.
+    // The html is returned untouched if tracking is disabled or the subscriber did not consent to it.
+    pub fn track_html(
+        &self,
+        html: &str,
+        newsletter_issue_id: Uuid,
+        subscriber_id: Uuid,
+        tracking_consent: bool,
+    ) -> String {
+        if !self.enabled || !tracking_consent {
+            return html.to_string();
+        }
+
+        let rewritten = HREF_REGEX.replace_all(html, |caps: &Captures| {
+            format!(
+                "href={}{}{}",
+                &caps[1],
+                self.click_url(newsletter_issue_id, subscriber_id, &caps[2]),
+                &caps[3]
+            )
+        });
+
+        let pixel = format!(
+            "<img src=\"{}\" width=\"1\" height=\"1\" alt=\"\" />",
+            self.open_pixel_url(newsletter_issue_id, subscriber_id)
+        );
+        // put the pixel at the end of the body if there is one
+        match rewritten.rfind("</body>") {
+            Some(index) => format!("{}{}{}", &rewritten[..index], pixel, &rewritten[index..]),
+            None => format!("{}{}", rewritten, pixel),
+        }
+    }
+
+    // returns the open carried by a signed pixel id, if the signature is valid
+    pub fn parse_open(&self, id: &str) -> Option<TrackedOpen> {
+        let payload = verify(&self.signing_key, id)?;
+        let (newsletter_issue_id, subscriber_id) = payload.split_once(':')?;
+        Some(TrackedOpen {
+            newsletter_issue_id: Uuid::parse_str(newsletter_issue_id).ok()?,
+            subscriber_id: Uuid::parse_str(subscriber_id).ok()?,
+        })
+    }
+
+    // returns the click carried by a signed redirect id, if the signature is valid
+    pub fn parse_click(&self, id: &str) -> Option<TrackedClick> {
+        let payload = verify(&self.signing_key, id)?;
+        let mut parts = payload.splitn(3, ':');
+        Some(TrackedClick {
+            newsletter_issue_id: Uuid::parse_str(parts.next()?).ok()?,
+            subscriber_id: Uuid::parse_str(parts.next()?).ok()?,
+            url: parts.next()?.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TrackedClick, TrackedOpen, Tracker};
+    use crate::configuration::TrackingSettings;
+    use claim::{assert_none, assert_some_eq};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn tracker(enabled: bool) -> Tracker {
+        Tracker::new(
+            TrackingSettings {
+                enabled,
+                signing_key: Secret::new("tracking-key".to_string()),
+            },
+            "http://127.0.0.1".to_string(),
+        )
+        .unwrap()
+    }
+
+    fn id_from(url: &str, prefix: &str) -> String {
+        url.strip_prefix(prefix).unwrap().to_string()
+    }
+
+    #[test]
+    fn links_are_rewritten_into_signed_redirects() {
+        let tracker = tracker(true);
+        let (issue, subscriber) = (Uuid::new_v4(), Uuid::new_v4());
+        let html = r#"<p><a href="https://example.com/post?id=1">read</a></p>"#;
+
+        let tracked = tracker.track_html(html, issue, subscriber, true);
+
+        assert!(!tracked.contains("href=\"https://example.com"));
+        let click_url = tracker.click_url(issue, subscriber, "https://example.com/post?id=1");
+        assert!(tracked.contains(&click_url));
+        assert_some_eq!(
+            tracker.parse_click(&id_from(&click_url, "http://127.0.0.1/t/c/")),
+            TrackedClick {
+                newsletter_issue_id: issue,
+                subscriber_id: subscriber,
+                url: "https://example.com/post?id=1".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn the_pixel_is_added_before_the_end_of_the_body() {
+        let tracker = tracker(true);
+        let (issue, subscriber) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let tracked = tracker.track_html("<body><p>hi</p></body>", issue, subscriber, true);
+
+        let pixel_url = tracker.open_pixel_url(issue, subscriber);
+        assert!(tracked.ends_with("/></body>"));
+        assert!(tracked.contains(&pixel_url));
+        assert_some_eq!(
+            tracker.parse_open(&id_from(&pixel_url, "http://127.0.0.1/t/o/")),
+            TrackedOpen {
+                newsletter_issue_id: issue,
+                subscriber_id: subscriber
+            }
+        );
+    }
+
+    #[test]
+    fn html_is_untouched_without_consent_or_when_disabled() {
+        let html = r#"<a href="https://example.com">read</a>"#;
+        let (issue, subscriber) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(
+            tracker(true).track_html(html, issue, subscriber, false),
+            html
+        );
+        assert_eq!(
+            tracker(false).track_html(html, issue, subscriber, true),
+            html
+        );
+    }
+
+    #[test]
+    fn forged_ids_are_rejected() {
+        let tracker = tracker(true);
+        assert_none!(tracker.parse_click("aHR0cHM6Ly9ldmlsLmNvbQ.c2lnbmF0dXJl"));
+        assert_none!(tracker.parse_open("garbage"));
+    }
+
+    #[test]
+    fn a_signing_key_is_required_only_if_tracking_is_enabled() {
+        let settings = |enabled| TrackingSettings {
+            enabled,
+            signing_key: Secret::new(String::new()),
+        };
+
+        assert!(Tracker::new(settings(true), "http://127.0.0.1".to_string()).is_err());
+        assert!(Tracker::new(settings(false), "http://127.0.0.1".to_string()).is_ok());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::helpers::{spawn_app, spawn_app_with_settings, TestApp};
+use actix_server::bot_protection::ProofOfWork;
+use std::collections::HashMap;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const BODY: &str = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+async fn expect_emails(test_app: &TestApp, count: u64) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(count)
+        .mount(&test_app.email_server)
+        .await;
+}
+
+async fn get_form_fields(test_app: &TestApp) -> HashMap<String, String> {
+    reqwest::Client::new()
+        .get(format!("{}/subscriptions/form", test_app.address))
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+async fn count_subscribers(test_app: &TestApp) -> Option<i64> {
+    sqlx::query!("SELECT COUNT(*) FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+#[tokio::test]
+async fn a_filled_honeypot_gets_a_fake_success_and_no_email() {
+    let test_app = spawn_app().await;
+    expect_emails(&test_app, 0).await;
+
+    let response = test_app
+        .post_subscriptions(format!("{}&website=https%3A%2F%2Fspam.example", BODY))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    test_app.dispatch_all_pending_emails().await;
+    assert_eq!(count_subscribers(&test_app).await, Some(0));
+}
+
+#[tokio::test]
+async fn the_form_token_is_required_when_enabled() {
+    let test_app =
+        spawn_app_with_settings(|c| c.bot_protection.min_submit_milliseconds = Some(0)).await;
+    expect_emails(&test_app, 1).await;
+
+    let response = test_app.post_subscriptions(BODY.into()).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(count_subscribers(&test_app).await, Some(0));
+
+    let fields = get_form_fields(&test_app).await;
+    let response = test_app
+        .post_subscriptions(format!("{}&form_token={}", BODY, fields["form_token"]))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    test_app.dispatch_all_pending_emails().await;
+    assert_eq!(count_subscribers(&test_app).await, Some(1));
+}
+
+#[tokio::test]
+async fn forms_submitted_too_fast_are_ignored() {
+    let test_app =
+        spawn_app_with_settings(|c| c.bot_protection.min_submit_milliseconds = Some(60_000)).await;
+    expect_emails(&test_app, 0).await;
+
+    let fields = get_form_fields(&test_app).await;
+    let response = test_app
+        .post_subscriptions(format!("{}&form_token={}", BODY, fields["form_token"]))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    test_app.dispatch_all_pending_emails().await;
+    assert_eq!(count_subscribers(&test_app).await, Some(0));
+}
+
+#[tokio::test]
+async fn the_proof_of_work_is_verified_when_enabled() {
+    let test_app =
+        spawn_app_with_settings(|c| c.bot_protection.proof_of_work_difficulty = Some(16)).await;
+    expect_emails(&test_app, 1).await;
+
+    let fields = get_form_fields(&test_app).await;
+    let challenge = &fields["pow_challenge"];
+    assert_eq!(fields["pow_difficulty"], "16");
+
+    // a random nonce solves the challenge once in 65536 attempts
+    let response = test_app
+        .post_subscriptions(format!(
+            "{}&pow_challenge={}&pow_nonce=not-a-solution",
+            BODY, challenge
+        ))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let nonce = ProofOfWork::solve(challenge, "alphacentauri@smail.com", 16);
+    let response = test_app
+        .post_subscriptions(format!(
+            "{}&pow_challenge={}&pow_nonce={}",
+            BODY, challenge, nonce
+        ))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    test_app.dispatch_all_pending_emails().await;
+    assert_eq!(count_subscribers(&test_app).await, Some(1));
+}
+
+#[tokio::test]
+async fn browsers_get_a_form_with_the_hidden_fields() {
+    let test_app =
+        spawn_app_with_settings(|c| c.bot_protection.min_submit_milliseconds = Some(3000)).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/subscriptions/form", test_app.address))
+        .header("Accept", "text/html")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"name="website""#));
+    assert!(html.contains(r#"type="hidden" name="form_token""#));
+}
+
+#[tokio::test]
+async fn a_solved_challenge_can_not_be_replayed() {
+    let test_app =
+        spawn_app_with_settings(|c| c.bot_protection.proof_of_work_difficulty = Some(8)).await;
+    expect_emails(&test_app, 1).await;
+
+    let fields = get_form_fields(&test_app).await;
+    let challenge = &fields["pow_challenge"];
+    for email in ["alpha@smail.com", "beta@smail.com"] {
+        let nonce = ProofOfWork::solve(challenge, email, 8);
+        let response = test_app
+            .post_subscriptions(format!(
+                "name=Alpha%20Centauri&email={}&pow_challenge={}&pow_nonce={}",
+                email.replace('@', "%40"),
+                challenge,
+                nonce
+            ))
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    test_app.dispatch_all_pending_emails().await;
+    assert_eq!(count_subscribers(&test_app).await, Some(1));
+}
+
+#[tokio::test]
+async fn a_corrected_form_can_be_sent_again_with_the_same_token() {
+    let test_app =
+        spawn_app_with_settings(|c| c.bot_protection.min_submit_milliseconds = Some(0)).await;
+    expect_emails(&test_app, 1).await;
+    let fields = get_form_fields(&test_app).await;
+
+    let response = test_app
+        .post_subscriptions(format!(
+            "name=&email=alphacentauri%40smail.com&form_token={}",
+            fields["form_token"]
+        ))
+        .await;
+    assert_eq!(response.status().as_u16(), 400);
+
+    let response = test_app
+        .post_subscriptions(format!("{}&form_token={}", BODY, fields["form_token"]))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    test_app.dispatch_all_pending_emails().await;
+    assert_eq!(count_subscribers(&test_app).await, Some(1));
+}
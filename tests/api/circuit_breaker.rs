@@ -0,0 +1,95 @@
+use crate::helpers::{spawn_app_with_settings, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+// the circuit opens after two failed requests, and stays open during the test
+async fn spawn_app_with_failing_provider() -> TestApp {
+    let test_app = spawn_app_with_settings(|c| {
+        c.outbox.retry_delay_milliseconds = 0;
+        c.email_client.circuit_breaker.window_size = 2;
+        c.email_client.circuit_breaker.minimum_requests = 2;
+        c.email_client.circuit_breaker.cooldown_milliseconds = 60_000;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(2)
+        .mount(&test_app.email_server)
+        .await;
+    test_app
+}
+
+#[tokio::test]
+async fn emails_wait_in_the_outbox_while_the_circuit_is_open() {
+    let test_app = spawn_app_with_failing_provider().await;
+
+    for email in ["alpha%40smail.com", "beta%40smail.com", "gamma%40smail.com"] {
+        let body = format!("name=Alpha%20Centauri&email={}", email);
+        let response = test_app.post_subscriptions(body).await;
+        assert_eq!(response.status().as_u16(), 200);
+        test_app.dispatch_all_pending_emails().await;
+    }
+
+    // the first email failed twice and opened the circuit, the others didn't use an attempt
+    let attempts: Vec<i32> = sqlx::query!("SELECT attempts FROM outbox ORDER BY attempts")
+        .fetch_all(&test_app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.attempts)
+        .collect();
+    assert_eq!(attempts, vec![0, 0, 2]);
+    let (dead_letters,): (i64,) = sqlx::query_as("SELECT count(*) FROM dead_letters")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(dead_letters, 0);
+}
+
+#[tokio::test]
+async fn readiness_reports_the_open_circuit() {
+    let test_app = spawn_app_with_failing_provider().await;
+
+    let response = reqwest::get(format!("{}/health/ready", test_app.address))
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["components"]["email_circuit"]["status"], "up");
+    assert_eq!(body["components"]["email_circuit"]["circuit"], "closed");
+
+    for email in ["alpha%40smail.com", "beta%40smail.com"] {
+        let body = format!("name=Alpha%20Centauri&email={}", email);
+        test_app.post_subscriptions(body).await;
+    }
+    test_app.dispatch_all_pending_emails().await;
+
+    let response = reqwest::get(format!("{}/health/ready", test_app.address))
+        .await
+        .unwrap();
+    // the service keeps accepting signups, the emails are queued
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["components"]["email_circuit"]["status"], "down");
+    assert_eq!(body["components"]["email_circuit"]["circuit"], "open");
+    assert_eq!(body["components"]["email_circuit"]["critical"], false);
+}
+
+#[tokio::test]
+async fn metrics_expose_the_circuit_state() {
+    let test_app = spawn_app_with_settings(|_| {}).await;
+
+    let metrics = reqwest::get(format!("{}/metrics", test_app.address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    for state in ["closed", "open", "half_open"] {
+        assert!(metrics.contains(&format!(
+            r#"circuit_breaker_state{{circuit="postmark",state="{}"}}"#,
+            state
+        )));
+    }
+}
@@ -0,0 +1,75 @@
+use crate::helpers::spawn_app;
+use actix_server::authentication::create_admin;
+use actix_server::cli::check_config;
+use actix_server::configuration::{get_configuration, EmailBackendSettings, Environment};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use claim::{assert_err, assert_ok};
+use secrecy::Secret;
+
+#[tokio::test]
+async fn create_admin_stores_a_hashed_password() {
+    let test_app = spawn_app().await;
+
+    create_admin(
+        &test_app.db_pool,
+        "admin",
+        Secret::new("a-long-password".into()),
+    )
+    .await
+    .unwrap();
+
+    let saved = sqlx::query!("SELECT username, password_hash FROM users WHERE username = 'admin'")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("cannot retrieve the admin");
+    assert_eq!(saved.username, "admin");
+    assert_ne!(saved.password_hash, "a-long-password");
+    let password_hash = PasswordHash::new(&saved.password_hash).unwrap();
+    Argon2::default()
+        .verify_password(b"a-long-password", &password_hash)
+        .expect("the password does not match its hash");
+}
+
+#[tokio::test]
+async fn create_admin_rejects_a_taken_username() {
+    let test_app = spawn_app().await;
+
+    create_admin(&test_app.db_pool, "admin", Secret::new("first".into()))
+        .await
+        .unwrap();
+
+    assert_err!(create_admin(&test_app.db_pool, "admin", Secret::new("second".into())).await);
+}
+
+#[test]
+fn config_check_redacts_secrets() {
+    let mut configuration = get_configuration().expect("failed to load configuration");
+    configuration.database.password = Secret::new("db-super-secret".into());
+    configuration.email_client.authorization_token = Secret::new("token-super-secret".into());
+
+    let printed = check_config(&configuration).unwrap();
+
+    assert!(printed.contains("database_name"));
+    assert!(!printed.contains("db-super-secret"));
+    assert!(!printed.contains("token-super-secret"));
+}
+
+#[test]
+fn config_check_rejects_an_invalid_configuration() {
+    let mut configuration = get_configuration().expect("failed to load configuration");
+    configuration.email_client.sender_email = "not-an-email".into();
+
+    assert_err!(check_config(&configuration));
+}
+
+#[test]
+fn config_check_rejects_a_development_email_backend_in_production() {
+    let mut configuration = get_configuration().expect("failed to load configuration");
+    configuration.environment = Environment::Production;
+    configuration.email_client.backend = EmailBackendSettings::Stdout;
+
+    assert_err!(check_config(&configuration));
+
+    configuration.email_client.backend = EmailBackendSettings::Postmark;
+    assert_ok!(check_config(&configuration));
+}
@@ -0,0 +1,290 @@
+use crate::helpers::{spawn_app, spawn_app_with_settings, TestApp};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+// the relay gives up after the second failed attempt
+async fn spawn_app_with_failing_provider() -> TestApp {
+    let test_app = spawn_app_with_settings(|c| {
+        c.outbox.retry_delay_milliseconds = 0;
+        c.outbox.max_attempts = 2;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&test_app.email_server)
+        .await;
+    test_app
+}
+
+async fn dead_letter_a_confirmation(test_app: &TestApp, email: &str) {
+    let body = format!("name=Alpha%20Centauri&email={}", email.replace('@', "%40"));
+    let response = test_app.post_subscriptions(body).await;
+    assert_eq!(response.status().as_u16(), 200);
+    test_app.dispatch_all_pending_emails().await;
+}
+
+async fn get_json(test_app: &TestApp, path: &str) -> (u16, serde_json::Value) {
+    let response = test_app.admin_get(path).await;
+    (
+        response.status().as_u16(),
+        response.json().await.expect("the response is not JSON"),
+    )
+}
+
+async fn post_json(test_app: &TestApp, path: &str) -> (u16, serde_json::Value) {
+    let response = test_app.admin_post(path).await;
+    (
+        response.status().as_u16(),
+        response.json().await.expect("the response is not JSON"),
+    )
+}
+
+async fn deliver_from_now_on(test_app: &TestApp) {
+    test_app.email_server.reset().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+}
+
+#[tokio::test]
+async fn emails_failing_every_attempt_are_listed_as_dead_letters() {
+    let test_app = spawn_app_with_failing_provider().await;
+
+    dead_letter_a_confirmation(&test_app, "alpha@smail.com").await;
+
+    let (status, list) = get_json(&test_app, "/admin/dead_letters").await;
+    assert_eq!(status, 200);
+    let dead_letters = list["dead_letters"].as_array().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0]["recipient"], "alpha@smail.com");
+    assert_eq!(dead_letters[0]["attempts"], 2);
+    assert!(dead_letters[0]["last_error"]
+        .as_str()
+        .unwrap()
+        .contains("500"));
+    // the payload is only returned when a dead letter is inspected
+    assert!(dead_letters[0].get("html_body").is_none());
+}
+
+#[tokio::test]
+async fn a_dead_letter_keeps_its_payload_and_attempt_history() {
+    let test_app = spawn_app_with_failing_provider().await;
+    dead_letter_a_confirmation(&test_app, "alpha@smail.com").await;
+    let (_, list) = get_json(&test_app, "/admin/dead_letters").await;
+    let id = list["dead_letters"][0]["id"].as_str().unwrap().to_owned();
+
+    let (status, dead_letter) = get_json(&test_app, &format!("/admin/dead_letters/{}", id)).await;
+
+    assert_eq!(status, 200);
+    assert_eq!(dead_letter["subject"], "Welcome!");
+    assert!(dead_letter["text_body"]
+        .as_str()
+        .unwrap()
+        .contains("/subscriptions/confirm?subscription_token="));
+    let history = dead_letter["history"].as_array().unwrap();
+    assert_eq!(history.len(), 2);
+    assert!(history
+        .iter()
+        .all(|attempt| attempt["error"].as_str().unwrap().contains("500")));
+}
+
+#[tokio::test]
+async fn emails_that_can_never_be_delivered_are_dead_lettered_at_once() {
+    let test_app = spawn_app_with_failing_provider().await;
+    sqlx::query(
+        "INSERT INTO outbox (id, recipient, subject, html_body, text_body, created_at, next_attempt_at) \
+        VALUES ($1, 'not-an-email', 'Welcome!', 'html', 'text', now(), now())",
+    )
+    .bind(Uuid::new_v4())
+    .execute(&test_app.db_pool)
+    .await
+    .unwrap();
+
+    test_app.dispatch_all_pending_emails().await;
+
+    let (_, list) = get_json(&test_app, "/admin/dead_letters").await;
+    assert_eq!(list["dead_letters"][0]["attempts"], 1);
+    assert!(test_app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn a_replayed_dead_letter_is_delivered() {
+    let test_app = spawn_app_with_failing_provider().await;
+    dead_letter_a_confirmation(&test_app, "alpha@smail.com").await;
+    let (_, list) = get_json(&test_app, "/admin/dead_letters").await;
+    let id = list["dead_letters"][0]["id"].as_str().unwrap().to_owned();
+    deliver_from_now_on(&test_app).await;
+
+    let (status, report) =
+        post_json(&test_app, &format!("/admin/dead_letters/{}/replay", id)).await;
+    test_app.dispatch_all_pending_emails().await;
+
+    assert_eq!(status, 200);
+    assert_eq!(report["replayed"], 1);
+    assert_eq!(
+        test_app
+            .email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+    let (status, _) = get_json(&test_app, &format!("/admin/dead_letters/{}", id)).await;
+    assert_eq!(status, 404);
+    let (attempts,): (i64,) =
+        sqlx::query_as("SELECT count(*) FROM email_delivery_attempts WHERE email_id = $1")
+            .bind(Uuid::parse_str(&id).unwrap())
+            .fetch_one(&test_app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(attempts, 3);
+}
+
+#[tokio::test]
+async fn every_dead_letter_can_be_replayed_at_once() {
+    let test_app = spawn_app_with_failing_provider().await;
+    dead_letter_a_confirmation(&test_app, "alpha@smail.com").await;
+    dead_letter_a_confirmation(&test_app, "beta@smail.com").await;
+    deliver_from_now_on(&test_app).await;
+
+    let (status, report) = post_json(&test_app, "/admin/dead_letters/replay").await;
+    test_app.dispatch_all_pending_emails().await;
+
+    assert_eq!(status, 200);
+    assert_eq!(report["replayed"], 2);
+    assert_eq!(
+        test_app
+            .email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+    let (_, list) = get_json(&test_app, "/admin/dead_letters").await;
+    assert!(list["dead_letters"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn unknown_dead_letters_are_reported_as_not_found() {
+    let test_app = spawn_app_with_failing_provider().await;
+    let id = Uuid::new_v4();
+
+    let (status, problem) = get_json(&test_app, &format!("/admin/dead_letters/{}", id)).await;
+    assert_eq!(status, 404);
+    assert_eq!(problem["code"], "dead_letter_not_found");
+
+    let (status, problem) =
+        post_json(&test_app, &format!("/admin/dead_letters/{}/replay", id)).await;
+    assert_eq!(status, 404);
+    assert_eq!(problem["code"], "dead_letter_not_found");
+}
+
+#[tokio::test]
+async fn metrics_expose_the_dead_letters() {
+    let test_app = spawn_app_with_failing_provider().await;
+    dead_letter_a_confirmation(&test_app, "alpha@smail.com").await;
+
+    let metrics = reqwest::get(format!("{}/metrics", test_app.address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    assert!(metrics.contains("dead_letter_queue_depth 1"));
+    assert!(metrics.contains("email_dead_letters_total"));
+}
+
+#[tokio::test]
+async fn recipients_the_provider_marked_inactive_are_dead_lettered_at_once() {
+    let test_app = spawn_app_with_settings(|c| c.outbox.retry_delay_milliseconds = 0).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive."
+        })))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    dead_letter_a_confirmation(&test_app, "alpha@smail.com").await;
+
+    let (_, list) = get_json(&test_app, "/admin/dead_letters").await;
+    assert_eq!(list["dead_letters"][0]["attempts"], 1);
+    assert!(list["dead_letters"][0]["last_error"]
+        .as_str()
+        .unwrap()
+        .starts_with("the recipient is inactive"));
+}
+
+#[tokio::test]
+async fn the_dead_letters_need_admin_credentials() {
+    let test_app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    for request in [
+        client.get(format!("{}/admin/dead_letters", test_app.address)),
+        client.post(format!("{}/admin/dead_letters/replay", test_app.address)),
+        client.get(format!(
+            "{}/admin/dead_letters/{}",
+            test_app.address,
+            Uuid::new_v4()
+        )),
+        client.post(format!(
+            "{}/admin/dead_letters/{}/replay",
+            test_app.address,
+            Uuid::new_v4()
+        )),
+    ] {
+        let response = request.send().await.unwrap();
+
+        assert_eq!(response.status().as_u16(), 401);
+        assert_eq!(
+            response.headers()["WWW-Authenticate"],
+            r#"Basic realm="admin""#
+        );
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["code"], "missing_credentials");
+    }
+}
+
+#[tokio::test]
+async fn the_dead_letters_refuse_invalid_credentials() {
+    let test_app = spawn_app().await;
+
+    for (username, password) in [
+        // wrong password
+        (
+            test_app.test_user.username.clone(),
+            Uuid::new_v4().to_string(),
+        ),
+        // unknown user
+        (
+            Uuid::new_v4().to_string(),
+            test_app.test_user.password.clone(),
+        ),
+    ] {
+        let response = reqwest::Client::new()
+            .get(format!("{}/admin/dead_letters", test_app.address))
+            .basic_auth(username, Some(password))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status().as_u16(), 401);
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["code"], "invalid_credentials");
+    }
+}
@@ -0,0 +1,99 @@
+use crate::helpers::{spawn_app_with_settings, TestApp};
+use actix_server::configuration::EmailBackendSettings;
+use uuid::Uuid;
+
+async fn sign_up(test_app: &TestApp) {
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+    let response = test_app.post_subscriptions(body.into()).await;
+    assert_eq!(response.status().as_u16(), 200);
+    test_app.dispatch_all_pending_emails().await;
+}
+
+async fn delivering_provider(test_app: &TestApp) -> String {
+    sqlx::query!("SELECT provider FROM email_delivery_attempts WHERE error IS NULL")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("the email was not delivered")
+        .provider
+        .unwrap()
+}
+
+#[tokio::test]
+async fn the_in_memory_backend_keeps_the_sent_emails() {
+    let test_app =
+        spawn_app_with_settings(|c| c.email_client.backend = EmailBackendSettings::InMemory).await;
+
+    sign_up(&test_app).await;
+
+    let sent = test_app.sent_emails();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].to, "alphacentauri@smail.com");
+    assert_eq!(sent[0].subject, "Welcome!");
+    assert_eq!(delivering_provider(&test_app).await, "in_memory");
+    // nothing reached the provider
+    assert!(test_app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn the_links_of_an_email_kept_in_memory_confirm_the_subscription() {
+    let test_app =
+        spawn_app_with_settings(|c| c.email_client.backend = EmailBackendSettings::InMemory).await;
+    sign_up(&test_app).await;
+    let confirmation_links = test_app.get_confirmation_links_from(&test_app.sent_emails()[0]);
+
+    let response = test_app.confirm(&confirmation_links.html).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn the_file_drop_backend_writes_an_eml_file() {
+    let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    let drop_directory = directory.clone();
+    let test_app = spawn_app_with_settings(move |c| {
+        c.email_client.backend = EmailBackendSettings::FileDrop {
+            directory: drop_directory,
+        }
+    })
+    .await;
+
+    sign_up(&test_app).await;
+
+    let files: Vec<_> = std::fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].extension().unwrap(), "eml");
+    let eml = std::fs::read_to_string(&files[0]).unwrap();
+    assert!(eml.contains("\r\nTo: alphacentauri@smail.com\r\n"));
+    assert!(eml.contains("\r\nSubject: Welcome!\r\n"));
+    assert_eq!(delivering_provider(&test_app).await, "file_drop");
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn the_stdout_backend_delivers_without_a_provider() {
+    let test_app =
+        spawn_app_with_settings(|c| c.email_client.backend = EmailBackendSettings::Stdout).await;
+
+    sign_up(&test_app).await;
+
+    assert_eq!(delivering_provider(&test_app).await, "stdout");
+    assert!(test_app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .is_empty());
+}
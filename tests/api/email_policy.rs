@@ -0,0 +1,168 @@
+use crate::helpers::{spawn_app_with_settings, TestApp};
+use hickory_proto::op::{Message, MessageType, ResponseCode};
+use hickory_proto::rr::rdata::MX;
+use hickory_proto::rr::{Name, RData, Record};
+use std::collections::HashMap;
+use tokio::net::UdpSocket;
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+// a local DNS server answering MX queries from a fixed zone,
+// the domains missing from the zone don't exist
+async fn spawn_dns_stand_in(zone: HashMap<&'static str, &'static str>) -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 512];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buffer).await.unwrap();
+            let query = Message::from_vec(&buffer[..len]).unwrap();
+
+            let mut response = Message::new();
+            response
+                .set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(query.op_code())
+                .set_recursion_desired(query.recursion_desired())
+                .set_recursion_available(true);
+            for question in query.queries() {
+                response.add_query(question.clone());
+                let name = question.name().to_ascii().to_lowercase();
+                match zone.get(name.as_str()) {
+                    Some(exchange) => {
+                        response.add_answer(Record::from_rdata(
+                            question.name().clone(),
+                            60,
+                            RData::MX(MX::new(10, Name::from_ascii(exchange).unwrap())),
+                        ));
+                    }
+                    None => {
+                        response.set_response_code(ResponseCode::NXDomain);
+                    }
+                }
+            }
+            socket
+                .send_to(&response.to_vec().unwrap(), peer)
+                .await
+                .unwrap();
+        }
+    });
+
+    address
+}
+
+async fn mount_email_server(test_app: &TestApp) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+}
+
+async fn rejection_code(test_app: &TestApp, email: &str) -> Option<String> {
+    let response = test_app
+        .post_subscriptions(format!(
+            "name=Alpha%20Centauri&email={}",
+            email.replace('@', "%40")
+        ))
+        .await;
+    if response.status().is_success() {
+        return None;
+    }
+    assert_eq!(response.status().as_u16(), 400);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["field"], "email");
+    Some(problem["errors"][0]["code"].as_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn domains_without_mail_server_are_rejected() {
+    let resolver = spawn_dns_stand_in(HashMap::from([
+        ("smail.com.", "mx.smail.com."),
+        // a "null MX", the domain doesn't accept mail
+        ("nomail.com.", "."),
+    ]))
+    .await;
+    let test_app = spawn_app_with_settings(|c| {
+        c.email_policy.mx_lookup = true;
+        c.email_policy.dns_resolver = Some(resolver);
+    })
+    .await;
+    mount_email_server(&test_app).await;
+
+    assert_eq!(rejection_code(&test_app, "alpha@smail.com").await, None);
+    assert_eq!(
+        rejection_code(&test_app, "alpha@nomail.com")
+            .await
+            .as_deref(),
+        Some("no_mail_server")
+    );
+    assert_eq!(
+        rejection_code(&test_app, "alpha@does-not-exist.com")
+            .await
+            .as_deref(),
+        Some("no_mail_server")
+    );
+}
+
+#[tokio::test]
+async fn addresses_are_accepted_if_the_dns_is_not_reachable() {
+    // nothing listens on this port
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let resolver = socket.local_addr().unwrap().to_string();
+    drop(socket);
+    let test_app = spawn_app_with_settings(|c| {
+        c.email_policy.mx_lookup = true;
+        c.email_policy.dns_resolver = Some(resolver);
+        c.email_policy.mx_lookup_timeout_milliseconds = 200;
+    })
+    .await;
+    mount_email_server(&test_app).await;
+
+    assert_eq!(rejection_code(&test_app, "alpha@smail.com").await, None);
+}
+
+#[tokio::test]
+async fn the_disposable_domains_are_loaded_from_the_blocklist() {
+    let blocklist = std::env::temp_dir().join(format!("{}.txt", Uuid::new_v4()));
+    std::fs::write(&blocklist, "# disposable providers\nmailinator.com\n").unwrap();
+    let test_app = spawn_app_with_settings(|c| {
+        c.email_policy.disposable_domains_file = Some(blocklist.display().to_string());
+    })
+    .await;
+    mount_email_server(&test_app).await;
+
+    assert_eq!(
+        rejection_code(&test_app, "alpha@mailinator.com")
+            .await
+            .as_deref(),
+        Some("disposable_domain")
+    );
+    assert_eq!(rejection_code(&test_app, "alpha@smail.com").await, None);
+    std::fs::remove_file(blocklist).unwrap();
+}
+
+#[tokio::test]
+async fn role_accounts_and_denied_domains_are_rejected_when_configured() {
+    let test_app = spawn_app_with_settings(|c| {
+        c.email_policy.reject_role_accounts = true;
+        c.email_policy.denied_domains = vec!["competitor.com".into()];
+    })
+    .await;
+    mount_email_server(&test_app).await;
+
+    assert_eq!(
+        rejection_code(&test_app, "postmaster@smail.com")
+            .await
+            .as_deref(),
+        Some("role_account")
+    );
+    assert_eq!(
+        rejection_code(&test_app, "alpha@competitor.com")
+            .await
+            .as_deref(),
+        Some("domain_not_allowed")
+    );
+}
@@ -0,0 +1,172 @@
+use crate::helpers::{spawn_app_with_settings, TestApp};
+use actix_server::configuration::EmailTransportSettings;
+use secrecy::Secret;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+// the test app's email server is the primary provider
+async fn spawn_app_with_fallback(fallback_server: &MockServer) -> TestApp {
+    let fallback_url = fallback_server.uri();
+    spawn_app_with_settings(move |c| {
+        c.outbox.retry_delay_milliseconds = 0;
+        c.email_client.circuit_breaker.window_size = 2;
+        c.email_client.circuit_breaker.minimum_requests = 2;
+        c.email_client.circuit_breaker.cooldown_milliseconds = 60_000;
+        c.email_client.fallbacks = vec![EmailTransportSettings {
+            name: "fallback".into(),
+            base_url: fallback_url,
+            authorization_token: Secret::new("fallback-token".into()),
+        }];
+    })
+    .await
+}
+
+async fn sign_up(test_app: &TestApp, email: &str) {
+    let body = format!("name=Alpha%20Centauri&email={}", email.replace('@', "%40"));
+    let response = test_app.post_subscriptions(body).await;
+    assert_eq!(response.status().as_u16(), 200);
+    test_app.dispatch_all_pending_emails().await;
+}
+
+async fn delivering_providers(test_app: &TestApp) -> Vec<String> {
+    sqlx::query!("SELECT provider FROM email_delivery_attempts WHERE error IS NULL ORDER BY id")
+        .fetch_all(&test_app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.provider.unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn the_fallback_delivers_when_the_primary_provider_fails() {
+    let fallback_server = MockServer::start().await;
+    let test_app = spawn_app_with_fallback(&fallback_server).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&fallback_server)
+        .await;
+
+    sign_up(&test_app, "alpha@smail.com").await;
+
+    // a single attempt, recorded with the provider that accepted the email
+    let (attempts,): (i32,) = sqlx::query_as("SELECT attempts FROM outbox")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(attempts, 1);
+    assert_eq!(delivering_providers(&test_app).await, vec!["fallback"]);
+    // the delivery log keeps the failure of the primary provider
+    let tried = sqlx::query!(
+        "SELECT provider, error FROM email_delivery_attempts ORDER BY attempted_at, id"
+    )
+    .fetch_all(&test_app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(tried.len(), 2);
+    assert_eq!(tried[0].provider.as_deref(), Some("postmark"));
+    assert!(tried[0].error.as_deref().unwrap().contains("503"));
+    assert_eq!(tried[1].provider.as_deref(), Some("fallback"));
+    assert_eq!(tried[1].error, None);
+    let request = &fallback_server.received_requests().await.unwrap()[0];
+    assert_eq!(
+        request.headers.get("X-Postmark-Server-Token").unwrap(),
+        "fallback-token"
+    );
+}
+
+#[tokio::test]
+async fn the_primary_provider_is_skipped_while_its_circuit_is_open() {
+    let fallback_server = MockServer::start().await;
+    let test_app = spawn_app_with_fallback(&fallback_server).await;
+    // the circuit opens after the first two emails
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(2)
+        .mount(&test_app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(3)
+        .mount(&fallback_server)
+        .await;
+
+    for email in ["alpha@smail.com", "beta@smail.com", "gamma@smail.com"] {
+        sign_up(&test_app, email).await;
+    }
+
+    assert_eq!(
+        delivering_providers(&test_app).await,
+        vec!["fallback", "fallback", "fallback"]
+    );
+    let body: serde_json::Value = reqwest::get(format!("{}/health/ready", test_app.address))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    // the primary circuit is open, the emails still go out
+    assert_eq!(body["components"]["email_circuit"]["circuit"], "open");
+    assert_eq!(body["components"]["email_circuit"]["status"], "up");
+}
+
+#[tokio::test]
+async fn the_primary_provider_is_used_while_it_delivers() {
+    let fallback_server = MockServer::start().await;
+    let test_app = spawn_app_with_fallback(&fallback_server).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&fallback_server)
+        .await;
+
+    sign_up(&test_app, "alpha@smail.com").await;
+
+    assert_eq!(delivering_providers(&test_app).await, vec!["postmark"]);
+}
+
+#[tokio::test]
+async fn refused_recipients_are_not_sent_to_the_fallback() {
+    let fallback_server = MockServer::start().await;
+    let test_app = spawn_app_with_fallback(&fallback_server).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive."
+        })))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&fallback_server)
+        .await;
+
+    sign_up(&test_app, "alpha@smail.com").await;
+
+    let (dead_letters,): (i64,) = sqlx::query_as("SELECT count(*) FROM dead_letters")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(dead_letters, 1);
+}
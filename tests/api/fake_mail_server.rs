@@ -0,0 +1,297 @@
+use crate::helpers::{spawn_app_with_settings, TestApp};
+use serde_json::json;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+
+// the fake provider binary, killed when dropped
+struct FakeMailServer {
+    address: String,
+    process: Child,
+}
+
+impl Drop for FakeMailServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+// runs the fake provider on a random port, its address is read from the first log line
+fn spawn_fake_mail_server(arguments: &[&str]) -> FakeMailServer {
+    let mut process = Command::new(env!("CARGO_BIN_EXE_fake_mail_server"))
+        .args(["--port", "0"])
+        .args(arguments)
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run the fake mail server");
+    let mut lines = BufReader::new(process.stdout.take().unwrap()).lines();
+    let address = lines
+        .by_ref()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(&line).ok())
+        .find(|log| log["msg"] == "fake mail server listening")
+        .and_then(|log| log["address"].as_str().map(str::to_owned))
+        .expect("the fake mail server did not start");
+    // keeps reading the logs, so that the server never blocks on a full pipe
+    std::thread::spawn(move || lines.for_each(drop));
+    FakeMailServer {
+        address: format!("http://{}", address),
+        process,
+    }
+}
+
+async fn spawn_app_sending_to(fake_mail_server: &str) -> TestApp {
+    let base_url = fake_mail_server.to_string();
+    spawn_app_with_settings(move |c| {
+        c.email_client.base_url = base_url;
+        c.outbox.retry_delay_milliseconds = 0;
+    })
+    .await
+}
+
+async fn sign_up(test_app: &TestApp) {
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+    let response = test_app.post_subscriptions(body.into()).await;
+    assert_eq!(response.status().as_u16(), 200);
+    test_app.dispatch_all_pending_emails().await;
+}
+
+async fn get_json(url: String) -> serde_json::Value {
+    reqwest::get(url).await.unwrap().json().await.unwrap()
+}
+
+async fn post_emails(
+    fake_mail_server: &str,
+    path: &str,
+    body: serde_json::Value,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}{}", fake_mail_server, path))
+        .header("X-Postmark-Server-Token", "any-token")
+        .json(&body)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn inject_failures(fake_mail_server: &str, failures: serde_json::Value) {
+    let response = reqwest::Client::new()
+        .put(format!("{}/api/failures", fake_mail_server))
+        .json(&failures)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn the_confirmation_link_received_by_the_fake_provider_confirms_the_subscription() {
+    let fake_mail_server = spawn_fake_mail_server(&[]);
+    let test_app = spawn_app_sending_to(&fake_mail_server.address).await;
+    sign_up(&test_app).await;
+
+    let list = get_json(format!(
+        "{}/api/messages?to=alphacentauri@smail.com",
+        fake_mail_server.address
+    ))
+    .await;
+    let messages = list["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0]["subject"], "Welcome!");
+    let message = get_json(format!(
+        "{}/api/messages/{}",
+        fake_mail_server.address,
+        messages[0]["id"].as_str().unwrap()
+    ))
+    .await;
+    let links = message["links"].as_array().unwrap();
+    assert_eq!(links.len(), 1);
+    let mut confirmation_link = reqwest::Url::parse(links[0].as_str().unwrap()).unwrap();
+    confirmation_link.set_port(Some(test_app.port)).unwrap();
+
+    let response = test_app.confirm(&confirmation_link).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn each_message_of_a_batch_reports_its_own_outcome() {
+    let fake_mail_server = spawn_fake_mail_server(&[]);
+    let message = |to: &str| {
+        json!({
+            "From": "newsletter@example.com",
+            "To": to,
+            "Subject": "Hello",
+            "TextBody": "Hello"
+        })
+    };
+
+    let response = post_emails(
+        &fake_mail_server.address,
+        "/email/batch",
+        json!([
+            message("alpha@smail.com"),
+            message(""),
+            message("beta@smail.com")
+        ]),
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let outcomes: serde_json::Value = response.json().await.unwrap();
+    let error_codes: Vec<_> = outcomes
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|outcome| outcome["ErrorCode"].as_i64().unwrap())
+        .collect();
+    assert_eq!(error_codes, vec![0, 300, 0]);
+    let list = get_json(format!("{}/api/messages", fake_mail_server.address)).await;
+    assert_eq!(list["messages"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn requests_without_a_token_are_unauthorized() {
+    let fake_mail_server = spawn_fake_mail_server(&[]);
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/email", fake_mail_server.address))
+        .json(&json!({"From": "a@example.com", "To": "b@example.com", "TextBody": "Hello"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["ErrorCode"], 10);
+}
+
+#[tokio::test]
+async fn injected_rate_limiting_postpones_the_delivery() {
+    let fake_mail_server = spawn_fake_mail_server(&[]);
+    let test_app = spawn_app_sending_to(&fake_mail_server.address).await;
+    inject_failures(
+        &fake_mail_server.address,
+        json!({"rate": 1.0, "status": 429, "retry_after_seconds": 60}),
+    )
+    .await;
+
+    sign_up(&test_app).await;
+
+    let queued = sqlx::query!(
+        "SELECT attempts, last_error, next_attempt_at > now() + interval '50 seconds' AS \"postponed!\" FROM outbox"
+    )
+    .fetch_one(&test_app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(queued.attempts, 1);
+    assert!(queued.last_error.unwrap().contains("rate limiting"));
+    assert!(queued.postponed);
+}
+
+#[tokio::test]
+async fn injected_server_errors_are_retried() {
+    let fake_mail_server = spawn_fake_mail_server(&[]);
+    let test_app = spawn_app_with_settings({
+        let base_url = fake_mail_server.address.clone();
+        move |c| {
+            c.email_client.base_url = base_url;
+            c.outbox.retry_delay_milliseconds = 0;
+            c.outbox.max_attempts = 2;
+        }
+    })
+    .await;
+    inject_failures(
+        &fake_mail_server.address,
+        json!({"rate": 1.0, "status": 500}),
+    )
+    .await;
+
+    sign_up(&test_app).await;
+
+    let dead_letter = sqlx::query!("SELECT attempts, last_error FROM dead_letters")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(dead_letter.attempts, 2);
+    assert!(dead_letter.last_error.contains("500"));
+    let list = get_json(format!("{}/api/messages", fake_mail_server.address)).await;
+    assert!(list["messages"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn the_web_ui_shows_the_received_emails() {
+    let fake_mail_server = spawn_fake_mail_server(&[]);
+    let response = post_emails(
+        &fake_mail_server.address,
+        "/email",
+        json!({
+            "From": "newsletter@example.com",
+            "To": "alpha@smail.com",
+            "Subject": "Our <latest> issue",
+            "HtmlBody": "<p>Read it <a href=\"https://example.com/issue\">here</a></p>",
+            "TextBody": "Read it at https://example.com/issue"
+        }),
+    )
+    .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let id = response.json::<serde_json::Value>().await.unwrap()["MessageID"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+
+    let index = reqwest::get(format!("{}/", fake_mail_server.address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    // the subject is escaped
+    assert!(index.contains("Our &lt;latest&gt; issue"));
+    assert!(index.contains(&format!("/messages/{}", id)));
+
+    let page = reqwest::get(format!("{}/messages/{}", fake_mail_server.address, id))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    // the links are listed, escaped like every other value of the page
+    assert!(page.contains(r#"<a href="https:&#x2f;&#x2f;example.com&#x2f;issue">"#));
+    assert!(page.contains(&format!(r#"<iframe src="/messages/{}/html" sandbox>"#, id)));
+
+    let html = reqwest::get(format!("{}/messages/{}/html", fake_mail_server.address, id))
+        .await
+        .unwrap();
+    assert_eq!(html.headers()["content-security-policy"], "sandbox");
+    assert!(html.text().await.unwrap().starts_with("<p>Read it"));
+}
+
+#[tokio::test]
+async fn only_the_latest_messages_are_kept() {
+    let fake_mail_server = spawn_fake_mail_server(&["--max-messages", "2"]);
+
+    for to in ["alpha@smail.com", "beta@smail.com", "gamma@smail.com"] {
+        let response = post_emails(
+            &fake_mail_server.address,
+            "/email",
+            json!({"From": "newsletter@example.com", "To": to, "TextBody": "Hello"}),
+        )
+        .await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    let list = get_json(format!("{}/api/messages", fake_mail_server.address)).await;
+    let recipients: Vec<_> = list["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["to"].as_str().unwrap())
+        .collect();
+    assert_eq!(recipients, vec!["gamma@smail.com", "beta@smail.com"]);
+}
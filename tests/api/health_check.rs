@@ -1,4 +1,6 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with_settings};
+use actix_server::configuration::get_configuration;
+use sqlx::{Connection, Executor, PgConnection};
 
 #[tokio::test]
 async fn health_check_works() {
@@ -19,3 +21,110 @@ async fn health_check_works() {
     // verify content length 0
     assert_eq!(response.content_length(), Some(0));
 }
+
+#[tokio::test]
+async fn liveness_returns_200() {
+    let test_app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/health/live", test_app.address))
+        .await
+        .expect("failed to execute liveness request");
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "up");
+}
+
+#[tokio::test]
+async fn readiness_reports_every_component() {
+    let test_app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/health/ready", test_app.address))
+        .await
+        .expect("failed to execute readiness request");
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "up");
+    for component in ["database", "migrations", "email_provider"] {
+        assert_eq!(body["components"][component]["status"], "up");
+        assert!(body["components"][component]["latency_ms"].is_u64());
+    }
+}
+
+#[tokio::test]
+async fn readiness_returns_503_if_migrations_are_missing() {
+    let test_app = spawn_app().await;
+
+    // pretend the last migration was never applied
+    sqlx::query(
+        "DELETE FROM _sqlx_migrations WHERE version = (SELECT MAX(version) FROM _sqlx_migrations)",
+    )
+    .execute(&test_app.db_pool)
+    .await
+    .unwrap();
+
+    let response = reqwest::get(format!("{}/health/ready", test_app.address))
+        .await
+        .expect("failed to execute readiness request");
+
+    assert_eq!(response.status().as_u16(), 503);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "down");
+    assert_eq!(body["components"]["database"]["status"], "up");
+    assert_eq!(body["components"]["migrations"]["status"], "down");
+}
+
+#[tokio::test]
+async fn readiness_does_not_expose_the_database_errors() {
+    let test_app = spawn_app().await;
+    let database_name: String = sqlx::query_scalar("SELECT current_database()")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    // take the database down under the running application
+    let configuration = get_configuration().expect("failed to load configuration");
+    let mut connection = PgConnection::connect_with(&configuration.database.without_db())
+        .await
+        .unwrap();
+    connection
+        .execute(format!(r#"DROP DATABASE "{}" WITH (FORCE)"#, database_name).as_str())
+        .await
+        .unwrap();
+
+    let response = reqwest::get(format!("{}/health/ready", test_app.address))
+        .await
+        .expect("failed to execute readiness request");
+
+    assert_eq!(response.status().as_u16(), 503);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "down");
+    assert_eq!(
+        body["components"]["database"]["error"],
+        "the database can not be queried"
+    );
+    assert_eq!(
+        body["components"]["migrations"]["error"],
+        "the applied migrations can not be read"
+    );
+}
+
+#[tokio::test]
+async fn readiness_does_not_expose_the_email_provider_errors() {
+    // nothing listens on this port
+    let test_app =
+        spawn_app_with_settings(|c| c.email_client.base_url = "http://127.0.0.1:9".into()).await;
+
+    let response = reqwest::get(format!("{}/health/ready", test_app.address))
+        .await
+        .expect("failed to execute readiness request");
+
+    // the email provider isn't critical
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["components"]["email_provider"]["status"], "down");
+    assert_eq!(
+        body["components"]["email_provider"]["error"],
+        "the email provider can not be reached"
+    );
+}
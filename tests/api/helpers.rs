@@ -1,11 +1,19 @@
-use actix_server::startup::Application;
+use actix_server::authentication::create_admin;
+use actix_server::dev_transports::SentEmail;
+use actix_server::email_client::EmailClient;
+use actix_server::outbox::{DeliveryOutcome, OutboxRelay};
+use actix_server::shutdown::ShutdownHandle;
+use actix_server::startup::{Application, MIGRATOR};
+use actix_server::tracking::Tracker;
 use actix_server::{
-    configuration::{get_configuration, DatabaseSettings},
+    configuration::{get_configuration, DatabaseSettings, Settings, TelemetrySettings},
     startup::get_connection_pool,
-    telemetry::{get_tracing_subscriber, init_tracing_subscriber},
+    telemetry::{get_tracer_provider, get_tracing_subscriber, init_tracing_subscriber},
 };
 use once_cell::sync::Lazy;
+use secrecy::Secret;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 use wiremock::MockServer;
 
@@ -13,13 +21,19 @@ static TRACING: Lazy<()> = Lazy::new(|| {
     let sub_name = "test_actix_server".to_string();
     let sub_env_filter = "debug".to_string();
 
+    // spans are not exported during tests, but the trace context is still propagated
+    let tracer_provider = get_tracer_provider(sub_name.clone(), &TelemetrySettings::default())
+        .expect("failed to build the tracer provider");
+
     // use environment variable TEST_LOG = true to display the log messages
     if std::env::var("TEST_LOG").is_ok() {
-        let tracing_subscriber = get_tracing_subscriber(sub_name, sub_env_filter, std::io::stdout);
-        init_tracing_subscriber(tracing_subscriber);
+        let tracing_subscriber =
+            get_tracing_subscriber(sub_name, sub_env_filter, std::io::stdout, &tracer_provider);
+        init_tracing_subscriber(tracing_subscriber, tracer_provider);
     } else {
-        let tracing_subscriber = get_tracing_subscriber(sub_name, sub_env_filter, std::io::sink);
-        init_tracing_subscriber(tracing_subscriber);
+        let tracing_subscriber =
+            get_tracing_subscriber(sub_name, sub_env_filter, std::io::sink, &tracer_provider);
+        init_tracing_subscriber(tracing_subscriber, tracer_provider);
     }
 });
 
@@ -28,6 +42,33 @@ pub struct TestApp {
     pub db_pool: PgPool,
     pub email_server: MockServer,
     pub port: u16,
+    pub tracker: Tracker,
+    pub shutdown_handle: ShutdownHandle,
+    pub application_task: JoinHandle<Result<(), std::io::Error>>,
+    pub outbox_relay: OutboxRelay,
+    pub email_client: EmailClient,
+    pub test_user: TestUser,
+}
+
+// an admin of the `users` table, for the `/admin` routes
+pub struct TestUser {
+    pub username: String,
+    pub password: String,
+}
+
+impl TestUser {
+    fn generate() -> Self {
+        Self {
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        }
+    }
+
+    async fn store(&self, db_pool: &PgPool) {
+        create_admin(db_pool, &self.username, Secret::new(self.password.clone()))
+            .await
+            .expect("failed to store the test admin");
+    }
 }
 
 pub struct ConfirmationLinks {
@@ -36,9 +77,19 @@ pub struct ConfirmationLinks {
 }
 
 impl TestApp {
+    // the relay doesn't run in the background during the tests, the emails are
+    // delivered when the test asks for them
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            if let DeliveryOutcome::QueueEmpty = self.outbox_relay.deliver_next().await.unwrap() {
+                break;
+            }
+        }
+    }
+
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "Application/x-www-form-urlencoded")
             .body(body)
             .send()
@@ -46,9 +97,65 @@ impl TestApp {
             .expect("couldn't send the request.")
     }
 
+    // submits the form of the confirmation page, what a human does after visiting the link
+    pub async fn confirm(&self, confirmation_link: &reqwest::Url) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions/confirm", &self.address))
+            .form(&confirmation_link.query_pairs().collect::<Vec<_>>())
+            .send()
+            .await
+            .expect("couldn't send the request.")
+    }
+
+    pub async fn post_subscriptions_json(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &self.address))
+            .json(body)
+            .send()
+            .await
+            .expect("couldn't send the request.")
+    }
+
+    // the `/admin` routes, with the credentials of the test admin
+    pub async fn admin_get(&self, path: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}{}", &self.address, path))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("couldn't send the request.")
+    }
+
+    pub async fn admin_post(&self, path: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}{}", &self.address, path))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("couldn't send the request.")
+    }
+
+    // the emails delivered so far, when the application uses the in-memory backend
+    pub fn sent_emails(&self) -> Vec<SentEmail> {
+        self.email_client
+            .in_memory_outbox()
+            .expect("the application doesn't use the in-memory email backend")
+            .sent()
+    }
+
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let request_body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+        self.confirmation_links_in(
+            request_body["HtmlBody"].as_str().unwrap(),
+            request_body["TextBody"].as_str().unwrap(),
+        )
+    }
 
+    pub fn get_confirmation_links_from(&self, email: &SentEmail) -> ConfirmationLinks {
+        self.confirmation_links_in(&email.html_body, &email.text_body)
+    }
+
+    fn confirmation_links_in(&self, html_body: &str, text_body: &str) -> ConfirmationLinks {
         // declare closure to find the links in a string
         let get_link = |s: &str| {
             let links: Vec<_> = linkify::LinkFinder::new()
@@ -65,14 +172,31 @@ impl TestApp {
             confirmation_link
         };
 
-        let html = get_link(&request_body["HtmlBody"].as_str().unwrap());
-        let plain_text = get_link(&request_body["TextBody"].as_str().unwrap());
+        let html = get_link(html_body);
+        let plain_text = get_link(text_body);
 
         ConfirmationLinks { html, plain_text }
     }
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| ()).await
+}
+
+// lets the test customise the application, e.g. adding workers, before it starts
+pub async fn spawn_app_with(customise: impl FnOnce(&mut Application)) -> TestApp {
+    spawn_test_app(|_| (), customise).await
+}
+
+// lets the test change the settings the application is built with
+pub async fn spawn_app_with_settings(configure: impl FnOnce(&mut Settings)) -> TestApp {
+    spawn_test_app(configure, |_| ()).await
+}
+
+async fn spawn_test_app(
+    configure: impl FnOnce(&mut Settings),
+    customise: impl FnOnce(&mut Application),
+) -> TestApp {
     // LOG INITIALIZATION
     // use environment variable TEST_LOG = true to display the log messages
     Lazy::force(&TRACING);
@@ -91,28 +215,67 @@ pub async fn spawn_app() -> TestApp {
         c.email_client.base_url = email_server.uri();
         // use a random port
         c.application.port = 0;
+        // report the email provider in the readiness check
+        c.health.probe_email_provider = true;
+        // enable open and click tracking
+        c.tracking.enabled = true;
+        c.tracking.signing_key = Secret::new(Uuid::new_v4().to_string());
+        c.bot_protection.signing_key = Secret::new(Uuid::new_v4().to_string());
+        c.confirmation.token_hash_key = Secret::new(Uuid::new_v4().to_string());
+        // the tests signing up again expect another confirmation email
+        c.confirmation.resend_interval_seconds = 0;
+        c.outbox.relay_in_server = false;
+        configure(&mut c);
         c
     };
 
     //configure database pool
     configure_test_database(&configuration.database).await;
 
-    let application = Application::build(configuration.clone())
+    let mut application = Application::build(configuration.clone())
         .await
         .expect("failed to build the application");
+    customise(&mut application);
     let application_port = application.port();
     let address = format!("http://127.0.0.1:{}", application_port);
-    let _ = tokio::spawn(application.run_until_stopped());
+    let shutdown_handle = application.shutdown_handle();
+    // the relay trips the same circuit as the server
+    let email_client = application.email_client();
+    let application_task = tokio::spawn(application.run_until_stopped());
+    let db_pool = get_connection_pool(&configuration.database).await;
+    let test_user = TestUser::generate();
+    test_user.store(&db_pool).await;
 
     TestApp {
+        outbox_relay: OutboxRelay::new(
+            get_connection_pool(&configuration.database).await,
+            email_client.clone(),
+            configuration.outbox.clone(),
+        ),
+        email_client,
+        // the tracking links point directly to the test application
+        tracker: Tracker::new(configuration.tracking, address.clone()).unwrap(),
         address,
-        db_pool: get_connection_pool(&configuration.database).await,
+        db_pool,
         email_server,
         port: application_port,
+        shutdown_handle,
+        application_task,
+        test_user,
     }
 }
 
 async fn configure_test_database(db_conf: &DatabaseSettings) -> PgPool {
+    let db_pool = create_test_database(db_conf).await;
+    MIGRATOR
+        .run(&db_pool)
+        .await
+        .expect("failed do execute migrations");
+    db_pool
+}
+
+// creates an empty database, for the tests that need to control the migrations
+pub async fn create_test_database(db_conf: &DatabaseSettings) -> PgPool {
     // connect to the database
     let mut connection = PgConnection::connect_with(&db_conf.without_db())
         .await
@@ -124,14 +287,7 @@ async fn configure_test_database(db_conf: &DatabaseSettings) -> PgPool {
         .await
         .expect("failed to create database");
 
-    //create the pool and execute migrations
-    let db_pool = PgPool::connect_with(db_conf.with_db())
+    PgPool::connect_with(db_conf.with_db())
         .await
-        .expect("failed to connect to the newly created database");
-    //
-    sqlx::migrate!("./migrations")
-        .run(&db_pool)
-        .await
-        .expect("failed do execute migrations");
-    db_pool
+        .expect("failed to connect to the newly created database")
 }
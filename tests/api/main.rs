@@ -1,4 +1,17 @@
+mod bot_protection;
+mod circuit_breaker;
+mod cli;
+mod dead_letters;
+mod dev_transports;
+mod email_policy;
+mod failover;
+mod fake_mail_server;
 mod health_check;
 mod helpers;
+mod metrics;
+mod migrations;
+mod outbox;
+mod shutdown;
 mod subscriptions;
 mod subscriptions_confirm;
+mod tracking;
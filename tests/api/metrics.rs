@@ -0,0 +1,59 @@
+use crate::helpers::spawn_app;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn get_metrics(address: &str) -> String {
+    let response = reqwest::get(format!("{}/metrics", address))
+        .await
+        .expect("failed to execute metrics request");
+    assert!(response.status().is_success());
+    response.text().await.unwrap()
+}
+
+#[tokio::test]
+async fn metrics_expose_http_requests_by_route_and_status() {
+    let test_app = spawn_app().await;
+
+    reqwest::get(format!("{}/health_check", test_app.address))
+        .await
+        .unwrap();
+    let metrics = get_metrics(&test_app.address).await;
+
+    assert!(
+        metrics.contains(r#"http_requests_total{method="GET",route="/health_check",status="200"}"#)
+    );
+    assert!(metrics.contains(
+        r#"http_request_duration_seconds_bucket{method="GET",route="/health_check",status="200""#
+    ));
+}
+
+#[tokio::test]
+async fn metrics_expose_pool_and_queue_gauges() {
+    let test_app = spawn_app().await;
+
+    let metrics = get_metrics(&test_app.address).await;
+
+    assert!(metrics.contains(r#"db_pool_connections{state="size"}"#));
+    assert!(metrics.contains(r#"db_pool_connections{state="idle"}"#));
+    assert!(metrics.contains(r#"db_pool_connections{state="busy"}"#));
+    assert!(metrics.contains("delivery_queue_depth"));
+}
+
+#[tokio::test]
+async fn metrics_expose_email_sends_by_provider() {
+    let test_app = spawn_app().await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+
+    test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
+    let metrics = get_metrics(&test_app.address).await;
+
+    assert!(metrics.contains(r#"email_send_attempts_total{provider="postmark"}"#));
+    assert!(metrics.contains(r#"email_send_duration_seconds_count{provider="postmark"}"#));
+}
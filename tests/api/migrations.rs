@@ -0,0 +1,230 @@
+use crate::helpers::create_test_database;
+use actix_server::cli::migrate;
+use actix_server::configuration::get_configuration;
+use actix_server::domain::SubscriptionToken;
+use actix_server::startup::MIGRATOR;
+use sqlx::migrate::Migrator;
+use sqlx::PgPool;
+use std::borrow::Cow;
+use uuid::Uuid;
+
+// version of the migration introducing the canonical email addresses
+const CANONICAL_EMAIL_MIGRATION: i64 = 20240922101530;
+// version of the migration storing the hashes of the subscription tokens
+const TOKEN_HASH_MIGRATION: i64 = 20240929093015;
+
+// the embedded migrations up to, and including, the given version
+fn migrator_through(version: i64) -> Migrator {
+    Migrator {
+        migrations: Cow::Owned(
+            MIGRATOR
+                .iter()
+                .filter(|m| m.version <= version)
+                .cloned()
+                .collect(),
+        ),
+        ignore_missing: false,
+    }
+}
+
+// a database migrated up to, but excluding, the given version
+async fn database_before(version: i64) -> PgPool {
+    let mut configuration = get_configuration().expect("failed to load configuration");
+    configuration.database.database_name = Uuid::new_v4().to_string();
+    let db_pool = create_test_database(&configuration.database).await;
+
+    migrator_through(version - 1)
+        .run(&db_pool)
+        .await
+        .expect("failed to execute the migrations");
+    db_pool
+}
+
+async fn insert_subscriber(db_pool: &PgPool, email: &str, status: &str, days_ago: i64) -> Uuid {
+    let id = Uuid::new_v4();
+    sqlx::query(
+        "INSERT INTO subscriptions (id, email, name, subscribed_at, status) \
+        VALUES ($1, $2, 'Alpha Centauri', now() - make_interval(days => $3::int), $4)",
+    )
+    .bind(id)
+    .bind(email)
+    .bind(days_ago)
+    .bind(status)
+    .execute(db_pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO subscription_tokens (subscription_token, subscriber_id) VALUES ($1, $2)",
+    )
+    .bind(SubscriptionToken::new().as_ref())
+    .bind(id)
+    .execute(db_pool)
+    .await
+    .unwrap();
+    id
+}
+
+#[tokio::test]
+async fn duplicated_addresses_are_merged_into_the_confirmed_subscriber() {
+    let db_pool = database_before(CANONICAL_EMAIL_MIGRATION).await;
+    let pending = insert_subscriber(&db_pool, "Alice@Example.com", "pending_confirmation", 2).await;
+    let confirmed = insert_subscriber(&db_pool, "alice@example.com", "confirmed", 1).await;
+    let other = insert_subscriber(&db_pool, "bob@example.com", "pending_confirmation", 1).await;
+    sqlx::query(
+        "INSERT INTO tracking_events (id, newsletter_issue_id, subscriber_id, kind, occurred_at) \
+        VALUES ($1, $2, $3, 'open', now())",
+    )
+    .bind(Uuid::new_v4())
+    .bind(Uuid::new_v4())
+    .bind(pending)
+    .execute(&db_pool)
+    .await
+    .unwrap();
+
+    migrator_through(CANONICAL_EMAIL_MIGRATION)
+        .run(&db_pool)
+        .await
+        .expect("failed to migrate");
+
+    let subscribers: Vec<(Uuid, String, String)> = sqlx::query_as(
+        "SELECT id, email, email_canonical FROM subscriptions ORDER BY email_canonical",
+    )
+    .fetch_all(&db_pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        subscribers,
+        vec![
+            (
+                confirmed,
+                "alice@example.com".to_string(),
+                "alice@example.com".to_string()
+            ),
+            (
+                other,
+                "bob@example.com".to_string(),
+                "bob@example.com".to_string()
+            ),
+        ]
+    );
+    let tracked: Uuid = sqlx::query_scalar("SELECT subscriber_id FROM tracking_events")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tracked, confirmed);
+    let tokens: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM subscription_tokens")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tokens, 2);
+}
+
+#[tokio::test]
+async fn the_oldest_subscriber_is_kept_if_none_is_confirmed() {
+    let db_pool = database_before(CANONICAL_EMAIL_MIGRATION).await;
+    let oldest = insert_subscriber(&db_pool, "Alice@Example.com", "pending_confirmation", 2).await;
+    insert_subscriber(&db_pool, "ALICE@example.com", "pending_confirmation", 1).await;
+
+    migrator_through(CANONICAL_EMAIL_MIGRATION)
+        .run(&db_pool)
+        .await
+        .expect("failed to migrate");
+
+    let kept: (Uuid, String) = sqlx::query_as("SELECT id, email FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    // the address is displayed as it was typed
+    assert_eq!(kept, (oldest, "Alice@Example.com".to_string()));
+}
+
+async fn insert_pending_subscriber_with_token(db_pool: &PgPool, email: &str, status: &str) -> Uuid {
+    let id = Uuid::new_v4();
+    sqlx::query(
+        "INSERT INTO subscriptions (id, email, email_canonical, name, subscribed_at, status) \
+        VALUES ($1, $2, $2, 'Alice', now(), $3)",
+    )
+    .bind(id)
+    .bind(email)
+    .bind(status)
+    .execute(db_pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO subscription_tokens (subscription_token, subscriber_id) VALUES ($1, $2)",
+    )
+    .bind(SubscriptionToken::new().as_ref())
+    .bind(id)
+    .execute(db_pool)
+    .await
+    .unwrap();
+    id
+}
+
+#[tokio::test]
+async fn the_existing_tokens_are_kept_aside() {
+    let db_pool = database_before(TOKEN_HASH_MIGRATION).await;
+    let pending =
+        insert_pending_subscriber_with_token(&db_pool, "alice@example.com", "pending_confirmation")
+            .await;
+
+    MIGRATOR.run(&db_pool).await.expect("failed to migrate");
+
+    // the raw tokens can't be used anymore, but they are kept until `migrate` reissues them
+    let tokens: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM subscription_tokens")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tokens, 0);
+    let legacy: Uuid = sqlx::query_scalar("SELECT subscriber_id FROM legacy_subscription_tokens")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(legacy, pending);
+    let kept: Uuid = sqlx::query_scalar("SELECT id FROM subscriptions")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(kept, pending);
+}
+
+#[tokio::test]
+async fn migrate_sends_a_new_link_for_the_legacy_tokens_and_deletes_them() {
+    let db_pool = database_before(TOKEN_HASH_MIGRATION).await;
+    let pending =
+        insert_pending_subscriber_with_token(&db_pool, "alice@example.com", "pending_confirmation")
+            .await;
+    insert_pending_subscriber_with_token(&db_pool, "bob@example.com", "confirmed").await;
+    let mut configuration = get_configuration().expect("failed to load configuration");
+    configuration.database.database_name = sqlx::query_scalar("SELECT current_database()")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+
+    migrate(configuration.clone()).await.unwrap();
+
+    let recipient: String = sqlx::query_scalar("SELECT recipient FROM outbox")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(recipient, "alice@example.com");
+    let token_owner: Uuid = sqlx::query_scalar("SELECT subscriber_id FROM subscription_tokens")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(token_owner, pending);
+    // no raw token is left, whether it was reissued or not
+    let legacy: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM legacy_subscription_tokens")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(legacy, 0);
+
+    // migrating again doesn't send another email
+    migrate(configuration).await.unwrap();
+    let queued: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM outbox")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued, 1);
+}
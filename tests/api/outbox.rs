@@ -0,0 +1,204 @@
+use crate::helpers::{spawn_app, spawn_app_with_settings};
+use actix_server::outbox::DeliveryOutcome;
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+struct OutboxEmail {
+    status: String,
+    attempts: i32,
+    last_error: Option<String>,
+}
+
+async fn outbox_email(db_pool: &sqlx::PgPool) -> OutboxEmail {
+    sqlx::query_as!(
+        OutboxEmail,
+        "SELECT status, attempts, last_error FROM outbox"
+    )
+    .fetch_one(db_pool)
+    .await
+    .expect("cannot retrieve the queued email")
+}
+
+#[tokio::test]
+async fn subscribe_does_not_depend_on_the_email_provider() {
+    let test_app = spawn_app().await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    let response = test_app.post_subscriptions(body.into()).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let queued = outbox_email(&test_app.db_pool).await;
+    assert_eq!(queued.status, "pending");
+    assert_eq!(queued.attempts, 0);
+
+    test_app.dispatch_all_pending_emails().await;
+
+    // the failed attempt is recorded, the email is retried later
+    let retried = outbox_email(&test_app.db_pool).await;
+    assert_eq!(retried.status, "pending");
+    assert_eq!(retried.attempts, 1);
+    assert!(retried.last_error.is_some());
+}
+
+#[tokio::test]
+async fn the_relay_retries_until_the_email_is_delivered() {
+    let test_app = spawn_app_with_settings(|c| c.outbox.retry_delay_milliseconds = 0).await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(2)
+        .expect(2)
+        .mount(&test_app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
+
+    let delivered = outbox_email(&test_app.db_pool).await;
+    assert_eq!(delivered.status, "delivered");
+    assert_eq!(delivered.attempts, 3);
+    assert_eq!(delivered.last_error, None);
+}
+
+#[tokio::test]
+async fn the_relay_gives_up_after_the_maximum_attempts() {
+    let test_app = spawn_app_with_settings(|c| {
+        c.outbox.retry_delay_milliseconds = 0;
+        c.outbox.max_attempts = 3;
+    })
+    .await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(3)
+        .mount(&test_app.email_server)
+        .await;
+
+    test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
+
+    // the email leaves the outbox for the dead letters
+    let (queued,): (i64,) = sqlx::query_as("SELECT count(*) FROM outbox")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued, 0);
+    let dead_letter = sqlx::query!("SELECT attempts, last_error FROM dead_letters")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("cannot retrieve the dead letter");
+    assert_eq!(dead_letter.attempts, 3);
+    assert!(dead_letter.last_error.contains("500"));
+}
+
+#[tokio::test]
+async fn the_server_delivers_the_emails_in_the_background() {
+    let test_app = spawn_app_with_settings(|c| {
+        c.outbox.relay_in_server = true;
+        c.outbox.poll_interval_milliseconds = 50;
+    })
+    .await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    test_app.post_subscriptions(body.into()).await;
+
+    let delivered = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if outbox_email(&test_app.db_pool).await.status == "delivered" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    assert!(delivered.is_ok(), "the email was not delivered");
+}
+
+#[tokio::test]
+async fn the_email_is_not_locked_while_it_is_sent() {
+    let test_app = spawn_app().await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+    test_app.post_subscriptions(body.into()).await;
+
+    let (outcome, status_while_sending) =
+        tokio::join!(test_app.outbox_relay.deliver_next(), async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            // `FOR UPDATE NOWAIT` fails at once if the relay still holds a row lock
+            sqlx::query!("SELECT status FROM outbox FOR UPDATE NOWAIT")
+                .fetch_one(&test_app.db_pool)
+                .await
+                .expect("the email is locked while it's sent")
+                .status
+        });
+
+    assert_eq!(status_while_sending, "sending");
+    assert_eq!(outcome.unwrap(), DeliveryOutcome::Delivered);
+    assert_eq!(outbox_email(&test_app.db_pool).await.status, "delivered");
+}
+
+#[tokio::test]
+async fn a_claimed_email_is_taken_over_once_its_lease_expires() {
+    let test_app = spawn_app().await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+    test_app.post_subscriptions(body.into()).await;
+
+    // another relay is delivering the email
+    sqlx::query!(
+        "UPDATE outbox SET status = 'sending', locked_until = now() + interval '1 minute'"
+    )
+    .execute(&test_app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        test_app.outbox_relay.deliver_next().await.unwrap(),
+        DeliveryOutcome::QueueEmpty
+    );
+
+    // and crashed before recording the outcome
+    sqlx::query!("UPDATE outbox SET locked_until = now() - interval '1 second'")
+        .execute(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        test_app.outbox_relay.deliver_next().await.unwrap(),
+        DeliveryOutcome::Delivered
+    );
+}
@@ -0,0 +1,71 @@
+use crate::helpers::{spawn_app, spawn_app_with};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use wiremock::matchers::method;
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn shutdown_lets_in_flight_requests_complete() {
+    let test_app = spawn_app().await;
+
+    // keep the readiness check in flight for a while, it probes the email provider
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+        .mount(&test_app.email_server)
+        .await;
+
+    let in_flight = {
+        let address = test_app.address.clone();
+        tokio::spawn(async move { reqwest::get(format!("{}/health/ready", address)).await })
+    };
+    // wait for the request to reach the email provider, it's in flight from then on
+    while test_app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .is_empty()
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    test_app.shutdown_handle.shutdown().await;
+
+    let response = in_flight.await.unwrap().unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    test_app.application_task.await.unwrap().unwrap();
+    // new connections are refused
+    assert!(reqwest::get(format!("{}/health_check", test_app.address))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn shutdown_stops_workers_after_their_current_item() {
+    let processed = Arc::new(AtomicUsize::new(0));
+    let completed = Arc::new(AtomicUsize::new(0));
+
+    let test_app = {
+        let (processed, completed) = (processed.clone(), completed.clone());
+        spawn_app_with(move |application| {
+            application.spawn_worker(move |signal| async move {
+                while !signal.is_triggered() {
+                    processed.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    completed.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+        })
+        .await
+    };
+    tokio::time::sleep(Duration::from_millis(120)).await;
+
+    test_app.shutdown_handle.shutdown().await;
+    test_app.application_task.await.unwrap().unwrap();
+
+    // every item that was started has been completed
+    let processed = processed.load(Ordering::SeqCst);
+    assert!(processed > 0);
+    assert_eq!(processed, completed.load(Ordering::SeqCst));
+}
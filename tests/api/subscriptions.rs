@@ -1,4 +1,4 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with_settings};
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
 
@@ -60,6 +60,7 @@ async fn subscribe_sends_confirmation_email_for_valid_data() {
         .await;
 
     test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
 }
 
 #[tokio::test]
@@ -75,8 +76,9 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
 
     test_app.post_subscriptions(body.into()).await;
 
+    test_app.dispatch_all_pending_emails().await;
     let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = test_app.get_confirmation_links(&email_request);
+    let confirmation_links = test_app.get_confirmation_links(email_request);
 
     // the two links should be identical
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
@@ -128,6 +130,61 @@ async fn subscribe_returns_400_when_fields_are_present_but_invalid() {
     }
 }
 
+#[tokio::test]
+async fn subscribe_validation_errors_are_described_as_problem_details() {
+    let test_app = spawn_app().await;
+    let test_cases = vec![
+        ("name=&email=alphacentauri%40smail.com", "name"),
+        (
+            "name=Alpha%20Centauri&email=certainly-not-an-email",
+            "email",
+        ),
+    ];
+
+    for (body, field) in test_cases {
+        let response = test_app.post_subscriptions(body.into()).await;
+
+        assert_eq!(response.status().as_u16(), 400);
+        assert_eq!(
+            response.headers()["Content-Type"],
+            "application/problem+json"
+        );
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["code"], "invalid_field");
+        assert_eq!(problem["status"], 400);
+        assert_eq!(problem["field"], field);
+        assert!(problem["title"].is_string());
+        assert!(problem["request_id"].is_string());
+    }
+}
+
+#[tokio::test]
+async fn subscribe_reports_every_invalid_field_at_once() {
+    let test_app = spawn_app().await;
+
+    let response = test_app
+        .post_subscriptions("name=Alpha%20%7BCentauri%7D&email=not-an-email".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        problem["errors"],
+        serde_json::json!([
+            {
+                "field": "name",
+                "code": "forbidden_character",
+                "detail": "name must not contain '{'"
+            },
+            {
+                "field": "email",
+                "code": "invalid_format",
+                "detail": "email is not in a valid format"
+            }
+        ])
+    );
+}
+
 #[tokio::test]
 async fn subscribe_resends_confirmation_email_for_duplicate_email() {
     let test_app = spawn_app().await;
@@ -142,6 +199,149 @@ async fn subscribe_resends_confirmation_email_for_duplicate_email() {
 
     test_app.post_subscriptions(body.into()).await;
     test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn concurrent_signups_for_the_same_address_send_a_single_confirmation_email() {
+    let test_app = spawn_app_with_settings(|c| c.confirmation.resend_interval_seconds = 60).await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    let responses =
+        futures_util::future::join_all((0..5).map(|_| test_app.post_subscriptions(body.into())))
+            .await;
+
+    test_app.dispatch_all_pending_emails().await;
+
+    for response in responses {
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let (subscribers,): (i64,) = sqlx::query_as("SELECT count(*) FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("cannot retrieve the subscribers");
+    assert_eq!(subscribers, 1);
+}
+
+#[tokio::test]
+async fn signing_up_again_within_the_resend_interval_sends_no_email() {
+    let test_app = spawn_app_with_settings(|c| c.confirmation.resend_interval_seconds = 60).await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    let first = test_app.post_subscriptions(body.into()).await;
+    let second = test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn subscribe_treats_addresses_differing_only_in_case_as_the_same_subscriber() {
+    let test_app = spawn_app().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&test_app.email_server)
+        .await;
+
+    for body in [
+        "name=Alice&email=Alice%40Example.com",
+        "name=Alice&email=alice%40EXAMPLE.com",
+    ] {
+        let response = test_app.post_subscriptions(body.into()).await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    test_app.dispatch_all_pending_emails().await;
+
+    let saved = sqlx::query!("SELECT email, email_canonical FROM subscriptions")
+        .fetch_all(&test_app.db_pool)
+        .await
+        .expect("cannot retrieve the subscribers");
+    assert_eq!(saved.len(), 1);
+    // the address is kept as it was first typed
+    assert_eq!(saved[0].email, "Alice@Example.com");
+    assert_eq!(saved[0].email_canonical, "alice@example.com");
+}
+
+#[tokio::test]
+async fn subscribe_accepts_internationalised_addresses_the_transport_can_deliver() {
+    let test_app = spawn_app().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // the domain is converted to punycode, the local part can't be
+    let response = test_app
+        .post_subscriptions_json(&serde_json::json!({
+            "name": "Alice",
+            "email": "alice@bücher.example"
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = test_app
+        .post_subscriptions_json(&serde_json::json!({
+            "name": "José",
+            "email": "josé@bücher.example"
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 400);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["errors"][0]["code"], "undeliverable");
+
+    test_app.dispatch_all_pending_emails().await;
+    let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], "alice@xn--bcher-kva.example");
+}
+
+#[tokio::test]
+async fn subscribe_accepts_internationalised_mailboxes_with_an_smtputf8_transport() {
+    let test_app = spawn_app_with_settings(|c| c.email_client.smtputf8 = true).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    let response = test_app
+        .post_subscriptions_json(&serde_json::json!({
+            "name": "José",
+            "email": "josé@bücher.example"
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    test_app.dispatch_all_pending_emails().await;
+
+    let saved = sqlx::query!("SELECT email, email_canonical FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("cannot retrieve the subscriber");
+    assert_eq!(saved.email, "josé@bücher.example");
+    assert_eq!(saved.email_canonical, "josé@xn--bcher-kva.example");
 }
 
 #[tokio::test]
@@ -150,7 +350,7 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error() {
     let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
 
     // sabotage the database
-    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;",)
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token_hash;",)
         .execute(&test_app.db_pool)
         .await
         .unwrap();
@@ -158,4 +358,149 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error() {
     let response = test_app.post_subscriptions(body.into()).await;
 
     assert_eq!(response.status().as_u16(), 500);
+    // the error chain is logged, not disclosed
+    let body = response.text().await.unwrap();
+    let problem: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(problem["code"], "internal_error");
+    assert!(problem.get("detail").is_none());
+    assert!(!body.contains("subscription_token"));
+}
+
+#[tokio::test]
+async fn subscribe_propagates_the_incoming_trace_context_to_the_email_provider() {
+    let test_app = spawn_app().await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+    let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", &test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header(
+            "traceparent",
+            format!("00-{}-00f067aa0ba902b7-01", trace_id),
+        )
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+
+    test_app.dispatch_all_pending_emails().await;
+    let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
+    let traceparent = email_request.headers["traceparent"].to_str().unwrap();
+    // same trace, different span
+    assert!(traceparent.starts_with(&format!("00-{}-", trace_id)));
+    assert!(!traceparent.contains("00f067aa0ba902b7"));
+}
+
+#[tokio::test]
+async fn subscribe_accepts_a_json_body() {
+    let test_app = spawn_app().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    let response = test_app
+        .post_subscriptions_json(&serde_json::json!({
+            "name": "Alpha Centauri",
+            "email": "alphacentauri@smail.com"
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    test_app.dispatch_all_pending_emails().await;
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "pending_confirmation");
+    let saved = sqlx::query!("SELECT email, name FROM public.subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("cannot retrieve subscriber");
+    assert_eq!(saved.email, "alphacentauri@smail.com");
+    assert_eq!(saved.name, "Alpha Centauri");
+}
+
+#[tokio::test]
+async fn subscribe_returns_400_for_invalid_json_bodies() {
+    let test_app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({"name": "Alpha Centauri"}),
+            "missing email",
+        ),
+        (
+            serde_json::json!({"name": "", "email": "alphacentauri@smail.com"}),
+            "empty name",
+        ),
+        (
+            serde_json::json!({"name": "Alpha Centauri", "email": "certainly-not-an-email"}),
+            "invalid email",
+        ),
+    ];
+
+    for (body, error_message) in test_cases {
+        let response = test_app.post_subscriptions_json(&body).await;
+
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not fail with 400 code when the body was {}",
+            error_message
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_rejects_unsupported_content_types_with_415() {
+    let test_app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &test_app.address))
+        .header("Content-Type", "text/plain")
+        .body("name=Alpha%20Centauri&email=alphacentauri%40smail.com")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 415);
+}
+
+#[tokio::test]
+async fn subscribe_negotiates_the_response_format() {
+    let test_app = spawn_app().await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+
+    for (accept, content_type) in [
+        ("text/html,application/xhtml+xml,*/*;q=0.8", "text/html"),
+        ("application/json", "application/json"),
+    ] {
+        let response = reqwest::Client::new()
+            .post(format!("{}/subscriptions", &test_app.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Accept", accept)
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.headers()["Content-Type"]
+            .to_str()
+            .unwrap()
+            .starts_with(content_type));
+    }
 }
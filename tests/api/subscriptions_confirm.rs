@@ -1,4 +1,6 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with_settings, TestApp};
+use actix_server::configuration::ConfirmationMode;
+use secrecy::Secret;
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
 
@@ -27,6 +29,9 @@ async fn confirmations_with_invalid_token_are_rejected_with_400() {
     .unwrap();
 
     assert_eq!(response.status().as_u16(), 400);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "invalid_token");
+    assert_eq!(problem["field"], "subscription_token");
 }
 #[tokio::test]
 async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
@@ -41,8 +46,9 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
         .await;
 
     test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
     let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = test_app.get_confirmation_links(&email_request);
+    let confirmation_links = test_app.get_confirmation_links(email_request);
 
     let response = reqwest::get(confirmation_links.html).await.unwrap();
 
@@ -50,7 +56,43 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
 }
 
 #[tokio::test]
-async fn visiting_confirmation_link_confirms_a_subscriber() {
+async fn visiting_the_confirmation_link_does_not_confirm_a_subscriber() {
+    let test_app = spawn_app().await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+
+    test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
+    let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = test_app.get_confirmation_links(email_request);
+
+    // what a mail scanner does
+    let response = reqwest::Client::new()
+        .get(confirmation_links.html)
+        .header("Accept", "text/html")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let page = response.text().await.unwrap();
+    assert!(page.contains(r#"<form id="confirm" action="/subscriptions/confirm" method="post">"#));
+    // scripts are not run by the scanners, the page doesn't submit itself unless configured
+    assert!(!page.contains("<script>"));
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to retrieve saved subscription");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn submitting_the_confirmation_form_confirms_a_subscriber() {
     // prepare what's needed
     let test_app = spawn_app().await;
     let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
@@ -62,13 +104,13 @@ async fn visiting_confirmation_link_confirms_a_subscriber() {
         .await;
 
     test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
     let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = test_app.get_confirmation_links(&email_request);
+    let confirmation_links = test_app.get_confirmation_links(email_request);
 
-    // visit the link
-    reqwest::get(confirmation_links.html)
+    test_app
+        .confirm(&confirmation_links.html)
         .await
-        .unwrap()
         .error_for_status()
         .unwrap();
 
@@ -82,6 +124,71 @@ async fn visiting_confirmation_link_confirms_a_subscriber() {
     assert_eq!(saved.status, "confirmed");
 }
 
+#[tokio::test]
+async fn confirming_again_reports_an_already_confirmed_subscription() {
+    let test_app = spawn_app().await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+
+    test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
+    let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = test_app.get_confirmation_links(email_request);
+    test_app.confirm(&confirmation_links.html).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/confirm", test_app.address))
+        .header("Accept", "application/json")
+        .form(&confirmation_links.html.query_pairs().collect::<Vec<_>>())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let confirmation: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(confirmation["status"], "already_confirmed");
+
+    let response = reqwest::Client::new()
+        .get(confirmation_links.html)
+        .header("Accept", "text/html")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let page = response.text().await.unwrap();
+    assert!(page.contains("already confirmed"));
+    assert!(!page.contains("<form"));
+}
+
+#[tokio::test]
+async fn the_confirmation_page_can_submit_itself() {
+    let test_app = spawn_app_with_settings(|c| c.confirmation.auto_submit = true).await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+
+    test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
+    let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = test_app.get_confirmation_links(email_request);
+    let page = reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    assert!(page.contains(r#"document.getElementById("confirm").submit()"#));
+}
+
 #[tokio::test]
 async fn subscription_confirmation_fails_if_there_is_a_fatal_database_error() {
     let test_app = spawn_app().await;
@@ -101,8 +208,9 @@ async fn subscription_confirmation_fails_if_there_is_a_fatal_database_error() {
         .await
         .unwrap();
 
+    test_app.dispatch_all_pending_emails().await;
     let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = test_app.get_confirmation_links(&email_request);
+    let confirmation_links = test_app.get_confirmation_links(email_request);
 
     // visit the link
     let response = reqwest::get(confirmation_links.html).await.unwrap();
@@ -129,11 +237,198 @@ async fn subscription_confirmation_fails_with_401_if_token_has_no_subscriber() {
         .await
         .unwrap();
 
+    test_app.dispatch_all_pending_emails().await;
     let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = test_app.get_confirmation_links(&email_request);
+    let confirmation_links = test_app.get_confirmation_links(email_request);
 
     // visit the link
     let response = reqwest::get(confirmation_links.html).await.unwrap();
 
     assert_eq!(response.status().as_u16(), 401);
 }
+
+#[tokio::test]
+async fn only_the_hash_of_the_token_is_stored() {
+    let test_app = spawn_app().await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+
+    test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
+    let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = test_app.get_confirmation_links(email_request);
+    let (_, token) = confirmation_links
+        .html
+        .query_pairs()
+        .find(|(name, _)| name == "subscription_token")
+        .unwrap();
+
+    let stored = sqlx::query!("SELECT subscription_token_hash FROM subscription_tokens")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to retrieve the stored token");
+
+    assert_ne!(stored.subscription_token_hash, token);
+    assert!(!stored.subscription_token_hash.contains(token.as_ref()));
+}
+
+#[tokio::test]
+async fn signing_up_again_replaces_the_confirmation_link() {
+    let test_app = spawn_app().await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&test_app.email_server)
+        .await;
+
+    test_app.post_subscriptions(body.into()).await;
+    test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
+    let email_requests = test_app.email_server.received_requests().await.unwrap();
+    let first_link = test_app.get_confirmation_links(&email_requests[0]).html;
+    let second_link = test_app.get_confirmation_links(&email_requests[1]).html;
+    assert_ne!(first_link, second_link);
+
+    let response = reqwest::get(first_link).await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    let response = reqwest::get(second_link).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+async fn spawn_app_with_signed_links(link_max_age_seconds: u64) -> TestApp {
+    let test_app = spawn_app_with_settings(|c| {
+        c.confirmation.mode = ConfirmationMode::Signed;
+        c.confirmation.signing_keys = vec![Secret::new("confirmation-key".into())];
+        c.confirmation.link_max_age_seconds = link_max_age_seconds;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+    test_app
+}
+
+#[tokio::test]
+async fn a_signed_link_confirms_a_subscriber_without_storing_tokens() {
+    let test_app = spawn_app_with_signed_links(3600).await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    test_app.post_subscriptions(body.into()).await;
+    let tokens: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM subscription_tokens")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tokens, 0);
+
+    test_app.dispatch_all_pending_emails().await;
+    let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = test_app.get_confirmation_links(email_request);
+    let response = test_app.confirm(&confirmation_links.html).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to retrieve saved subscription");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn a_forged_signed_link_is_rejected_with_401() {
+    let test_app = spawn_app_with_signed_links(3600).await;
+
+    let forged = actix_server::signing::sign(
+        &Secret::new("another-key".into()),
+        &format!("confirm:{}:{}", uuid::Uuid::new_v4(), i64::MAX),
+    );
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?confirmation={}",
+        test_app.address, forged
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "invalid_link");
+}
+
+#[tokio::test]
+async fn an_expired_signed_link_is_rejected_with_410() {
+    let test_app = spawn_app_with_signed_links(0).await;
+    let body = "name=Alpha%20Centauri&email=alphacentauri%40smail.com";
+
+    test_app.post_subscriptions(body.into()).await;
+    test_app.dispatch_all_pending_emails().await;
+    let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = test_app.get_confirmation_links(email_request);
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 410);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "expired_link");
+}
+
+#[tokio::test]
+async fn browsers_are_shown_a_page_for_an_invalid_link() {
+    let test_app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/subscriptions/confirm?subscription_token=not-a-token",
+            test_app.address
+        ))
+        .header("Accept", "text/html")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 400);
+    assert!(response.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("This link is not valid"));
+}
+
+#[tokio::test]
+async fn the_pages_can_be_replaced_by_redirects() {
+    let test_app = spawn_app_with_settings(|c| {
+        c.application.redirects.invalid_link = Some("https://example.com/invalid-link".into());
+    })
+    .await;
+
+    let response = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .get(format!(
+            "{}/subscriptions/confirm?subscription_token=not-a-token",
+            test_app.address
+        ))
+        .header("Accept", "text/html")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(
+        response.headers()["Location"],
+        "https://example.com/invalid-link"
+    );
+}
@@ -0,0 +1,161 @@
+use crate::helpers::{spawn_app, spawn_app_with_settings, TestApp};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn create_subscriber(test_app: &TestApp, tracking_consent: bool) -> Uuid {
+    let body = format!(
+        "name=Alpha%20Centauri&email=alphacentauri%40smail.com&tracking_consent={}",
+        tracking_consent
+    );
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+
+    test_app
+        .post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("cannot retrieve subscriber")
+        .id
+}
+
+fn no_redirect_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+}
+
+async fn get_report(test_app: &TestApp, newsletter_issue_id: Uuid) -> serde_json::Value {
+    test_app
+        .admin_get(&format!("/admin/issues/{}/tracking", newsletter_issue_id))
+        .await
+        .json()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn the_open_pixel_is_served_and_counted_once_per_subscriber() {
+    let test_app = spawn_app().await;
+    let subscriber_id = create_subscriber(&test_app, true).await;
+    let newsletter_issue_id = Uuid::new_v4();
+
+    let pixel_url = test_app
+        .tracker
+        .open_pixel_url(newsletter_issue_id, subscriber_id);
+    for _ in 0..2 {
+        let response = reqwest::get(&pixel_url).await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["Content-Type"], "image/gif");
+    }
+
+    let report = get_report(&test_app, newsletter_issue_id).await;
+    assert_eq!(report["unique_opens"], 1);
+    assert_eq!(report["unique_clicks"], 0);
+}
+
+#[tokio::test]
+async fn a_tracked_link_redirects_to_the_original_url_and_is_counted() {
+    let test_app = spawn_app().await;
+    let subscriber_id = create_subscriber(&test_app, true).await;
+    let newsletter_issue_id = Uuid::new_v4();
+
+    let click_url = test_app.tracker.click_url(
+        newsletter_issue_id,
+        subscriber_id,
+        "https://example.com/post?id=1",
+    );
+    let response = no_redirect_client().get(&click_url).send().await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 302);
+    assert_eq!(
+        response.headers()["Location"],
+        "https://example.com/post?id=1"
+    );
+    let report = get_report(&test_app, newsletter_issue_id).await;
+    assert_eq!(report["unique_clicks"], 1);
+}
+
+#[tokio::test]
+async fn events_are_not_stored_for_subscribers_who_did_not_consent() {
+    let test_app = spawn_app().await;
+    let subscriber_id = create_subscriber(&test_app, false).await;
+    let newsletter_issue_id = Uuid::new_v4();
+
+    let pixel_url = test_app
+        .tracker
+        .open_pixel_url(newsletter_issue_id, subscriber_id);
+    let click_url =
+        test_app
+            .tracker
+            .click_url(newsletter_issue_id, subscriber_id, "https://example.com");
+    reqwest::get(&pixel_url).await.unwrap();
+    let response = no_redirect_client().get(&click_url).send().await.unwrap();
+
+    // the subscriber still reaches the link
+    assert_eq!(response.status().as_u16(), 302);
+    let report = get_report(&test_app, newsletter_issue_id).await;
+    assert_eq!(report["unique_opens"], 0);
+    assert_eq!(report["unique_clicks"], 0);
+}
+
+#[tokio::test]
+async fn forged_tracking_links_are_rejected_with_404() {
+    let test_app = spawn_app().await;
+
+    let response = no_redirect_client()
+        .get(format!(
+            "{}/t/c/aHR0cHM6Ly9ldmlsLmNvbQ.c2lnbmF0dXJl",
+            test_app.address
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn the_tracking_links_are_not_followed_when_tracking_is_disabled() {
+    let test_app = spawn_app_with_settings(|c| c.tracking.enabled = false).await;
+    let newsletter_issue_id = Uuid::new_v4();
+    let subscriber_id = Uuid::new_v4();
+
+    for url in [
+        test_app
+            .tracker
+            .open_pixel_url(newsletter_issue_id, subscriber_id),
+        test_app
+            .tracker
+            .click_url(newsletter_issue_id, subscriber_id, "https://example.com"),
+    ] {
+        let response = no_redirect_client().get(&url).send().await.unwrap();
+
+        assert_eq!(response.status().as_u16(), 404);
+    }
+}
+
+#[tokio::test]
+async fn the_tracking_report_needs_admin_credentials() {
+    let test_app = spawn_app().await;
+
+    let response = reqwest::get(format!(
+        "{}/admin/issues/{}/tracking",
+        test_app.address,
+        Uuid::new_v4()
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}